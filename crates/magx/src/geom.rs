@@ -181,6 +181,25 @@ pub fn persp(fovy: Angle, aspect: Tyf, near: Tyf, far: Tyf) -> Mat4 {
     )
 }
 
+/// 反向Z（reversed-Z）透视投影
+///
+/// 与persp的x、y相同，但NC坐标的z范围为[0, 1]，且近平面z为1、远平面z为0（类似D3D的深度范围）；
+/// 浮点数在0附近精度更高，正好抵消透视投影中远处深度值变化慢的问题，减少远处的z-fighting。
+/// 需要配合viewport_reversed使用。
+///
+/// - fovy: Y轴方向的视角
+/// - aspect: 宽/高的比值
+/// - near/far: 视点到近/远平面的距离
+pub fn persp_reversed(fovy: Angle, aspect: Tyf, near: Tyf, far: Tyf) -> Mat4 {
+    let half = (fovy.to_rad() / 2.0).tan();
+    Mat4::from(
+        Vec4::new().x(1.0 / (half * aspect)),
+        Vec4::new().y(1.0 / half),
+        Vec4::new().z(near / (far - near)).w(near * far / (far - near)),
+        Vec4::new().z(-1.0),
+    )
+}
+
 /// 视口变换
 ///
 /// 用于将NC坐标映射到屏幕坐标系上，即将[-1,1]x3的立方体，映射到[x,x+w]-[y,y+h]-[0,1]上。
@@ -194,6 +213,18 @@ pub fn viewport(x: Tyf, y: Tyf, width: Tyf, height: Tyf) -> Mat4 {
     )
 }
 
+/// 反向Z的视口变换
+///
+/// 与viewport相同，但NC坐标的z范围已经是[0, 1]，不需要变换（避免加0.5损失0附近的精度）。
+pub fn viewport_reversed(x: Tyf, y: Tyf, width: Tyf, height: Tyf) -> Mat4 {
+    Mat4::from(
+        Vec4::new().x(width / 2.0).w(x + width / 2.0),
+        Vec4::new().y(height / 2.0).w(y + height / 2.0),
+        Vec4::new().z(1.0),
+        Vec4::from(0.0, 0.0, 0.0, 1.0),
+    )
+}
+
 /// lerp函数
 #[inline]
 pub fn lerp<T>(a: &T, b: &T, t: Tyf) -> T
@@ -503,6 +534,12 @@ mod tests {
             ),
             epsilon = 0.000001
         ));
+        let proj = persp_reversed(Angle::Ang(45.0), 2.0 / 1.0, 0.1, 10.0);
+        println!("reversed-z perspective: \n{}", proj);
+        let near = proj.mul_vec(&Vec4::from(0.0, 0.0, -0.1, 1.0));
+        let far = proj.mul_vec(&Vec4::from(0.0, 0.0, -10.0, 1.0));
+        assert!(approx_eq!(Tyf, near.z / near.w, 1.0, epsilon = 0.000001));
+        assert!(approx_eq!(Tyf, far.z / far.w, 0.0, epsilon = 0.000001));
     }
 
     #[test]
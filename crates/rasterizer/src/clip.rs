@@ -0,0 +1,235 @@
+//! 齐次裁剪
+//!
+//! 在透视除法之前，于齐次裁剪空间中对片段进行裁剪（Sutherland-Hodgman算法）。
+//!
+//! 裁剪空间中，视锥体内的点满足`-w <= x,y,z <= w`，即6个裁剪平面：
+//!
+//! ```text
+//! w + x >= 0, w - x >= 0
+//! w + y >= 0, w - y >= 0
+//! w + z >= 0, w - z >= 0
+//! ```
+//!
+//! 对于穿过近平面或者在摄像头后方（w <= 0）的片段，若不裁剪直接做透视除法，会得到错误的屏幕坐标。
+//!
+//! 反向Z时（见`magx::persp_reversed`），z的范围为`0 <= z <= w`，近平面和远平面分别为：
+//!
+//! ```text
+//! w - z >= 0, z >= 0
+//! ```
+
+use magx::*;
+
+/// 裁剪平面（与齐次坐标点乘，结果>=0表示在平面内侧）
+const CLIP_PLANES: [Vec4; 6] = [
+    Vec4::from(0.0, 0.0, 1.0, 1.0),  // near
+    Vec4::from(0.0, 0.0, -1.0, 1.0), // far
+    Vec4::from(1.0, 0.0, 0.0, 1.0),  // left
+    Vec4::from(-1.0, 0.0, 0.0, 1.0), // right
+    Vec4::from(0.0, 1.0, 0.0, 1.0),  // bottom
+    Vec4::from(0.0, -1.0, 0.0, 1.0), // top
+];
+
+/// 反向Z的裁剪平面
+const CLIP_PLANES_REVERSED: [Vec4; 6] = [
+    Vec4::from(0.0, 0.0, -1.0, 1.0), // near
+    Vec4::from(0.0, 0.0, 1.0, 0.0),  // far
+    Vec4::from(1.0, 0.0, 0.0, 1.0),  // left
+    Vec4::from(-1.0, 0.0, 0.0, 1.0), // right
+    Vec4::from(0.0, 1.0, 0.0, 1.0),  // bottom
+    Vec4::from(0.0, -1.0, 0.0, 1.0), // top
+];
+
+/// 裁剪顶点
+#[derive(Debug, Copy, Clone)]
+pub struct ClipVertex {
+    /// 顶点的投影坐标
+    pub pos: Vec4,
+    /// 顶点在原片段中的重心坐标
+    pub bc: Vec3,
+}
+
+impl ClipVertex {
+    pub const fn new(pos: Vec4, bc: Vec3) -> Self {
+        Self { pos, bc }
+    }
+
+    /// 在两个顶点之间线性插值（投影坐标和重心坐标在裁剪空间中均是线性的）
+    #[inline]
+    fn lerp(&self, rhs: &Self, t: Tyf) -> Self {
+        Self {
+            pos: lerp(&self.pos, &rhs.pos, t),
+            bc: lerp(&self.bc, &rhs.bc, t),
+        }
+    }
+}
+
+/// 裁剪后的三角形
+pub type ClipTriangle = [ClipVertex; 3];
+
+/// 对片段进行裁剪
+///
+/// 片段先被裁剪成凸多边形，再以第0个顶点为中心拆分成多个三角形；
+/// 新三角形顶点的重心坐标均相对于原片段，用于片段着色时的重心坐标重映射。
+///
+/// - abc: 片段三个顶点的投影坐标
+/// - reversed_z: 投影坐标是否为反向Z
+pub fn clip_triangle(abc: &(Vec4, Vec4, Vec4), reversed_z: bool) -> Vec<ClipTriangle> {
+    let planes = if reversed_z { &CLIP_PLANES_REVERSED } else { &CLIP_PLANES };
+    let polygon = vec![
+        ClipVertex::new(abc.0, Vec3::from(1.0, 0.0, 0.0)),
+        ClipVertex::new(abc.1, Vec3::from(0.0, 1.0, 0.0)),
+        ClipVertex::new(abc.2, Vec3::from(0.0, 0.0, 1.0)),
+    ];
+
+    // 片段完全在视锥体内，不需要裁剪
+    if polygon.iter().all(|v| planes.iter().all(|p| v.pos.dot(p) >= 0.0)) {
+        return vec![[polygon[0], polygon[1], polygon[2]]];
+    }
+
+    let polygon = planes.iter().fold(polygon, |polygon, plane| clip_polygon(&polygon, plane));
+
+    let mut triangles = Vec::new();
+    for k in 1..polygon.len().saturating_sub(1) {
+        triangles.push([polygon[0], polygon[k], polygon[k + 1]]);
+    }
+    triangles
+}
+
+/// 对线段进行裁剪
+///
+/// 返回裁剪后线段的两个端点，端点的重心坐标相对于原线段（只使用前两个分量）；
+/// 线段完全在视锥体外时返回None。
+///
+/// - ab: 线段两个端点的投影坐标
+/// - reversed_z: 投影坐标是否为反向Z
+pub fn clip_line(ab: &(Vec4, Vec4), reversed_z: bool) -> Option<[ClipVertex; 2]> {
+    let planes = if reversed_z { &CLIP_PLANES_REVERSED } else { &CLIP_PLANES };
+    let a = ClipVertex::new(ab.0, Vec3::from(1.0, 0.0, 0.0));
+    let b = ClipVertex::new(ab.1, Vec3::from(0.0, 1.0, 0.0));
+    // 线段参数t的可见范围（Liang-Barsky算法）
+    let (mut t0, mut t1): (Tyf, Tyf) = (0.0, 1.0);
+    for plane in planes {
+        let da = a.pos.dot(plane);
+        let db = b.pos.dot(plane);
+        if da < 0.0 && db < 0.0 {
+            return None;
+        }
+        if da < 0.0 {
+            t0 = t0.max(da / (da - db));
+        } else if db < 0.0 {
+            t1 = t1.min(da / (da - db));
+        }
+    }
+    if t0 > t1 {
+        return None;
+    }
+    Some([a.lerp(&b, t0), a.lerp(&b, t1)])
+}
+
+/// 点是否在视锥体内（点不需要裁剪，只需要剃除）
+///
+/// - p: 点的投影坐标
+/// - reversed_z: 投影坐标是否为反向Z
+pub fn clip_point(p: &Vec4, reversed_z: bool) -> bool {
+    let planes = if reversed_z { &CLIP_PLANES_REVERSED } else { &CLIP_PLANES };
+    planes.iter().all(|plane| p.dot(plane) >= 0.0)
+}
+
+/// 用一个裁剪平面裁剪多边形
+fn clip_polygon(polygon: &[ClipVertex], plane: &Vec4) -> Vec<ClipVertex> {
+    let mut out = Vec::with_capacity(polygon.len() + 1);
+    for (k, cur) in polygon.iter().enumerate() {
+        let pre = &polygon[(k + polygon.len() - 1) % polygon.len()];
+        let dc = cur.pos.dot(plane);
+        let dp = pre.pos.dot(plane);
+        if dc >= 0.0 {
+            if dp < 0.0 {
+                // 从外侧进入内侧，添加交点
+                out.push(pre.lerp(cur, dp / (dp - dc)));
+            }
+            out.push(*cur);
+        } else if dp >= 0.0 {
+            // 从内侧离开到外侧，只添加交点
+            out.push(pre.lerp(cur, dp / (dp - dc)));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::approx_eq;
+
+    #[test]
+    fn clipping() {
+        // 完全在视锥体内的片段保持不变
+        let abc = (
+            Vec4::from(-0.5, -0.5, 0.0, 1.0),
+            Vec4::from(0.5, -0.5, 0.0, 1.0),
+            Vec4::from(0.0, 0.5, 0.0, 1.0),
+        );
+        let tris = clip_triangle(&abc, false);
+        assert_eq!(tris.len(), 1);
+        assert_eq!(tris[0][1].pos, abc.1);
+
+        // 完全在摄像头后方的片段被丢弃
+        let abc = (
+            Vec4::from(-0.5, -0.5, 2.0, -1.0),
+            Vec4::from(0.5, -0.5, 2.0, -1.0),
+            Vec4::from(0.0, 0.5, 2.0, -1.0),
+        );
+        assert!(clip_triangle(&abc, false).is_empty());
+
+        // 穿过近平面的片段，裁剪成四边形（2个三角形）
+        let abc = (
+            Vec4::from(0.0, 0.0, -2.0, 1.0),
+            Vec4::from(0.5, 0.0, 0.0, 1.0),
+            Vec4::from(0.0, 0.5, 0.0, 1.0),
+        );
+        let tris = clip_triangle(&abc, false);
+        assert_eq!(tris.len(), 2);
+        for tri in &tris {
+            for v in tri {
+                // 裁剪后的顶点在视锥体内，且重心坐标能还原出顶点的投影坐标
+                assert!(v.pos.z >= -v.pos.w - 0.000001);
+                let pos = interpolate(&v.bc, &abc.0, &abc.1, &abc.2);
+                assert!(approx_eq!(Vec4, pos, v.pos, epsilon = 0.000001));
+                assert!(approx_eq!(Tyf, v.bc.x + v.bc.y + v.bc.z, 1.0, epsilon = 0.000001));
+            }
+        }
+
+        // 反向Z时，z < 0的部分在远平面之外
+        let abc = (
+            Vec4::from(0.0, 0.0, -0.5, 1.0),
+            Vec4::from(0.5, 0.0, 0.5, 1.0),
+            Vec4::from(0.0, 0.5, 0.5, 1.0),
+        );
+        assert_eq!(clip_triangle(&abc, false).len(), 1);
+        let tris = clip_triangle(&abc, true);
+        assert_eq!(tris.len(), 2);
+        assert!(tris.iter().flatten().all(|v| v.pos.z >= -0.000001));
+
+        // 线段穿过近平面和右边界
+        let ab = (Vec4::from(0.0, 0.0, -3.0, 1.0), Vec4::from(1.5, 0.0, 1.0, 1.0));
+        let [a, b] = clip_line(&ab, false).unwrap();
+        assert!(approx_eq!(Vec4, a.pos, Vec4::from(0.75, 0.0, -1.0, 1.0), epsilon = 0.000001));
+        assert!(approx_eq!(Vec3, a.bc, Vec3::from(0.5, 0.5, 0.0), epsilon = 0.000001));
+        assert!(approx_eq!(
+            Vec4,
+            b.pos,
+            Vec4::from(1.0, 0.0, -1.0 / 3.0, 1.0),
+            epsilon = 0.000001
+        ));
+        // 线段完全在视锥体外
+        let ab = (Vec4::from(2.0, -2.0, 0.0, 1.0), Vec4::from(2.0, 2.0, 0.0, 1.0));
+        assert!(clip_line(&ab, false).is_none());
+        let ab = (Vec4::from(2.0, 0.0, 0.0, 1.0), Vec4::from(0.0, 2.5, 0.0, 1.0));
+        assert!(clip_line(&ab, false).is_none());
+
+        assert!(clip_point(&Vec4::from(0.5, 0.5, 0.5, 1.0), false));
+        assert!(!clip_point(&Vec4::from(0.5, 0.5, -0.5, 1.0), true));
+        assert!(!clip_point(&Vec4::from(0.5, 0.5, 0.5, -1.0), false));
+    }
+} /* tests */
@@ -0,0 +1,296 @@
+//! 图像比较
+//!
+//! 用于golden image回归测试：将渲染结果与参考图像（golden image）逐像素比较，
+//! 计算PSNR、SSIM，并生成差异图像，便于发现Rasterizer和着色器的改动引起的渲染变化。
+//!
+//! 颜色buffer与Rasterizer一致，以左下角为原点，按行保存；保存成图像文件时上下翻转。
+//!
+//! ```text
+//! let diff = compare::golden("assets/golden/spot.png", r.sz, r.get_color(), &Tolerance::default());
+//! assert!(diff.is_ok(), "{}", diff.unwrap_err());
+//! ```
+//!
+//! 设置环境变量`ERENDER_UPDATE_GOLDEN`后，golden()会用渲染结果覆盖参考图像。
+
+use std::fmt;
+use std::path::Path;
+
+/// 更新参考图像的环境变量
+pub const UPDATE_GOLDEN: &str = "ERENDER_UPDATE_GOLDEN";
+
+/// 比较容差
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tolerance {
+    /// 像素任一通道的差值超过pixel时，认为像素不匹配
+    pub pixel: u8,
+    /// 允许不匹配的像素比例
+    pub ratio: f64,
+    /// 最小PSNR（dB）
+    pub psnr: f64,
+    /// 最小SSIM
+    pub ssim: f64,
+}
+
+impl Tolerance {
+    /// 要求完全一致
+    pub fn exact() -> Self {
+        Self {
+            pixel: 0,
+            ratio: 0.0,
+            psnr: f64::INFINITY,
+            ssim: 1.0,
+        }
+    }
+}
+
+impl Default for Tolerance {
+    /// 允许浮点计算误差引起的少量边缘像素差异
+    fn default() -> Self {
+        Self {
+            pixel: 2,
+            ratio: 0.002,
+            psnr: 40.0,
+            ssim: 0.99,
+        }
+    }
+}
+
+/// 图像比较结果
+pub struct ImageDiff {
+    /// 大小(w, h)
+    pub sz: (u32, u32),
+    /// 不匹配的像素数
+    pub mismatched: usize,
+    /// 所有通道中的最大差值
+    pub max_diff: u8,
+    /// 峰值信噪比（dB），图像相同时为无穷大
+    pub psnr: f64,
+    /// 结构相似性，范围[-1, 1]，图像相同时为1
+    pub ssim: f64,
+    /// 差异图像：不匹配的像素为红色（差值越大越亮），其余像素为变暗的参考图像
+    pub diff: Vec<[u8; 4]>,
+}
+
+impl ImageDiff {
+    /// 不匹配的像素比例
+    pub fn ratio(&self) -> f64 {
+        self.mismatched as f64 / self.diff.len().max(1) as f64
+    }
+
+    /// 是否在容差范围内
+    pub fn passed(&self, tol: &Tolerance) -> bool {
+        self.ratio() <= tol.ratio && self.psnr >= tol.psnr && self.ssim >= tol.ssim
+    }
+}
+
+impl fmt::Display for ImageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}: mismatched {} ({:.4}%), max diff {}, PSNR {:.2} dB, SSIM {:.5}",
+            self.sz.0,
+            self.sz.1,
+            self.mismatched,
+            self.ratio() * 100.0,
+            self.max_diff,
+            self.psnr,
+            self.ssim
+        )
+    }
+}
+
+/// 比较sz(w, h)大小的图像a和参考图像b
+///
+/// - pixel: 像素任一通道的差值超过pixel时，认为像素不匹配
+pub fn compare(sz: (u32, u32), a: &[[u8; 4]], b: &[[u8; 4]], pixel: u8) -> ImageDiff {
+    assert_eq!(a.len(), (sz.0 * sz.1) as usize);
+    assert_eq!(a.len(), b.len());
+    let mut mismatched = 0;
+    let mut max_diff = 0;
+    let diff = a
+        .iter()
+        .zip(b)
+        .map(|(pa, pb)| {
+            let d = (0..4).map(|k| pa[k].abs_diff(pb[k])).max().unwrap();
+            max_diff = max_diff.max(d);
+            if d > pixel {
+                mismatched += 1;
+                [128 + d / 2, 0, 0, 255]
+            } else {
+                let l = (luma(pb) / 4.0) as u8;
+                [l, l, l, 255]
+            }
+        })
+        .collect();
+    ImageDiff {
+        sz,
+        mismatched,
+        max_diff,
+        psnr: psnr(a, b),
+        ssim: ssim(sz, a, b),
+        diff,
+    }
+}
+
+/// 计算峰值信噪比（RGBA四个通道）
+pub fn psnr(a: &[[u8; 4]], b: &[[u8; 4]]) -> f64 {
+    let sum: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(pa, pb)| (0..4).map(|k| (pa[k] as f64 - pb[k] as f64).powi(2)).sum::<f64>())
+        .sum();
+    let mse = sum / (a.len().max(1) * 4) as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+/// 计算结构相似性（亮度通道）
+///
+/// 使用8x8窗口、步长4计算每个窗口的SSIM，再取平均；图像小于窗口时，整个图像作为一个窗口。
+pub fn ssim(sz: (u32, u32), a: &[[u8; 4]], b: &[[u8; 4]]) -> f64 {
+    const WIN: u32 = 8;
+    const STEP: u32 = 4;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let la: Vec<f64> = a.iter().map(luma).collect();
+    let lb: Vec<f64> = b.iter().map(luma).collect();
+    let (ww, wh) = (WIN.min(sz.0), WIN.min(sz.1));
+    let n = (ww * wh) as f64;
+    let mut sum = 0.0;
+    let mut cnt = 0;
+    for y in (0..=sz.1 - wh).step_by(STEP as usize) {
+        for x in (0..=sz.0 - ww).step_by(STEP as usize) {
+            let (mut ma, mut mb, mut va, mut vb, mut cov) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for j in y..y + wh {
+                for i in x..x + ww {
+                    let k = (i + j * sz.0) as usize;
+                    ma += la[k];
+                    mb += lb[k];
+                }
+            }
+            ma /= n;
+            mb /= n;
+            for j in y..y + wh {
+                for i in x..x + ww {
+                    let k = (i + j * sz.0) as usize;
+                    let (da, db) = (la[k] - ma, lb[k] - mb);
+                    va += da * da;
+                    vb += db * db;
+                    cov += da * db;
+                }
+            }
+            va /= n;
+            vb /= n;
+            cov /= n;
+            sum += ((2.0 * ma * mb + C1) * (2.0 * cov + C2)) / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            cnt += 1;
+        }
+    }
+    sum / cnt as f64
+}
+
+#[inline]
+fn luma(c: &[u8; 4]) -> f64 {
+    0.299 * c[0] as f64 + 0.587 * c[1] as f64 + 0.114 * c[2] as f64
+}
+
+/// 颜色buffer转换成图像（上下翻转）
+pub fn to_image(sz: (u32, u32), buf: &[[u8; 4]]) -> image::RgbaImage {
+    image::RgbaImage::from_fn(sz.0, sz.1, |x, y| image::Rgba(buf[(x + (sz.1 - 1 - y) * sz.0) as usize]))
+}
+
+/// 图像转换成颜色buffer（上下翻转）
+pub fn from_image(img: &image::RgbaImage) -> ((u32, u32), Vec<[u8; 4]>) {
+    let (wid, hei) = img.dimensions();
+    let buf = (0..hei)
+        .flat_map(|y| (0..wid).map(move |x| img.get_pixel(x, hei - 1 - y).0))
+        .collect();
+    ((wid, hei), buf)
+}
+
+/// 与path处的参考图像比较
+///
+/// 参考图像不存在或设置了`ERENDER_UPDATE_GOLDEN`时，保存buf为参考图像；
+/// 超出容差时，在参考图像旁保存渲染结果（`*.actual.png`）和差异图像（`*.diff.png`），并返回错误信息。
+pub fn golden<P: AsRef<Path>>(path: P, sz: (u32, u32), buf: &[[u8; 4]], tol: &Tolerance) -> Result<ImageDiff, String> {
+    let path = path.as_ref();
+    let save = |p: &Path, b: &[[u8; 4]]| {
+        to_image(sz, b)
+            .save(p)
+            .map_err(|e| format!("Failed to save {}: {}", p.display(), e))
+    };
+    if std::env::var_os(UPDATE_GOLDEN).is_some() || !path.exists() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        save(path, buf)?;
+        return Ok(compare(sz, buf, buf, 0));
+    }
+
+    let img = image::open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?
+        .to_rgba8();
+    let (gsz, gbuf) = from_image(&img);
+    if gsz != sz {
+        return Err(format!("{}: size {:?} != golden size {:?}", path.display(), sz, gsz));
+    }
+    let diff = compare(sz, buf, &gbuf, tol.pixel);
+    if diff.passed(tol) {
+        Ok(diff)
+    } else {
+        save(&path.with_extension("actual.png"), buf)?;
+        save(&path.with_extension("diff.png"), &diff.diff)?;
+        Err(format!("{}: {}", path.display(), diff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_test() {
+        let sz = (16, 12);
+        let a: Vec<[u8; 4]> = (0..sz.0 * sz.1)
+            .map(|k| [(k % 256) as u8, (k * 7 % 256) as u8, 64, 255])
+            .collect();
+
+        let d = compare(sz, &a, &a, 0);
+        assert_eq!(d.mismatched, 0);
+        assert_eq!(d.max_diff, 0);
+        assert_eq!(d.psnr, f64::INFINITY);
+        assert!((d.ssim - 1.0).abs() < 1e-9);
+        assert!(d.passed(&Tolerance::exact()));
+
+        // 少量像素的小误差在默认容差内
+        let mut b = a.clone();
+        b[5][0] += 2;
+        b[100][1] -= 1;
+        let d = compare(sz, &b, &a, Tolerance::default().pixel);
+        assert_eq!((d.mismatched, d.max_diff), (0, 2));
+        assert!(d.psnr > 50.0 && d.ssim > 0.999);
+        assert!(d.passed(&Tolerance::default()));
+        assert!(!d.passed(&Tolerance::exact()));
+
+        // 大块区域变化
+        let mut c = a.clone();
+        for p in &mut c[..64] {
+            *p = [255, 255, 255, 255];
+        }
+        let d = compare(sz, &c, &a, 2);
+        assert!(d.mismatched > 32);
+        assert_eq!(d.diff[0][1], 0);
+        assert!(d.psnr < 40.0 && d.ssim < 0.99);
+        assert!(!d.passed(&Tolerance::default()));
+
+        // 上下翻转保存后可以还原
+        let (isz, ibuf) = from_image(&to_image(sz, &a));
+        assert_eq!(isz, sz);
+        assert_eq!(ibuf, a);
+        assert_eq!(to_image(sz, &a).get_pixel(0, sz.1 - 1).0, a[0]);
+    }
+} /* tests */
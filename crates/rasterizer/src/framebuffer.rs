@@ -0,0 +1,193 @@
+//! 帧缓冲对象（Framebuffer Object）
+//!
+//! 类似OpenGL中的FBO：保存一组任意大小的buffer，绑定到Rasterizer后作为渲染目标（render-to-texture），
+//! 解绑后可以读取渲染结果，或转换成贴图在之后的绘制中采样（如镜面、画中画和阴影贴图）。
+//!
+//! 绑定时与Rasterizer当前的buffer交换，不复制buffer：
+//!
+//! ```text
+//! let screen = r.bind_framebuffer(fbo);   // 渲染到fbo
+//! ...
+//! let fbo = r.bind_framebuffer(screen);   // 恢复渲染到屏幕
+//! ```
+
+use crate::hiz::HiZ;
+use crate::oit::FragLists;
+use crate::rasterizer::EMsaa;
+use crate::shader::GlslVars;
+use std::mem::swap;
+
+/// 帧缓冲对象
+///
+/// 包括颜色、深度和模板buffer，以及绑定后开启的多重采样、HDR和附加渲染目标buffer；
+/// 混合、深度测试等渲染状态属于Rasterizer，不随帧缓冲切换。
+pub struct Framebuffer {
+    /// 大小(w, h)
+    pub(crate) sz: (u32, u32),
+    cbuf: Vec<[u8; 4]>,
+    zbuf: Vec<f32>,
+    sbuf: Vec<u8>,
+    hdr_cbuf: Vec<[f32; 4]>,
+    msaa: EMsaa,
+    ms_cbuf: Vec<[f32; 4]>,
+    ms_zbuf: Vec<f32>,
+    ms_sbuf: Vec<u8>,
+    hiz: HiZ,
+    targets: Vec<Vec<[f32; 4]>>,
+    overdraw: Vec<u32>,
+    frags: FragLists,
+}
+
+impl Framebuffer {
+    /// 创建sz(w, h)大小的帧缓冲，颜色清除为0，深度清除为1，模板清除为0
+    ///
+    /// 绑定后可以通过Rasterizer开启多重采样、HDR和附加渲染目标；开启反向Z时，需要在绑定后清除深度buffer。
+    pub fn new(sz: (u32, u32)) -> Self {
+        let max = (sz.0 * sz.1) as usize;
+        Self {
+            sz,
+            cbuf: vec![[0; 4]; max],
+            zbuf: vec![1.0; max],
+            sbuf: vec![0; max],
+            hdr_cbuf: Vec::new(),
+            msaa: EMsaa::Off,
+            ms_cbuf: Vec::new(),
+            ms_zbuf: Vec::new(),
+            ms_sbuf: Vec::new(),
+            hiz: HiZ::new(),
+            targets: Vec::new(),
+            overdraw: vec![0; max],
+            frags: FragLists::new(),
+        }
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> (u32, u32) {
+        self.sz
+    }
+
+    /// 返回颜色buffer（以左下角为原点，按行保存）
+    ///
+    /// 开启多重采样或HDR时，需要在解绑前调用`Rasterizer::resolve`。
+    #[inline]
+    pub fn get_color(&self) -> &Vec<[u8; 4]> {
+        &self.cbuf
+    }
+
+    /// 返回HDR颜色buffer（未开启HDR时为空）
+    #[inline]
+    pub fn get_hdr_color(&self) -> &Vec<[f32; 4]> {
+        &self.hdr_cbuf
+    }
+
+    #[inline]
+    pub fn get_depth(&self) -> &Vec<f32> {
+        &self.zbuf
+    }
+
+    #[inline]
+    pub fn get_stencil(&self) -> &Vec<u8> {
+        &self.sbuf
+    }
+
+    /// 返回location处的附加渲染目标（location从1开始）
+    #[inline]
+    pub fn get_target(&self, location: usize) -> &Vec<[f32; 4]> {
+        &self.targets[location - 1]
+    }
+
+    /// 返回overdraw buffer（每个像素调用片段着色器的次数）
+    #[inline]
+    pub fn get_overdraw(&self) -> &Vec<u32> {
+        &self.overdraw
+    }
+
+    /// 与gv的buffer交换
+    pub(crate) fn swap(&mut self, gv: &mut GlslVars) {
+        swap(&mut self.cbuf, &mut gv.cbuf);
+        swap(&mut self.zbuf, &mut gv.zbuf);
+        swap(&mut self.sbuf, &mut gv.sbuf);
+        swap(&mut self.hdr_cbuf, &mut gv.hdr_cbuf);
+        swap(&mut self.msaa, &mut gv.msaa);
+        swap(&mut self.ms_cbuf, &mut gv.ms_cbuf);
+        swap(&mut self.ms_zbuf, &mut gv.ms_zbuf);
+        swap(&mut self.ms_sbuf, &mut gv.ms_sbuf);
+        swap(&mut self.hiz, &mut gv.hiz);
+        swap(&mut self.targets, &mut gv.targets);
+        swap(&mut self.overdraw, &mut gv.overdraw);
+        swap(&mut self.frags, &mut gv.frags);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{IPipeline, IPrimitive};
+    use crate::rasterizer::Rasterizer;
+    use crate::shader::{triangle_vertex, FragOutputs, IGlsl, IShader, Instance};
+    use crate::varying::Varyings;
+    use magx::*;
+    use std::ops::Range;
+
+    /// 覆盖整个屏幕的三角形
+    struct Full(Vec4);
+
+    impl IPrimitive for Full {
+        fn indices(&self) -> Range<usize> {
+            0..1
+        }
+    }
+
+    impl IShader for Full {
+        fn vertex(&self, _pidx: usize, _inst: &Instance) -> ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)) {
+            let a = Vec4::from(-1.0, -1.0, 0.0, 1.0);
+            let b = Vec4::from(3.0, -1.0, 0.0, 1.0);
+            let c = Vec4::from(-1.0, 3.0, 0.0, 1.0);
+            ((a, b, c), (Varyings::new(), Varyings::new(), Varyings::new()))
+        }
+
+        fn vertex_at(&self, vidx: usize, inst: &Instance) -> (Vec4, Varyings) {
+            triangle_vertex(self.vertex(vidx / 3, inst), vidx % 3)
+        }
+
+        fn fragment(&self, _pidx: usize, _inst: &Instance, _v: &Varyings) -> FragOutputs {
+            self.0.into()
+        }
+    }
+
+    #[test]
+    fn framebuffer_test() {
+        let (wid, hei) = (16, 8);
+        let mut r = Rasterizer::new((wid, hei));
+        *r.wire_frame() = false;
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+
+        for (sz, msaa) in [((5, 3), EMsaa::Off), ((32, 20), EMsaa::X4)] {
+            let screen = r.bind_framebuffer(Framebuffer::new(sz));
+            assert_eq!(r.sz, sz);
+            assert_eq!(screen.get_size(), (wid, hei));
+            r.set_msaa(msaa);
+            r.clear_color(&Vec4::fill(0.0));
+            r.clear_depth();
+            let primitive: Box<dyn IPrimitive> = Box::new(Full(Vec4::from(1.0, 0.0, 0.0, 1.0)));
+            r.draw(primitive.as_ref());
+            r.resolve();
+            let fbo = r.bind_framebuffer(screen);
+
+            // 帧缓冲中保存渲染结果，绑定回来的buffer没有被修改
+            assert_eq!(r.sz, (wid, hei));
+            assert_eq!(r.get_msaa(), EMsaa::Off);
+            assert_eq!(fbo.get_color().len(), (sz.0 * sz.1) as usize);
+            assert!(fbo.get_color().iter().all(|c| *c == [255, 0, 0, 255]));
+            assert!(fbo.get_depth().iter().all(|z| *z == 0.5));
+            assert!(r.get_color().iter().all(|c| *c == [0, 0, 0, 0]));
+            assert!(r.get_depth().iter().all(|z| *z == 1.0));
+        }
+
+        // 绑定回来的buffer仍然可以正常渲染
+        let primitive: Box<dyn IPrimitive> = Box::new(Full(Vec4::fill(1.0)));
+        r.draw(primitive.as_ref());
+        assert!(r.get_color().iter().all(|c| *c == [255; 4]));
+    }
+} /* tests */
@@ -0,0 +1,259 @@
+//! 层次深度（Hierarchical-Z）
+//!
+//! 将深度buffer按HIZ_CELL x HIZ_CELL个像素分成单元，保存每个单元中深度值的范围(最小值, 最大值)；
+//! 再逐层将2x2个单元合并成上一层的一个单元，直到只剩一个单元，构成深度金字塔。
+//!
+//! 光栅化前用片段的深度范围与覆盖区域的深度范围比较，
+//! 可以直接剃除一定不能通过深度测试的三角形和单元，不需要逐个像素计算重心坐标和深度测试。
+
+use crate::state::ECompareFunc;
+
+/// 深度金字塔最底层单元的大小（像素）
+pub const HIZ_CELL: u32 = 8;
+
+/// 深度金字塔的一层
+#[derive(Debug, Clone, Default)]
+struct Level {
+    w: u32,
+    h: u32,
+    /// 每个单元中深度值的范围(最小值, 最大值)
+    cells: Vec<(f32, f32)>,
+}
+
+/// 深度金字塔
+///
+/// 深度buffer修改时只标记对应的单元，使用前再通过`sync`更新。
+#[derive(Debug, Clone, Default)]
+pub struct HiZ {
+    /// 深度buffer的大小(w, h)和每个像素的采样点数量
+    dims: (u32, u32, usize),
+    /// 从最底层到最顶层（只有一个单元）
+    levels: Vec<Level>,
+    /// 最底层中需要重新计算的单元
+    dirty: Vec<bool>,
+    /// 最底层中需要重新计算的单元索引
+    pending: Vec<usize>,
+    /// 是否需要重建整个金字塔
+    invalid: bool,
+}
+
+impl HiZ {
+    pub fn new() -> Self {
+        Self {
+            invalid: true,
+            ..Default::default()
+        }
+    }
+
+    /// 整个深度buffer都已经修改（如清除深度buffer）
+    #[inline]
+    pub fn invalidate(&mut self) {
+        self.invalid = true;
+    }
+
+    /// 深度buffer中第k个像素的深度值已经修改
+    #[inline]
+    pub fn mark(&mut self, k: usize) {
+        if self.invalid {
+            return;
+        }
+        let w = self.dims.0 as usize;
+        self.mark_cell((k % w) as u32 / HIZ_CELL, (k / w) as u32 / HIZ_CELL);
+    }
+
+    /// 深度buffer中rect区域(x, y, w, h)的深度值已经修改
+    pub fn mark_rect(&mut self, rect: (u32, u32, u32, u32)) {
+        if self.invalid || rect.2 == 0 || rect.3 == 0 {
+            return;
+        }
+        let (x, y, w, h) = rect;
+        for cy in y / HIZ_CELL..=(y + h - 1) / HIZ_CELL {
+            for cx in x / HIZ_CELL..=(x + w - 1) / HIZ_CELL {
+                self.mark_cell(cx, cy);
+            }
+        }
+    }
+
+    #[inline]
+    fn mark_cell(&mut self, cx: u32, cy: u32) {
+        let k = (cx + cy * self.levels[0].w) as usize;
+        if !self.dirty[k] {
+            self.dirty[k] = true;
+            self.pending.push(k);
+        }
+    }
+
+    /// 根据深度buffer更新深度金字塔
+    ///
+    /// - zbuf: 深度buffer（开启MSAA时为多重采样深度buffer）
+    /// - w, h: 深度buffer的大小
+    /// - n: 每个像素的采样点数量
+    pub fn sync(&mut self, zbuf: &[f32], w: u32, h: u32, n: usize) {
+        if self.invalid || self.dims != (w, h, n) {
+            self.rebuild(zbuf, w, h, n);
+            return;
+        }
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut cells = std::mem::take(&mut self.pending);
+        for &k in &cells {
+            self.dirty[k] = false;
+            self.levels[0].cells[k] = self.cell_range(zbuf, k);
+        }
+        // 逐层更新被修改单元的父单元
+        for l in 1..self.levels.len() {
+            let cw = self.levels[l - 1].w as usize;
+            let pw = self.levels[l].w as usize;
+            for k in cells.iter_mut() {
+                *k = (*k % cw) / 2 + (*k / cw) / 2 * pw;
+            }
+            cells.sort_unstable();
+            cells.dedup();
+            for &k in &cells {
+                self.levels[l].cells[k] = self.merge(l, k);
+            }
+        }
+    }
+
+    /// 重建整个深度金字塔
+    fn rebuild(&mut self, zbuf: &[f32], w: u32, h: u32, n: usize) {
+        self.dims = (w, h, n);
+        self.levels.clear();
+        let (mut lw, mut lh) = (w.div_ceil(HIZ_CELL).max(1), h.div_ceil(HIZ_CELL).max(1));
+        loop {
+            self.levels.push(Level {
+                w: lw,
+                h: lh,
+                cells: vec![(0.0, 0.0); (lw * lh) as usize],
+            });
+            if lw == 1 && lh == 1 {
+                break;
+            }
+            (lw, lh) = (lw.div_ceil(2), lh.div_ceil(2));
+        }
+        for k in 0..self.levels[0].cells.len() {
+            self.levels[0].cells[k] = self.cell_range(zbuf, k);
+        }
+        for l in 1..self.levels.len() {
+            for k in 0..self.levels[l].cells.len() {
+                self.levels[l].cells[k] = self.merge(l, k);
+            }
+        }
+        self.dirty = vec![false; self.levels[0].cells.len()];
+        self.pending.clear();
+        self.invalid = false;
+    }
+
+    /// 计算最底层第k个单元中深度值的范围
+    fn cell_range(&self, zbuf: &[f32], k: usize) -> (f32, f32) {
+        let (w, h, n) = self.dims;
+        let lw = self.levels[0].w as usize;
+        let x0 = (k % lw) as u32 * HIZ_CELL;
+        let y0 = (k / lw) as u32 * HIZ_CELL;
+        let mut range = (f32::MAX, f32::MIN);
+        for j in y0..(y0 + HIZ_CELL).min(h) {
+            for i in x0..(x0 + HIZ_CELL).min(w) {
+                let p = (i + j * w) as usize * n;
+                for z in &zbuf[p..p + n] {
+                    range = (range.0.min(*z), range.1.max(*z));
+                }
+            }
+        }
+        range
+    }
+
+    /// 合并第l - 1层中的2x2个单元，得到第l层第k个单元的深度范围
+    fn merge(&self, l: usize, k: usize) -> (f32, f32) {
+        let child = &self.levels[l - 1];
+        let pw = self.levels[l].w as usize;
+        let (cx, cy) = ((k % pw) as u32 * 2, (k / pw) as u32 * 2);
+        let mut range = (f32::MAX, f32::MIN);
+        for y in cy..(cy + 2).min(child.h) {
+            for x in cx..(cx + 2).min(child.w) {
+                let c = child.cells[(x + y * child.w) as usize];
+                range = (range.0.min(c.0), range.1.max(c.1));
+            }
+        }
+        range
+    }
+
+    /// 像素区域[x0, x1) x [y0, y1)中深度值的范围（保守估计，可能比实际范围大）
+    ///
+    /// 使用最多覆盖2x2个单元的一层计算。
+    pub fn range(&self, x0: u32, y0: u32, x1: u32, y1: u32) -> (f32, f32) {
+        let (mut cx0, mut cy0) = (x0 / HIZ_CELL, y0 / HIZ_CELL);
+        let (mut cx1, mut cy1) = ((x1 - 1) / HIZ_CELL, (y1 - 1) / HIZ_CELL);
+        let mut l = 0;
+        while (cx1 - cx0 > 1 || cy1 - cy0 > 1) && l + 1 < self.levels.len() {
+            (cx0, cy0, cx1, cy1) = (cx0 / 2, cy0 / 2, cx1 / 2, cy1 / 2);
+            l += 1;
+        }
+        let level = &self.levels[l];
+        let mut range = (f32::MAX, f32::MIN);
+        for cy in cy0..=cy1.min(level.h - 1) {
+            for cx in cx0..=cx1.min(level.w - 1) {
+                let c = level.cells[(cx + cy * level.w) as usize];
+                range = (range.0.min(c.0), range.1.max(c.1));
+            }
+        }
+        range
+    }
+
+    /// 深度范围为[zmin, zmax]的片段，在深度值范围为range的区域中是否一定不能通过深度测试
+    pub fn occluded(func: ECompareFunc, zmin: f32, zmax: f32, range: (f32, f32)) -> bool {
+        match func {
+            ECompareFunc::Never => true,
+            ECompareFunc::Less => zmin >= range.1,
+            ECompareFunc::LEqual => zmin > range.1,
+            ECompareFunc::Greater => zmax <= range.0,
+            ECompareFunc::GEqual => zmax < range.0,
+            ECompareFunc::Equal | ECompareFunc::NotEqual | ECompareFunc::Always => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hiz() {
+        let (w, h) = (20, 12);
+        let mut zbuf = vec![1.0; (w * h) as usize];
+        let mut hiz = HiZ::new();
+        hiz.sync(&zbuf, w, h, 1);
+        assert_eq!(hiz.levels.len(), 3);
+        assert_eq!(hiz.range(0, 0, w, h), (1.0, 1.0));
+
+        // 左下角的单元写入更近的深度值
+        for j in 0..HIZ_CELL {
+            for i in 0..HIZ_CELL {
+                let k = (i + j * w) as usize;
+                zbuf[k] = 0.25;
+                hiz.mark(k);
+            }
+        }
+        zbuf[(19 + 11 * w) as usize] = 0.5;
+        hiz.mark((19 + 11 * w) as usize);
+        hiz.sync(&zbuf, w, h, 1);
+        assert_eq!(hiz.range(0, 0, 8, 8), (0.25, 0.25));
+        assert_eq!(hiz.range(2, 3, 5, 4), (0.25, 0.25));
+        assert_eq!(hiz.range(8, 0, 16, 8), (1.0, 1.0));
+        assert_eq!(hiz.range(16, 8, 20, 12), (0.5, 1.0));
+        assert_eq!(hiz.range(0, 0, w, h), (0.25, 1.0));
+
+        // 与重建的金字塔一致
+        let mut full = HiZ::new();
+        full.sync(&zbuf, w, h, 1);
+        for (a, b) in hiz.levels.iter().zip(&full.levels) {
+            assert_eq!(a.cells, b.cells);
+        }
+
+        assert!(HiZ::occluded(ECompareFunc::Less, 0.25, 0.3, (0.1, 0.25)));
+        assert!(!HiZ::occluded(ECompareFunc::LEqual, 0.25, 0.3, (0.1, 0.25)));
+        assert!(HiZ::occluded(ECompareFunc::Greater, 0.05, 0.1, (0.1, 0.25)));
+        assert!(!HiZ::occluded(ECompareFunc::GEqual, 0.05, 0.1, (0.1, 0.25)));
+        assert!(!HiZ::occluded(ECompareFunc::Always, 1.0, 1.0, (0.0, 0.0)));
+    }
+} /* tests */
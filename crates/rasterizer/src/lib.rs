@@ -62,6 +62,13 @@
 //! 视口变换会将NC坐标映射到屏幕坐标系上。
 //!
 
+pub mod clip;
+pub mod compare;
+pub mod framebuffer;
+pub mod hiz;
+pub mod oit;
 pub mod pipeline;
 pub mod rasterizer;
 pub mod shader;
+pub mod state;
+pub mod varying;
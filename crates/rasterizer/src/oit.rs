@@ -0,0 +1,213 @@
+//! 顺序无关透明（Order-Independent Transparency）
+//!
+//! 按mesh排序后再混合透明片段，无法处理相互穿插的透明三角形（同一个mesh内的片段也没有排序）。
+//! 开启OIT的绘制调用不直接混合片段颜色，而是将通过深度测试的片段(depth, color)保存到逐像素链表中，
+//! 在帧结束时（`Rasterizer::resolve`）按深度从远到近排序，再依次与颜色buffer混合：
+//!
+//! - A-buffer：每个像素的所有片段都参与排序和混合，结果与片段的提交顺序无关
+//! - 深度剥离（depth peeling）：每一层取比上一层更远的最近片段，最多剥离layers层，超过的片段被丢弃；
+//!   与GPU上每层重新绘制一次场景的结果相同（同一深度的片段只保留最先提交的一个），用于与A-buffer比较
+//!
+//! 链表节点保存在一个数组中，每个像素只保存链表头的索引：
+//!
+//! ```text
+//! heads: [ 2, NIL, 3, ... ]
+//! nodes: [ {next: NIL}, {next: 0}, {next: 1}, {next: NIL} ]
+//!          0            1          2          3
+//! ```
+
+use crate::state::BlendState;
+
+/// 空链表
+const NIL: u32 = u32::MAX;
+
+/// 透明片段的处理方式（类似混合状态，作用于之后的绘制调用）
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EOit {
+    /// 片段直接与颜色buffer混合
+    Off,
+    /// 保存所有片段，排序后混合
+    ABuffer,
+    /// 保存片段，通过深度剥离取出最近的若干层后混合
+    DepthPeeling,
+}
+
+/// 保存到链表中的透明片段
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Fragment {
+    /// 深度值（覆盖的采样点的平均深度）
+    pub depth: f32,
+    /// 片段颜色
+    pub color: [f32; 4],
+    /// 覆盖的采样点掩码
+    pub mask: u32,
+    /// 像素被覆盖的比例（抗锯齿线段小于1）
+    pub coverage: f32,
+    /// 绘制时的混合状态
+    pub blend: BlendState,
+}
+
+/// 链表节点
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    frag: Fragment,
+    /// 是否通过深度剥离处理
+    peel: bool,
+    /// 下一个节点的索引
+    next: u32,
+}
+
+/// 逐像素片段链表
+#[derive(Debug, Clone, Default)]
+pub struct FragLists {
+    /// 每个像素链表头的索引（没有片段时为空）
+    heads: Vec<u32>,
+    nodes: Vec<Node>,
+}
+
+impl FragLists {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 保存的片段数量
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// 清除所有片段
+    #[inline]
+    pub fn clear(&mut self) {
+        self.heads.clear();
+        self.nodes.clear();
+    }
+
+    /// 将片段添加到第k个像素的链表头
+    ///
+    /// - max: 像素数量（第一次添加片段时分配链表头）
+    /// - peel: 是否通过深度剥离处理
+    pub fn push(&mut self, max: usize, k: usize, frag: Fragment, peel: bool) {
+        if self.heads.is_empty() {
+            self.heads = vec![NIL; max];
+        }
+        self.nodes.push(Node {
+            frag,
+            peel,
+            next: self.heads[k],
+        });
+        self.heads[k] = (self.nodes.len() - 1) as u32;
+    }
+
+    /// 按提交顺序返回第k个像素的链表节点
+    fn list(&self, k: usize) -> Vec<&Node> {
+        let mut out = Vec::new();
+        let mut n = self.heads.get(k).copied().unwrap_or(NIL);
+        while n != NIL {
+            let node = &self.nodes[n as usize];
+            out.push(node);
+            n = node.next;
+        }
+        out.reverse();
+        out
+    }
+
+    /// 将分块渲染保存的片段合并到rect区域(x, y, w, h)
+    ///
+    /// - w: 当前buffer的宽度
+    /// - max: 当前buffer的像素数量
+    pub fn merge(&mut self, w: u32, max: usize, rect: (u32, u32, u32, u32), tile: &Self) {
+        let (x, y, rw, _) = rect;
+        for k in 0..tile.heads.len() {
+            let i = x + k as u32 % rw;
+            let j = y + k as u32 / rw;
+            for node in tile.list(k) {
+                self.push(max, (i + j * w) as usize, node.frag, node.peel);
+            }
+        }
+    }
+
+    /// 返回第k个像素需要混合的片段，按深度从远到近排列
+    ///
+    /// - reversed: 是否为反向Z（离视点越近深度值越大）
+    /// - layers: 深度剥离的最大层数
+    pub fn sorted(&self, k: usize, reversed: bool, layers: usize) -> Vec<Fragment> {
+        // 转换成越小离视点越近的深度
+        let near = |f: &Fragment| if reversed { -f.depth } else { f.depth };
+        let list = self.list(k);
+        let mut out: Vec<Fragment> = list.iter().filter(|n| !n.peel).map(|n| n.frag).collect();
+
+        // 每层相当于开启深度测试（Less）重新绘制一遍，只保留比上一层更远的片段
+        let mut prev = f32::NEG_INFINITY;
+        for _ in 0..layers {
+            let layer = list
+                .iter()
+                .filter(|n| n.peel && near(&n.frag) > prev)
+                .min_by(|a, b| near(&a.frag).total_cmp(&near(&b.frag)));
+            match layer {
+                Some(n) => {
+                    prev = near(&n.frag);
+                    out.push(n.frag);
+                }
+                None => break,
+            }
+        }
+
+        // 稳定排序，深度相同时保持提交顺序
+        out.sort_by(|a, b| near(b).total_cmp(&near(a)));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oit_test() {
+        let frag = |depth: f32, r: f32| Fragment {
+            depth,
+            color: [r, 0.0, 0.0, 0.5],
+            mask: 1,
+            coverage: 1.0,
+            blend: BlendState::alpha(),
+        };
+        let depths = |frags: Vec<Fragment>| frags.iter().map(|f| f.depth).collect::<Vec<_>>();
+
+        let mut lists = FragLists::new();
+        assert!(lists.sorted(0, false, 4).is_empty());
+        for d in [0.5, 0.2, 0.8, 0.2] {
+            lists.push(4, 1, frag(d, 0.0), false);
+        }
+        assert_eq!(lists.len(), 4);
+        assert!(lists.sorted(0, false, 4).is_empty());
+        assert_eq!(depths(lists.sorted(1, false, 4)), [0.8, 0.5, 0.2, 0.2]);
+        assert_eq!(depths(lists.sorted(1, true, 4)), [0.2, 0.2, 0.5, 0.8]);
+
+        // 深度剥离：同一深度只保留最先提交的片段，超过层数的远处片段被丢弃
+        lists.clear();
+        for (d, r) in [(0.5, 0.1), (0.2, 0.2), (0.8, 0.3), (0.2, 0.4), (0.6, 0.5)] {
+            lists.push(4, 2, frag(d, r), true);
+        }
+        let peeled = lists.sorted(2, false, 4);
+        assert_eq!(depths(peeled.clone()), [0.8, 0.6, 0.5, 0.2]);
+        assert_eq!(peeled[3].color[0], 0.2);
+        assert_eq!(depths(lists.sorted(2, false, 2)), [0.5, 0.2]);
+        assert_eq!(depths(lists.sorted(2, true, 2)), [0.6, 0.8]);
+
+        // 合并分块的片段：tile中的(1, 0)对应rect中的(3, 2)
+        let mut tile = FragLists::new();
+        tile.push(4, 1, frag(0.3, 0.0), false);
+        tile.push(4, 1, frag(0.4, 0.0), true);
+        let mut all = FragLists::new();
+        all.merge(5, 20, (2, 2, 2, 2), &tile);
+        assert_eq!(all.len(), 2);
+        assert_eq!(depths(all.sorted(13, false, 1)), [0.4, 0.3]);
+        assert!(all.sorted(1, false, 1).is_empty());
+    }
+} /* tests */
@@ -1,17 +1,179 @@
 //! Graphics Pipeline
 
-use crate::rasterizer::IRasterizer;
-use crate::shader::{IGlsl, IShader};
+use crate::clip::ClipTriangle;
+use crate::rasterizer::{IRasterizer, MAX_SAMPLES};
+use crate::shader::{IGlsl, IShader, Instance};
+use crate::varying::Varyings;
 use magx::*;
-use std::ops::Range;
+use std::fmt::{Display, Formatter};
+use std::ops::{AddAssign, Range};
+
+/// 图元拓扑
+///
+/// 决定如何将顶点组装成基本图元（点、线段或三角形）。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ETopology {
+    /// 每个顶点为一个点
+    Points,
+    /// 每2个顶点为一条线段
+    Lines,
+    /// 相邻的2个顶点为一条线段
+    LineStrip,
+    /// 每3个顶点为一个三角形
+    Triangles,
+    /// 相邻的3个顶点为一个三角形（奇数三角形交换前两个顶点，保持顶点顺序一致）
+    TriangleStrip,
+    /// 第0个顶点和相邻的2个顶点为一个三角形
+    TriangleFan,
+}
+
+impl ETopology {
+    /// 每个基本图元的顶点数量
+    pub fn vertices(&self) -> usize {
+        match self {
+            ETopology::Points => 1,
+            ETopology::Lines | ETopology::LineStrip => 2,
+            ETopology::Triangles | ETopology::TriangleStrip | ETopology::TriangleFan => 3,
+        }
+    }
+
+    /// n个顶点组装成的基本图元数量
+    pub fn count(&self, n: usize) -> usize {
+        match self {
+            ETopology::Points => n,
+            ETopology::Lines => n / 2,
+            ETopology::LineStrip => n.saturating_sub(1),
+            ETopology::Triangles => n / 3,
+            ETopology::TriangleStrip | ETopology::TriangleFan => n.saturating_sub(2),
+        }
+    }
+
+    /// 第k个基本图元的顶点index（相对于第一个顶点，只有前vertices()个有效）
+    pub fn assemble(&self, k: usize) -> [usize; 3] {
+        match self {
+            ETopology::Points => [k, k, k],
+            ETopology::Lines => [2 * k, 2 * k + 1, 2 * k + 1],
+            ETopology::LineStrip => [k, k + 1, k + 1],
+            ETopology::Triangles => [3 * k, 3 * k + 1, 3 * k + 2],
+            ETopology::TriangleStrip if k % 2 == 1 => [k + 1, k, k + 2],
+            ETopology::TriangleStrip => [k, k + 1, k + 2],
+            ETopology::TriangleFan => [0, k + 1, k + 2],
+        }
+    }
+}
 
 /// 图元接口
 ///
 /// 这里IPrimitive是指图元集合，便于着色器IShader应用到所有图元。
-/// 这里只支持三角形图元（即三角形片段）。
+/// 图元按拓扑组装成点、线段或三角形（即三角形片段）。
 pub trait IPrimitive: IShader {
     /// 图元index范围
+    ///
+    /// Triangles拓扑时为三角形index（见`IShader::vertex`），其它拓扑时为顶点index（见`IShader::vertex_at`）。
     fn indices(&self) -> Range<usize>;
+
+    /// 图元拓扑
+    fn topology(&self) -> ETopology {
+        ETopology::Triangles
+    }
+
+    /// 组装后的基本图元index范围
+    fn primitives(&self) -> Range<usize> {
+        let topology = self.topology();
+        if topology == ETopology::Triangles {
+            self.indices()
+        } else {
+            0..topology.count(self.indices().len())
+        }
+    }
+
+    /// 是否为半透明图元
+    ///
+    /// 半透明图元需要开启混合，在所有不透明图元之后，按从远到近的顺序绘制。
+    fn transparent(&self) -> bool {
+        false
+    }
+
+    /// 图元在世界坐标系中的中心位置，用于半透明图元的排序
+    fn center(&self) -> Vec3 {
+        Vec3::new()
+    }
+
+    /// 图元包围盒8个顶点的投影坐标，用于遮挡查询
+    ///
+    /// 顶点索引的第0, 1, 2位分别表示顶点在x, y, z方向上取包围盒的最小值(0)或最大值(1)；
+    /// instances不为空时，包围盒需要包括所有实例（应用实例的模型变换之后）。
+    fn bounds(&self, _instances: &[Instance]) -> Option<[Vec4; 8]> {
+        None
+    }
+}
+
+/// 光栅化得到的像素
+///
+/// 深度和模板值的写入推迟到片段着色器之后，被片段着色器丢弃（discard）的像素不会修改任何buffer。
+#[derive(Debug, Clone)]
+pub struct Pixel {
+    /// 屏幕坐标
+    pub i: u32,
+    pub j: u32,
+    /// 插值后的varying变量
+    pub v: Varyings,
+    /// 通过模板测试和深度测试的采样点掩码
+    pub mask: u32,
+    /// 在片段内部的采样点掩码
+    pub cover: u32,
+    /// 每个采样点的深度值
+    pub z: [f32; MAX_SAMPLES],
+    /// 每个采样点根据测试结果更新后的模板值
+    pub s: [u8; MAX_SAMPLES],
+    /// 像素被覆盖的比例（抗锯齿线段小于1），片段颜色按该比例与buffer中的颜色插值
+    pub coverage: Tyf,
+}
+
+/// 管线统计（类似`GL_ARB_pipeline_statistics_query`）
+///
+/// 统计调用`Rasterizer::clear_stats`之后各个阶段处理的三角形和片段数量（包括深度预渲染和遮挡查询）；
+/// 片段以像素为单位计数（开启MSAA时不按采样点计数）。
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PipelineStats {
+    /// 提交的三角形
+    pub submitted: usize,
+    /// 被裁剪（部分或全部在视锥体外）的三角形
+    pub clipped: usize,
+    /// 被面剃除的三角形（裁剪后的三角形）
+    pub culled: usize,
+    /// 通过面剃除、进行光栅化的三角形（裁剪后的三角形）
+    pub rasterized: usize,
+    /// 光栅化产生的片段（有采样点被图元覆盖的像素，不包括被层次深度提前剃除的区域）
+    pub fragments: usize,
+    /// 没有采样点通过模板测试和深度测试的片段
+    pub depth_rejected: usize,
+    /// 调用片段着色器的片段（包括被丢弃的片段）
+    pub shaded: usize,
+}
+
+impl AddAssign for PipelineStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.submitted += rhs.submitted;
+        self.clipped += rhs.clipped;
+        self.culled += rhs.culled;
+        self.rasterized += rhs.rasterized;
+        self.fragments += rhs.fragments;
+        self.depth_rejected += rhs.depth_rejected;
+        self.shaded += rhs.shaded;
+    }
+}
+
+impl Display for PipelineStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Triangles submitted: {}", self.submitted)?;
+        writeln!(f, "Triangles clipped: {}", self.clipped)?;
+        writeln!(f, "Triangles culled: {}", self.culled)?;
+        writeln!(f, "Triangles rasterized: {}", self.rasterized)?;
+        writeln!(f, "Fragments generated: {}", self.fragments)?;
+        writeln!(f, "Fragments depth-rejected: {}", self.depth_rejected)?;
+        write!(f, "Fragments shaded: {}", self.shaded)
+    }
 }
 
 /// 图像渲染管线接口
@@ -21,74 +183,243 @@ pub trait IPipeline: IRasterizer + IGlsl {
     // 管线入口，组装图元开始渲染
     //fn input_assembly(&mut self);
 
-    /// 顶点着色器
-    fn vertex(&mut self, primitive: &Box<dyn IPrimitive>, pidx: usize);
+    /// 图元装配和顶点着色器
+    ///
+    /// 按图元拓扑组装第pidx个基本图元，计算其顶点的投影坐标和varying变量。
+    fn vertex(&mut self, primitive: &dyn IPrimitive, pidx: usize);
+
+    /// 裁剪
+    ///
+    /// 在齐次裁剪空间中，将片段裁剪成多边形，再拆分成多个三角形
+    fn clipping(&mut self) -> Vec<ClipTriangle>;
 
     /// 屏幕映射
     ///
-    /// 计算片段三个点顶在屏幕空间中的像素坐标，包括深度信息
-    fn mapping(&mut self);
+    /// 计算裁剪后三角形的三个顶点在屏幕空间中的像素坐标，包括深度信息
+    fn mapping(&mut self, tri: &ClipTriangle);
 
-    /// 背面剃除、裁剪等
+    /// 面剃除
     ///
-    /// 返回true表示当前片段是正面
+    /// 按顶点顺序计算当前片段的朝向（gl_FrontFacing），返回true表示片段没有被剃除
     fn culling(&mut self) -> bool;
 
     /// 对屏幕中的像素进行光栅化
     ///
-    /// 返回在片段内部的屏幕坐标(u32, u32)和重心坐标(Vec3)
-    fn rasterization(&mut self) -> Vec<(u32, u32, Vec3)>;
+    /// 返回有采样点通过模板测试和深度测试、需要着色的像素；
+    /// 没有采样点通过测试的像素不运行片段着色器，直接更新模板buffer。
+    fn rasterization(&mut self) -> Vec<Pixel>;
+
+    /// 对点进行光栅化
+    ///
+    /// 剃除视锥体外的点，返回点覆盖的（以点为中心、边长为点大小的正方形内的）像素。
+    fn rasterize_point(&mut self) -> Vec<Pixel>;
+
+    /// 对线段进行光栅化
+    ///
+    /// 裁剪线段，沿线段的主方向（x或y）在每个像素中心采样，返回线段经过的像素。
+    fn rasterize_line(&mut self) -> Vec<Pixel>;
 
     /// 片段着色器（计算像素的最终颜色）
     ///
-    /// - pixels: 当前正在处理片段的屏幕坐标(u32, u32)和重心坐标(Vec3)
-    fn fragment(&mut self, primitive: &Box<dyn IPrimitive>, pidx: usize, pixels: &Vec<(u32, u32, Vec3)>);
+    /// 片段着色器没有丢弃像素时，才写入颜色、深度和模板buffer。
+    ///
+    /// - pixels: 当前正在处理片段的像素
+    fn fragment(&mut self, primitive: &dyn IPrimitive, pidx: usize, pixels: &[Pixel]);
+
+    /// 只写入深度值的片段阶段（用于深度预渲染）
+    ///
+    /// 不运行片段着色器，不写入颜色和模板buffer。
+    fn fragment_depth(&mut self, pixels: &[Pixel]);
+
+    /// 绘制进行模板测试和深度测试的线段
+    ///
+    /// 按当前的线段光栅化算法和宽度绘制，深度值沿线段线性插值，通过测试的像素写入颜色、深度和模板值。
+    ///
+    /// - points: 2个端点的屏幕坐标（包括深度）
+    /// - color: 线段颜色
+    /// - slope: 线段所在片段的深度斜率，线段按斜率向视点方向偏移，避免被所在的片段遮挡
+    fn line_depth(&mut self, points: &[Vec4; 2], color: Vec4, slope: f32);
+
+    /// 模板测试
+    ///
+    /// 在深度测试之前进行，不修改任何buffer；
+    /// 返回模板测试和深度测试是否都通过，以及根据测试结果更新后的模板值
+    ///
+    /// - i: 屏幕坐标（通过一维数组索引，开启MSAA时为采样点索引）
+    /// - z: 深度值
+    fn test_stencil(&self, i: usize, z: f32) -> (bool, u8);
 
     /// 深度测试
     ///
-    /// - i: 屏幕坐标（通过一维数组索引）
+    /// 按深度状态比较深度值，不修改深度buffer
+    ///
+    /// - i: 屏幕坐标（通过一维数组索引，开启MSAA时为采样点索引）
     /// - z: 深度值
-    fn test_depth(&mut self, i: usize, z: f32) -> bool;
+    fn test_depth(&self, i: usize, z: f32) -> bool;
 
     /// 绘制模型
-    fn draw(&mut self, primitive: &Box<dyn IPrimitive>) {
+    ///
+    /// 点和线段没有网格模式和分块绘制，总是直接绘制。
+    fn draw(&mut self, primitive: &dyn IPrimitive) {
+        draw_topology(self, primitive);
+    }
+
+    /// 实例化绘制模型
+    ///
+    /// 按顺序对每个实例绘制一次图元，实例（index为其在instances中的位置）通过着色器的inst参数传入；
+    /// 绘制完成后恢复为非实例化绘制。
+    fn draw_instanced(&mut self, primitive: &dyn IPrimitive, instances: &[Instance]) {
+        for (id, inst) in instances.iter().enumerate() {
+            *self.instance() = Instance { id, ..*inst };
+            draw_topology(self, primitive);
+        }
+        *self.instance() = Instance::new();
+    }
+
+    /// 绘制点
+    fn draw_points(&mut self, primitive: &dyn IPrimitive) {
+        for pidx in primitive.primitives() {
+            self.vertex(primitive, pidx);
+            let pixels = self.rasterize_point();
+            self.fragment(primitive, pidx, &pixels);
+        }
+    }
+
+    /// 绘制线段
+    fn draw_lines(&mut self, primitive: &dyn IPrimitive) {
+        for pidx in primitive.primitives() {
+            self.vertex(primitive, pidx);
+            let pixels = self.rasterize_line();
+            self.fragment(primitive, pidx, &pixels);
+        }
+    }
+
+    /// 绘制三角形
+    ///
+    /// 开启叠加网格时，先绘制实体模型（使用多边形偏移），再绘制进行深度测试的边。
+    fn draw_triangles(&mut self, primitive: &dyn IPrimitive) {
         if *self.wire_frame() {
             self.draw_wire(primitive);
+            return;
+        }
+        if *self.tiled() {
+            self.draw_tiled(primitive);
         } else {
             self.draw_fill(primitive);
         }
+        if *self.wire_overlay() {
+            self.draw_edges(primitive, false);
+        }
     }
 
+    /// 分块绘制实体模型
+    ///
+    /// 先完成所有片段的几何处理，再按屏幕分块（tile）多线程并行光栅化；
+    /// 结果需要与draw_fill完全一致。
+    fn draw_tiled(&mut self, primitive: &dyn IPrimitive);
+
     /// 绘制实体模型
-    fn draw_fill(&mut self, primitive: &Box<dyn IPrimitive>) {
-        for pidx in primitive.indices() {
+    fn draw_fill(&mut self, primitive: &dyn IPrimitive) {
+        for pidx in primitive.primitives() {
             self.vertex(primitive, pidx);
-            self.mapping();
-            if (!*self.cull_face()) || (*self.cull_face() && self.culling()) {
-                let pixels = self.rasterization();
-                self.fragment(primitive, pidx, &pixels);
+            for tri in self.clipping() {
+                self.mapping(&tri);
+                // 总是计算片段朝向，模板测试和片段着色器需要区分正面和背面
+                if self.culling() {
+                    let pixels = self.rasterization();
+                    self.fragment(primitive, pidx, &pixels);
+                }
             }
         }
     }
 
+    /// 深度预渲染
+    ///
+    /// 只将三角形的深度值写入深度buffer，不写入颜色；点和线段不进行深度预渲染。
+    fn draw_depth(&mut self, primitive: &dyn IPrimitive) {
+        if primitive.topology().vertices() < 3 {
+            return;
+        }
+        for pidx in primitive.primitives() {
+            self.vertex(primitive, pidx);
+            for tri in self.clipping() {
+                self.mapping(&tri);
+                if self.culling() {
+                    let pixels = self.rasterization();
+                    self.fragment_depth(&pixels);
+                }
+            }
+        }
+    }
+
+    /// 实例化深度预渲染
+    ///
+    /// 与`draw_instanced`相同，按顺序对每个实例进行一次深度预渲染，绘制完成后恢复为非实例化绘制。
+    fn draw_depth_instanced(&mut self, primitive: &dyn IPrimitive, instances: &[Instance]) {
+        for (id, inst) in instances.iter().enumerate() {
+            *self.instance() = Instance { id, ..*inst };
+            self.draw_depth(primitive);
+        }
+        *self.instance() = Instance::new();
+    }
+
     /// 绘制网格模型
-    fn draw_wire(&mut self, primitive: &Box<dyn IPrimitive>) {
+    ///
+    /// 先进行深度预渲染，再绘制进行深度测试的边，被遮挡的边不会被绘制。
+    fn draw_wire(&mut self, primitive: &dyn IPrimitive) {
+        self.draw_depth(primitive);
+        self.draw_edges(primitive, true);
+    }
+
+    /// 绘制三角形的边（进行深度测试）
+    ///
+    /// - bias: 边是否按所在片段的深度斜率向视点方向偏移；叠加网格时实体模型已经使用多边形偏移，不需要再偏移
+    fn draw_edges(&mut self, primitive: &dyn IPrimitive, bias: bool) {
         let fg = Vec4::fill(1.0);
         let bg = Vec4::fill(0.6).w(1.0);
-        for pidx in primitive.indices() {
+        for pidx in primitive.primitives() {
             self.vertex(primitive, pidx);
-            self.mapping();
-            let culling = self.culling();
-            let color = if culling { fg } else { bg };
-            if (!*self.cull_face()) || (*self.cull_face() && culling) {
-                let &(a, b, c) = self.frag_coord();
-                let a = a.to_vec2();
-                let b = b.to_vec2();
-                let c = c.to_vec2();
-                self.line(&[a, b], color);
-                self.line(&[a, c], color);
-                self.line(&[b, c], color);
+            for tri in self.clipping() {
+                self.mapping(&tri);
+                let visible = self.culling();
+                let color = if self.front_facing() { fg } else { bg };
+                if visible {
+                    let &(a, b, c) = self.frag_coord();
+                    let abc = [a, b, c];
+                    // 至少偏移1e-6，避免与平行于屏幕的片段深度值相同
+                    let slope = if bias { depth_slope(&a, &b, &c).max(1e-6) } else { 0.0 };
+                    // 只绘制位于原片段边上的线段（两个端点的重心坐标在同一分量上为0），
+                    // 忽略裁剪产生的边和拆分多边形时产生的对角线
+                    for (m, n) in [(0, 1), (0, 2), (1, 2)] {
+                        let (bm, bn) = (tri[m].bc, tri[n].bc);
+                        if (0..3).any(|k| bm[k] == 0.0 && bn[k] == 0.0) {
+                            self.line_depth(&[abc[m], abc[n]], color, slope);
+                        }
+                    }
+                }
             }
         }
     }
 }
+
+/// 片段在屏幕空间中深度值的最大斜率max(|dz/dx|, |dz/dy|)
+///
+/// 片段平面垂直于屏幕时（没有覆盖任何像素）返回0。
+pub(crate) fn depth_slope(a: &Vec4, b: &Vec4, c: &Vec4) -> f32 {
+    let (a, b, c) = (a.to_vec3(), b.to_vec3(), c.to_vec3());
+    let n = (b - a).cross(&(c - a));
+    if n.z == 0.0 {
+        0.0
+    } else {
+        n.x.abs().max(n.y.abs()) / n.z.abs()
+    }
+}
+
+/// 按图元拓扑使用当前的实例绘制一次模型（`draw`和`draw_instanced`共用）
+fn draw_topology<P: IPipeline + ?Sized>(pipeline: &mut P, primitive: &dyn IPrimitive) {
+    match primitive.topology() {
+        ETopology::Points => pipeline.draw_points(primitive),
+        ETopology::Lines | ETopology::LineStrip => pipeline.draw_lines(primitive),
+        _ => pipeline.draw_triangles(primitive),
+    }
+}
@@ -1,8 +1,15 @@
 //! 图形光栅渲染器
 
-use crate::pipeline::{IPipeline, IPrimitive};
-use crate::shader::{GlslVars, IGlsl};
+use crate::clip::{clip_line, clip_point, clip_triangle, ClipTriangle, ClipVertex};
+use crate::framebuffer::Framebuffer;
+use crate::hiz::{HiZ, HIZ_CELL};
+use crate::oit::{EOit, Fragment};
+use crate::pipeline::{depth_slope, ETopology, IPipeline, IPrimitive, PipelineStats, Pixel};
+use crate::shader::{GlslVars, IGlsl, Instance, MAX_OUTPUTS};
+use crate::state::{BlendState, DepthState, ECullMode, EFrontFace, StencilState};
+use crate::varying::Varyings;
 use magx::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// 光栅渲染器接口
 pub trait IRasterizer {
@@ -31,6 +38,29 @@ pub trait IRasterizer {
         }
     }
 
+    /// 按覆盖比例输出像素颜色（用于抗锯齿）
+    ///
+    /// 默认将覆盖比例乘到颜色的alpha上直接输出，由set_color的实现进行混合。
+    ///
+    /// - coverage: 像素被覆盖的比例[0, 1]
+    fn plot(&mut self, i: u32, j: u32, color: &Vec4, coverage: Tyf) {
+        self.set_color(i, j, &color.w(color.w * coverage));
+    }
+
+    /// 使用指定的光栅化算法和宽度绘制直线
+    ///
+    /// - points: 2个端点
+    /// - color: 线段颜色
+    /// - mode: 线段光栅化算法
+    /// - width: 线段宽度（像素）
+    fn line_with(&mut self, points: &[Vec2; 2], color: Vec4, mode: ELineMode, width: Tyf) {
+        for p in line_pixels(&points[0], &points[1], mode, width) {
+            if p.i >= 0 && p.j >= 0 {
+                self.plot(p.i as u32, p.j as u32, &color, p.coverage);
+            }
+        }
+    }
+
     /// 绘制三角形
     ///
     /// - points: 3个顶点
@@ -58,6 +88,285 @@ pub trait IRasterizer {
     }
 }
 
+/// 未开启MSAA时的采样点（即像素坐标）
+const MSAA_X1: [Vec2; 1] = [Vec2::from(0.0, 0.0)];
+/// 2x MSAA采样点（D3D标准采样位置，相对于像素坐标）
+const MSAA_X2: [Vec2; 2] = [Vec2::from(0.25, 0.25), Vec2::from(-0.25, -0.25)];
+/// 4x MSAA采样点
+const MSAA_X4: [Vec2; 4] = [
+    Vec2::from(-0.125, -0.375),
+    Vec2::from(0.375, -0.125),
+    Vec2::from(-0.375, 0.125),
+    Vec2::from(0.125, 0.375),
+];
+/// 8x MSAA采样点
+const MSAA_X8: [Vec2; 8] = [
+    Vec2::from(0.0625, -0.1875),
+    Vec2::from(-0.0625, 0.1875),
+    Vec2::from(0.3125, 0.0625),
+    Vec2::from(-0.1875, -0.3125),
+    Vec2::from(-0.3125, 0.3125),
+    Vec2::from(-0.4375, -0.0625),
+    Vec2::from(0.1875, 0.4375),
+    Vec2::from(0.4375, -0.4375),
+];
+
+/// 每个像素最多的采样点数量
+pub const MAX_SAMPLES: usize = 8;
+
+/// 多重采样抗锯齿（MSAA）模式
+///
+/// 每个像素有多个采样点，每个采样点单独计算覆盖和深度，
+/// 但每个像素只运行一次片段着色器，最后将所有采样点的颜色平均（resolve）得到像素颜色。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EMsaa {
+    /// 不开启MSAA（每个像素1个采样点）
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl EMsaa {
+    /// 采样点相对于像素坐标的偏移
+    pub fn offsets(&self) -> &'static [Vec2] {
+        match self {
+            EMsaa::Off => &MSAA_X1,
+            EMsaa::X2 => &MSAA_X2,
+            EMsaa::X4 => &MSAA_X4,
+            EMsaa::X8 => &MSAA_X8,
+        }
+    }
+
+    /// 每个像素的采样点数量
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.offsets().len()
+    }
+}
+
+/// 色调映射（tone mapping）算子
+///
+/// 将HDR颜色（可能大于1.0）映射到显示范围[0, 1]。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ETonemap {
+    /// 直接截断到[0, 1]
+    Clamp,
+    /// Reinhard：c / (1 + c)
+    Reinhard,
+    /// ACES filmic曲线（Krzysztof Narkowicz的拟合）
+    Aces,
+}
+
+impl ETonemap {
+    /// 映射一个颜色分量
+    pub fn map(&self, c: f32) -> f32 {
+        let c = c.max(0.0);
+        match self {
+            ETonemap::Clamp => c.min(1.0),
+            ETonemap::Reinhard => c / (1.0 + c),
+            // - https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+            ETonemap::Aces => ((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14)).clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// 线段光栅化算法
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ELineMode {
+    /// 数字微分分析（DDA）：沿主方向在每个像素中心采样，使用浮点计算
+    Dda,
+    /// Bresenham：端点取整后，只使用整数加减计算
+    Bresenham,
+    /// Xiaolin Wu：抗锯齿，按次方向上与线段重叠的长度计算像素的覆盖比例
+    Wu,
+}
+
+/// 线段覆盖的像素
+#[derive(Debug, Copy, Clone)]
+struct LinePixel {
+    i: i64,
+    j: i64,
+    /// 像素在线段上的位置（0为起点，1为终点）
+    t: Tyf,
+    /// 像素被覆盖的比例
+    coverage: Tyf,
+}
+
+/// 计算线段覆盖的像素
+///
+/// 沿主方向（x或y）逐个像素步进，每一步在次方向上覆盖以线段为中心、宽度为width的范围；
+/// 主方向的采样范围为半开区间，相连的线段不会重复绘制端点。
+fn line_pixels(a: &Vec2, b: &Vec2, mode: ELineMode, width: Tyf) -> Vec<LinePixel> {
+    let mut pixels = Vec::new();
+    // 主方向上第m个像素，次方向上线段中心位于c
+    let mut spread = |x_major: bool, m: i64, c: Tyf, t: Tyf| {
+        let (lo, hi) = (c - width / 2.0, c + width / 2.0);
+        let mut push = |n: i64, coverage: Tyf| {
+            let (i, j) = if x_major { (m, n) } else { (n, m) };
+            pixels.push(LinePixel { i, j, t, coverage });
+        };
+        if mode == ELineMode::Wu {
+            for n in lo.floor() as i64..hi.ceil() as i64 {
+                let coverage = hi.min((n + 1) as Tyf) - lo.max(n as Tyf);
+                if coverage > 0.0 {
+                    push(n, coverage.min(1.0));
+                }
+            }
+        } else {
+            // 像素中心在(lo, hi]内的像素
+            for n in (lo + 0.5).floor() as i64..(hi + 0.5).floor() as i64 {
+                push(n, 1.0);
+            }
+        }
+    };
+
+    if mode == ELineMode::Bresenham {
+        let (mut x, mut y) = (a.x.floor() as i64, a.y.floor() as i64);
+        let (x1, y1) = (b.x.floor() as i64, b.y.floor() as i64);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (sx, sy) = ((x1 - x).signum(), (y1 - y).signum());
+        let x_major = dx >= -dy;
+        let num = dx.max(-dy);
+        // err为误差项：下一个像素在理想直线哪一侧的判断值，只需要整数加减更新
+        let mut err = dx + dy;
+        for k in 0..num {
+            let t = k as Tyf / num as Tyf;
+            if x_major {
+                spread(true, x, y as Tyf + 0.5, t);
+            } else {
+                spread(false, y, x as Tyf + 0.5, t);
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    } else {
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let x_major = dx.abs() >= dy.abs();
+        let (a0, c0, d, dc) = if x_major { (a.x, a.y, dx, dy) } else { (a.y, a.x, dy, dx) };
+        if d == 0.0 {
+            return pixels;
+        }
+        let (lo, hi) = (a0.min(a0 + d), a0.max(a0 + d));
+        for m in (lo - 0.5).ceil() as i64..(hi - 0.5).ceil() as i64 {
+            let t = (m as Tyf + 0.5 - a0) / d;
+            spread(x_major, m, c0 + t * dc, t);
+        }
+    }
+    pixels
+}
+
+/// 线性颜色分量编码为sRGB（gamma校正）
+#[inline]
+fn srgb_encode(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// [0, 1]范围的颜色转成8位颜色
+#[inline]
+fn to_rgba8(c: &[f32; 4]) -> [u8; 4] {
+    c.map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// 8位颜色转成[0, 1]范围的颜色
+#[inline]
+fn from_rgba8(c: &[u8; 4]) -> [f32; 4] {
+    c.map(|v| v as f32 / 255.0)
+}
+
+/// 片段颜色与颜色buffer中的颜色混合后，再按覆盖比例与buffer中的颜色插值
+///
+/// - hdr: 是否保留原始值（否则截断到[0, 1]）
+#[inline]
+fn blend_color(blend: &BlendState, src: &[f32; 4], dst: &[f32; 4], coverage: Tyf, hdr: bool) -> [f32; 4] {
+    let mut c = blend.blend(src, dst);
+    if coverage < 1.0 {
+        for (v, d) in c.iter_mut().zip(dst) {
+            *v = d + (*v - d) * coverage;
+        }
+    }
+    if hdr {
+        c
+    } else {
+        c.map(|v| v.clamp(0.0, 1.0))
+    }
+}
+
+/// 分块渲染的tile大小
+const TILE_SIZE: u32 = 64;
+
+/// 多边形偏移中units的单位：最小可分辨的深度差（按24位深度buffer计算）
+const DEPTH_UNIT: f32 = 1.0 / (1 << 24) as f32;
+
+/// 光栅化时屏幕坐标的定点精度（1/256像素）
+const SUBPIXEL: i64 = 256;
+
+/// 屏幕坐标转成定点坐标
+#[inline]
+fn to_fixed(p: &Vec4) -> (i64, i64) {
+    ((p.x * SUBPIXEL as Tyf).round() as i64, (p.y * SUBPIXEL as Tyf).round() as i64)
+}
+
+/// 有向边p->q的边函数（edge function）
+///
+/// ```text
+/// E(x, y) = (q.x - p.x) * (y - p.y) - (q.y - p.y) * (x - p.x)
+///         = a * x + b * y + c
+/// ```
+///
+/// 点在边的左侧时E > 0，且E的值等于点与p、q组成的三角形面积的2倍；
+/// 沿x方向移动1个单位时，E增加a，所以可以增量计算。
+/// 这里使用定点整数计算，共边的两个三角形，在同一点的边函数值恰好互为相反数。
+struct EdgeFn {
+    a: i64,
+    b: i64,
+    c: i64,
+    /// top-left填充规则的偏置
+    bias: i64,
+}
+
+impl EdgeFn {
+    fn new(p: (i64, i64), q: (i64, i64)) -> Self {
+        let a = p.1 - q.1;
+        let b = q.0 - p.0;
+        // top-left规则（y轴向上，三角形为逆时针方向）：
+        // 落在左边（向下的边）或上边（向左的水平边）上的采样点属于三角形，其它边上的采样点不属于三角形，
+        // 保证共边三角形的边上的采样点只被绘制一次
+        let top_left = a > 0 || (a == 0 && b < 0);
+        Self {
+            a,
+            b,
+            c: -(a * p.0 + b * p.1),
+            bias: if top_left { 0 } else { -1 },
+        }
+    }
+
+    #[inline]
+    fn eval(&self, p: (i64, i64)) -> i64 {
+        self.a * p.0 + self.b * p.1 + self.c
+    }
+}
+
+/// 分块渲染时，已完成几何处理的三角形
+struct TileTriangle {
+    pidx: usize,
+    frag_coord: (Vec4, Vec4, Vec4),
+    clip_bc: (Vec3, Vec3, Vec3),
+    varyings: (Varyings, Varyings, Varyings),
+    front_facing: bool,
+}
+
 /// 光栅渲染器
 #[allow(dead_code)]
 pub struct Rasterizer {
@@ -65,6 +374,10 @@ pub struct Rasterizer {
     pub sz: (u32, u32),
     /// 视口变换矩阵
     mat_viewport: Mat4,
+    /// buffer对应的屏幕区域(x, y, w, h)，分块渲染时为tile的区域
+    rect: (u32, u32, u32, u32),
+    /// 分块渲染的线程数量
+    threads: usize,
     gv: GlslVars,
 }
 
@@ -74,23 +387,115 @@ impl Rasterizer {
         Self {
             mat_viewport: viewport(0.0, 0.0, sz.0 as Tyf, sz.1 as Tyf),
             sz,
+            rect: (0, 0, sz.0, sz.1),
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
             gv: GlslVars::new(sz),
         }
     }
 
+    /// 绑定帧缓冲对象，之后的绘制、清除和读取都作用于fbo的buffer
+    ///
+    /// 与当前的buffer交换，返回之前绑定的帧缓冲（初始为Rasterizer创建时的默认帧缓冲），
+    /// 再次绑定返回的帧缓冲即可恢复。视口随帧缓冲的大小改变。
+    pub fn bind_framebuffer(&mut self, mut fbo: Framebuffer) -> Framebuffer {
+        fbo.swap(&mut self.gv);
+        std::mem::swap(&mut self.sz, &mut fbo.sz);
+        self.rect = (0, 0, self.sz.0, self.sz.1);
+        self.update_viewport();
+        fbo
+    }
+
+    /// 改变当前帧缓冲的大小
+    ///
+    /// 按新的大小重新分配所有buffer（保持MSAA、HDR和附加渲染目标的设置），并更新视口；
+    /// buffer的内容不保留，需要重新清除和绘制。
+    pub fn resize(&mut self, sz: (u32, u32)) {
+        if sz == self.sz {
+            return;
+        }
+        let max = (sz.0 * sz.1) as usize;
+        self.sz = sz;
+        self.rect = (0, 0, sz.0, sz.1);
+        self.gv.cbuf = vec![[0; 4]; max];
+        self.gv.zbuf = vec![self.depth_far(); max];
+        self.gv.sbuf = vec![0; max];
+        self.gv.overdraw = vec![0; max];
+        self.gv.frags.clear();
+        self.set_msaa(self.gv.msaa);
+        self.set_hdr(self.get_hdr());
+        self.set_targets(self.get_targets());
+        self.update_viewport();
+    }
+
+    /// 按屏幕大小和深度范围更新视口变换矩阵
+    fn update_viewport(&mut self) {
+        let (w, h) = (self.sz.0 as Tyf, self.sz.1 as Tyf);
+        self.mat_viewport = if self.gv.en_reversed_z {
+            viewport_reversed(0.0, 0.0, w, h)
+        } else {
+            viewport(0.0, 0.0, w, h)
+        };
+    }
+
+    /// 设置分块渲染的线程数量
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// 创建渲染rect区域的Rasterizer
+    fn tile(&self, rect: (u32, u32, u32, u32)) -> Self {
+        let local = (rect.0 - self.rect.0, rect.1 - self.rect.1, rect.2, rect.3);
+        Self {
+            sz: self.sz,
+            mat_viewport: self.mat_viewport,
+            rect,
+            threads: 1,
+            gv: self.gv.tile(self.rect.2, local),
+        }
+    }
+
+    /// 将tile的渲染结果写回buffer
+    fn blit(&mut self, tile: &Self) {
+        let local = (tile.rect.0 - self.rect.0, tile.rect.1 - self.rect.1, tile.rect.2, tile.rect.3);
+        self.gv.blit(self.rect.2, local, &tile.gv);
+    }
+
+    /// 像素坐标在buffer中的索引
+    #[inline]
+    fn index(&self, i: u32, j: u32) -> Option<usize> {
+        let (x, y, w, h) = self.rect;
+        if x <= i && i < x + w && y <= j && j < y + h {
+            Some(((i - x) + (j - y) * w) as usize)
+        } else {
+            None
+        }
+    }
+
     /// 返回颜色buffer
     #[inline]
     pub fn get_color(&self) -> &Vec<[u8; 4]> {
         &self.gv.cbuf
     }
 
-    /// 清除颜色buffer
+    /// 返回HDR颜色buffer（未开启HDR时为空）
+    #[inline]
+    pub fn get_hdr_color(&self) -> &Vec<[f32; 4]> {
+        &self.gv.hdr_cbuf
+    }
+
+    /// 清除颜色buffer，同时将附加渲染目标清除为0，并丢弃开启OIT时保存的片段
     ///
     /// - color: 用于填充color buffer的颜色
     #[inline]
     pub fn clear_color(&mut self, color: &Vec4) {
-        let c = (*color) * 255.0;
-        self.gv.cbuf.fill([c.x as u8, c.y as u8, c.z as u8, c.w as u8]);
+        let c = self.color_value(color);
+        self.gv.cbuf.fill(to_rgba8(&c));
+        self.gv.hdr_cbuf.fill(c);
+        self.gv.ms_cbuf.fill(c);
+        for t in &mut self.gv.targets {
+            t.fill([0.0; 4]);
+        }
+        self.gv.frags.clear();
     }
 
     #[inline]
@@ -98,199 +503,2280 @@ impl Rasterizer {
         &self.gv.zbuf
     }
 
+    /// 清除深度buffer（反向Z时清除为0，否则清除为1）
     #[inline]
     pub fn clear_depth(&mut self) {
-        self.gv.zbuf.fill(1.0);
+        let z = self.depth_far();
+        self.gv.zbuf.fill(z);
+        self.gv.ms_zbuf.fill(z);
+        self.gv.hiz.invalidate();
     }
-}
 
-impl IRasterizer for Rasterizer {
     #[inline]
-    fn set_color(&mut self, i: u32, j: u32, color: &Vec4) {
-        if i < self.sz.0 && j < self.sz.1 {
-            let c = (*color) * 255.0;
-            self.gv.cbuf[(i + j * self.sz.0) as usize] = [c.x as u8, c.y as u8, c.z as u8, c.w as u8];
-        }
+    pub fn get_stencil(&self) -> &Vec<u8> {
+        &self.gv.sbuf
     }
-}
 
-impl IGlsl for Rasterizer {
+    /// 清除模板buffer（清除为0）
     #[inline]
-    fn frag_coord(&self) -> &(Vec4, Vec4, Vec4) {
-        &self.gv.gl_FragCoord
+    pub fn clear_stencil(&mut self) {
+        self.gv.sbuf.fill(0);
+        self.gv.ms_sbuf.fill(0);
     }
 
     #[inline]
-    fn wire_frame(&mut self) -> &mut bool {
-        &mut self.gv.en_wire_frame
+    pub fn get_stencil_state(&self) -> StencilState {
+        self.gv.stencil
     }
 
+    /// 设置模板状态，作用于之后绘制的片段
     #[inline]
-    fn cull_face(&mut self) -> &mut bool {
-        &mut self.gv.en_cull_back_face
+    pub fn set_stencil_state(&mut self, stencil: StencilState) {
+        self.gv.stencil = stencil;
     }
-}
 
-impl IPipeline for Rasterizer {
     #[inline]
-    fn vertex(&mut self, primitive: &Box<dyn IPrimitive>, pidx: usize) {
-        self.gv.gl_Postion = primitive.vertex(pidx);
+    pub fn get_front_face(&self) -> EFrontFace {
+        self.gv.front_face
     }
 
-    fn mapping(&mut self) {
-        let (mut a, mut b, mut c) = self.gv.gl_Postion;
-        let aw = a.w;
-        let bw = b.w;
-        let cw = c.w;
+    /// 设置正面的顶点顺序，作用于之后绘制的三角形
+    #[inline]
+    pub fn set_front_face(&mut self, front_face: EFrontFace) {
+        self.gv.front_face = front_face;
+    }
 
-        // 对于视口边界的片段，应该进行裁剪，
-        // 这里不便修改Model中数据，放到rasterization中自动实现
+    #[inline]
+    pub fn get_cull_mode(&self) -> ECullMode {
+        self.gv.cull_mode
+    }
 
-        // 透视除法
-        a = a / a.w;
-        b = b / b.w;
-        c = c / c.w;
+    /// 设置剃除的面，开启面剃除（`cull_face`）时作用于之后绘制的三角形
+    #[inline]
+    pub fn set_cull_mode(&mut self, cull_mode: ECullMode) {
+        self.gv.cull_mode = cull_mode;
+    }
 
-        // 视口变换，得到屏幕坐标
-        a = self.mat_viewport.mul_vec(&a);
-        b = self.mat_viewport.mul_vec(&b);
-        c = self.mat_viewport.mul_vec(&c);
+    /// 设置附加渲染目标的数量
+    ///
+    /// 附加渲染目标依次保存片段着色器location为1..=n的输出，不进行混合和多重采样，
+    /// 每个像素只保存最后一个通过测试的片段的输出。
+    pub fn set_targets(&mut self, n: usize) {
+        assert!(n < MAX_OUTPUTS, "Too many render targets");
+        let max = (self.rect.2 * self.rect.3) as usize;
+        self.gv.targets = vec![vec![[0.0; 4]; max]; n];
+    }
 
-        self.gv.gl_FragCoord = (a, b, c);
-        // 保存值1/w到gl_FragCoord，用于透视逆运算（重心坐标校正）
-        self.gv.gl_FragCoord.0.w = aw;
-        self.gv.gl_FragCoord.1.w = bw;
-        self.gv.gl_FragCoord.2.w = cw;
+    /// 附加渲染目标的数量
+    #[inline]
+    pub fn get_targets(&self) -> usize {
+        self.gv.targets.len()
     }
 
-    fn culling(&mut self) -> bool {
-        let (a, b, c) = self.gv.gl_FragCoord;
-        let a = a.to_vec2().to_vec3(0.0);
-        let b = b.to_vec2().to_vec3(0.0);
-        let c = c.to_vec2().to_vec3(0.0);
+    /// 返回location处的附加渲染目标（location从1开始）
+    #[inline]
+    pub fn get_target(&self, location: usize) -> &Vec<[f32; 4]> {
+        &self.gv.targets[location - 1]
+    }
 
-        // back-face culling（通过三角形顶点顺序剃除背面的片段）
-        // 在屏幕坐标空间中，摄像头看向的方向即是-z方向，
-        // 所以三角形的法向量只需要z方向比较即可
-        let normal: Vec3 = (b - a).cross(&(c - a)).normalize();
-        self.gv.gl_FrontFacing = normal.dot(&Vec3::from(0.0, 0.0, 1.0)) > 0.0;
+    #[inline]
+    pub fn get_stats(&self) -> PipelineStats {
+        self.gv.stats
+    }
 
-        return self.gv.gl_FrontFacing;
+    /// 返回overdraw buffer（每个像素调用片段着色器的次数）
+    #[inline]
+    pub fn get_overdraw(&self) -> &Vec<u32> {
+        &self.gv.overdraw
     }
 
-    fn rasterization(&mut self) -> Vec<(u32, u32, Vec3)> {
-        let (a, b, c) = self.gv.gl_FragCoord;
-        let abc = [a.to_vec2(), b.to_vec2(), c.to_vec2()];
-
-        // 自动丢弃视口外的像素点，实现裁剪
-        let (lb, rt) = bound_box(&abc);
-        let xlo = lb.x.min(0.0) as u32;
-        let ylo = lb.y.min(0.0) as u32;
-        let xhi = rt.x.min(self.sz.0 as Tyf) as u32;
-        let yhi = rt.y.min(self.sz.1 as Tyf) as u32;
-        // 光栅化，用pixels保存一个片段中所有需要着色的像素点
-        let mut pixels = Vec::new();
+    /// 清除管线统计和overdraw buffer，一般在每帧开始时调用
+    #[inline]
+    pub fn clear_stats(&mut self) {
+        self.gv.stats = PipelineStats::default();
+        self.gv.overdraw.fill(0);
+    }
 
-        for i in xlo..xhi {
-            // 小于0的坐标，为自动转成0
-            for j in ylo..yhi {
-                if let Some(bc) = barycentric(&abc, Vec2::from(i as Tyf, j as Tyf)) {
-                    // 重心坐标校正
-                    // - https://www.comp.nus.edu.sg/~lowkl/publications/lowk_persp_interp_techrep.pdf
-                    // - https://zhuanlan.zhihu.com/p/144331875
-                    // 这里除以w和z是等效的，因为三维空间中，w和z成线性关系（因为透视的原理即z起大，片段越小）
-                    let bcc = Vec3::from(bc.x / a.w, bc.y / b.w, bc.z / c.w);
-                    let bc = bcc / (bcc.x + bcc.y + bcc.z);
-
-                    // Test: 取三个点的均值，渲染出三角面模型效果
-                    //let bc = Vec3::fill(1.0) / 3.0;
-
-                    // 深度测试
-                    let z = interpolate(&bc, &a.z, &b.z, &c.z) as f32;
-                    if self.test_depth((i + j * self.sz.0) as usize, z) {
-                        pixels.push((i, j, bc));
+    /// 遮挡查询
+    ///
+    /// 用图元的包围盒代替图元进行深度测试（不修改任何buffer和管线统计），返回是否有采样点可以通过深度测试；
+    /// 返回false时，图元被完全遮挡，不需要绘制。
+    /// 图元没有包围盒、未开启深度测试或包围盒跨过近平面时，保守地返回true。
+    ///
+    /// - instances: 实例化绘制的实例（非实例化绘制时为空），查询所有实例的包围盒
+    pub fn query_occlusion(&mut self, primitive: &dyn IPrimitive, instances: &[Instance]) -> bool {
+        let Some(corners) = primitive.bounds(instances) else {
+            return true;
+        };
+        let reversed = self.gv.en_reversed_z;
+        let near = |p: &Vec4| if reversed { p.w - p.z } else { p.w + p.z };
+        if !self.gv.depth.test || corners.iter().any(|p| p.w <= 0.0 || near(p) < 0.0) {
+            return true;
+        }
+
+        // 包围盒6个面的顶点索引（顶点索引的第0, 1, 2位分别表示x, y, z方向）
+        const FACES: [[usize; 4]; 6] = [
+            [0, 2, 6, 4],
+            [1, 3, 7, 5],
+            [0, 1, 5, 4],
+            [2, 3, 7, 6],
+            [0, 1, 3, 2],
+            [4, 5, 7, 6],
+        ];
+        // 只进行深度测试：模板测试可能修改模板buffer，查询时关闭；
+        // 查询不计入管线统计，也不修改正在进行的绘制的图元状态
+        let stencil = self.gv.stencil;
+        let stats = self.gv.stats;
+        let state = (
+            self.gv.gl_Postion,
+            self.gv.varyings,
+            self.gv.gl_FrontFacing,
+            self.gv.gl_FragCoord,
+            self.gv.clip_bc,
+        );
+        self.gv.stencil.test = false;
+        let mut visible = false;
+        'query: for [p0, p1, p2, p3] in FACES {
+            for tri in [(p0, p1, p2), (p0, p2, p3)] {
+                self.gv.gl_Postion = (corners[tri.0], corners[tri.1], corners[tri.2]);
+                self.gv.varyings = (Varyings::new(), Varyings::new(), Varyings::new());
+                for tri in self.clipping() {
+                    self.mapping(&tri);
+                    self.culling();
+                    if !self.rasterization().is_empty() {
+                        visible = true;
+                        break 'query;
                     }
                 }
             }
         }
-        pixels
+        self.gv.stencil = stencil;
+        self.gv.stats = stats;
+        (
+            self.gv.gl_Postion,
+            self.gv.varyings,
+            self.gv.gl_FrontFacing,
+            self.gv.gl_FragCoord,
+            self.gv.clip_bc,
+        ) = state;
+        visible
     }
 
+    /// 当前使用的模板buffer（开启MSAA时为多重采样模板buffer）
     #[inline]
-    fn fragment(&mut self, primitive: &Box<dyn IPrimitive>, pidx: usize, pixels: &Vec<(u32, u32, Vec3)>) {
-        for &(i, j, bc) in pixels {
-            self.set_color(i, j, &primitive.fragment(pidx, &bc));
+    fn stencil_buf(&mut self) -> &mut Vec<u8> {
+        if self.gv.msaa == EMsaa::Off {
+            &mut self.gv.sbuf
+        } else {
+            &mut self.gv.ms_sbuf
         }
     }
 
-    #[inline]
-    fn test_depth(&mut self, i: usize, z: f32) -> bool {
-        // 通过zbuffer剃除被遮挡的片段，z值通过重心坐标插值计算；
-        // 丢弃 深度值>=当前深度缓冲值 的片段（丢离视点更远的片段）；
-        // viewport()计算的z范围为[-1.0, 1.0]。
-        if -1.0 <= z && z <= 1.0 && self.gv.zbuf[i] > z {
-            self.gv.zbuf[i] = z;
-            return true;
+    /// 写入像素中采样点的模板值
+    ///
+    /// - k: 像素索引
+    /// - mask: 需要写入的采样点掩码
+    fn write_stencil(&mut self, k: usize, mask: u32, s: &[u8; MAX_SAMPLES]) {
+        if !self.gv.stencil.test {
+            return;
+        }
+        let n = self.gv.msaa.count();
+        let sbuf = self.stencil_buf();
+        for (t, v) in s.iter().enumerate().take(n) {
+            if mask & (1 << t) != 0 {
+                sbuf[k * n + t] = *v;
+            }
         }
-        false
     }
-}
 
-#[test]
-fn rasterizer_test() {
-    use magx::*;
+    /// 写入像素中采样点的深度值（开启深度测试和深度写入时）
+    ///
+    /// - k: 像素索引
+    /// - mask: 需要写入的采样点掩码
+    fn write_depth(&mut self, k: usize, mask: u32, z: &[f32; MAX_SAMPLES]) {
+        let depth = self.gv.depth;
+        if !depth.test || !depth.write {
+            return;
+        }
+        let n = self.gv.msaa.count();
+        let zbuf = if self.gv.msaa == EMsaa::Off {
+            &mut self.gv.zbuf
+        } else {
+            &mut self.gv.ms_zbuf
+        };
+        for (t, v) in z.iter().enumerate().take(n) {
+            if mask & (1 << t) != 0 {
+                zbuf[k * n + t] = *v;
+            }
+        }
+        self.gv.hiz.mark(k);
+    }
 
-    struct R {
-        sz: (u32, u32),
-        cbuf: Vec<[u8; 4]>,
+    /// 是否可以使用深度金字塔提前剃除，可以时先更新深度金字塔
+    ///
+    /// 开启模板测试时，未通过深度测试的片段也可能修改模板buffer，不能提前剃除。
+    fn early_z(&mut self) -> bool {
+        let gv = &mut self.gv;
+        if !gv.en_hiz || !gv.depth.test || gv.stencil.test {
+            return false;
+        }
+        let (w, h) = (self.rect.2, self.rect.3);
+        let zbuf = if gv.msaa == EMsaa::Off { &gv.zbuf } else { &gv.ms_zbuf };
+        gv.hiz.sync(zbuf, w, h, gv.msaa.count());
+        true
     }
 
-    impl R {
-        pub fn new(sz: (u32, u32)) -> Self {
-            let max = (sz.0 * sz.1) as usize;
-            Self {
-                sz,
-                cbuf: vec![[0; 4]; max],
+    /// 对点和线段覆盖的像素进行模板测试和深度测试
+    ///
+    /// 像素中所有的采样点都被覆盖，使用相同的深度值；没有采样点通过测试时返回None。
+    fn test_pixel(&mut self, i: u32, j: u32, z: f32, v: &Varyings) -> Option<Pixel> {
+        let k = self.index(i, j)?;
+        let n = self.gv.msaa.count();
+        let cover = (1 << n) - 1;
+        self.gv.stats.fragments += 1;
+        let mut mask = 0;
+        let mut ss = [0; MAX_SAMPLES];
+        for (s, sv) in ss.iter_mut().enumerate().take(n) {
+            let (pass, value) = self.test_stencil(k * n + s, z);
+            *sv = value;
+            if pass {
+                mask |= 1 << s;
             }
         }
+        if mask == 0 {
+            self.write_stencil(k, cover, &ss);
+            self.gv.stats.depth_rejected += 1;
+            return None;
+        }
+        Some(Pixel {
+            i,
+            j,
+            v: *v,
+            mask,
+            cover,
+            z: [z; MAX_SAMPLES],
+            s: ss,
+            coverage: 1.0,
+        })
+    }
 
-        pub fn get_color(&self) -> &Vec<[u8; 4]> {
-            &self.cbuf
+    #[inline]
+    pub fn get_point_size(&self) -> f32 {
+        self.gv.point_size
+    }
+
+    /// 设置点的大小（像素）
+    #[inline]
+    pub fn set_point_size(&mut self, size: f32) {
+        self.gv.point_size = size.max(1.0);
+    }
+
+    #[inline]
+    pub fn get_line_mode(&self) -> ELineMode {
+        self.gv.line_mode
+    }
+
+    /// 设置线段光栅化算法，作用于线段图元和网格模式的边
+    #[inline]
+    pub fn set_line_mode(&mut self, mode: ELineMode) {
+        self.gv.line_mode = mode;
+    }
+
+    #[inline]
+    pub fn get_line_width(&self) -> f32 {
+        self.gv.line_width
+    }
+
+    /// 设置线段的宽度（像素）
+    #[inline]
+    pub fn set_line_width(&mut self, width: f32) {
+        self.gv.line_width = width.max(1.0);
+    }
+
+    #[inline]
+    pub fn get_polygon_offset(&self) -> (f32, f32) {
+        self.gv.polygon_offset
+    }
+
+    /// 设置多边形偏移
+    ///
+    /// 叠加网格时，三角形的深度值加上`factor * 最大深度斜率 + units * 最小可分辨深度差`，
+    /// 使实体模型向远离视点的方向移动，避免与网格的边深度冲突（z-fighting）。
+    #[inline]
+    pub fn set_polygon_offset(&mut self, factor: f32, units: f32) {
+        self.gv.polygon_offset = (factor, units);
+    }
+
+    /// 三角形片段的多边形偏移（只在叠加网格时有效，反向Z时为负值）
+    fn depth_offset(&self, a: &Vec4, b: &Vec4, c: &Vec4) -> f32 {
+        if !self.gv.en_wire_overlay {
+            return 0.0;
+        }
+        let (factor, units) = self.gv.polygon_offset;
+        let offset = factor * depth_slope(a, b, c) + units * DEPTH_UNIT;
+        if self.gv.en_reversed_z {
+            -offset
+        } else {
+            offset
         }
     }
 
-    impl IRasterizer for R {
-        #[inline]
-        fn set_color(&mut self, i: u32, j: u32, color: &Vec4) {
-            if i < self.sz.0 && j < self.sz.1 {
-                let c = (*color) * 255.0;
-                self.cbuf[(i + j * self.sz.0) as usize] = [c.x as u8, c.y as u8, c.z as u8, c.w as u8];
-            }
+    /// 远平面的深度值
+    #[inline]
+    fn depth_far(&self) -> f32 {
+        if self.gv.en_reversed_z {
+            0.0
+        } else {
+            1.0
         }
     }
 
-    let wid = 640;
-    let hei = 480;
-    let mut r = R::new((wid, hei));
-    let a = Vec2::from(10.0, 10.0);
-    let b = Vec2::from(50.0, 460.0);
-    let c = Vec2::from(630.0, 430.0);
-    let d = Vec2::from(600.0, 100.0);
-    let colors = [
-        Vec4::from(1.0, 0.0, 0.0, 1.0),
-        Vec4::from(0.0, 1.0, 0.0, 1.0),
-        Vec4::from(0.0, 0.0, 1.0, 1.0),
-    ];
-    r.line(&[a, b], colors[0]);
-    r.line(&[b, c], colors[1]);
-    r.triangle(&[a, c, d], &colors);
+    #[inline]
+    pub fn get_depth_state(&self) -> DepthState {
+        self.gv.depth
+    }
+
+    /// 设置深度状态，作用于之后绘制的片段
+    #[inline]
+    pub fn set_depth_state(&mut self, depth: DepthState) {
+        self.gv.depth = depth;
+    }
+
+    #[inline]
+    pub fn get_reversed_z(&self) -> bool {
+        self.gv.en_reversed_z
+    }
 
-    let buf = r.get_color();
-    let mut img = image::ImageBuffer::from_pixel(wid, hei, image::Rgba([0, 0, 0, 0]));
-    for x in 0..wid {
-        for y in 0..hei {
-            img.put_pixel(x, hei - (y + 1), image::Rgba(buf[(x + y * wid) as usize]));
+    /// 开启或关闭反向Z
+    ///
+    /// 反向Z需要配合`magx::persp_reversed`投影矩阵使用：
+    /// 裁剪和视口变换使用[0, 1]的深度范围，深度buffer清除为0，深度比较函数反转（如Less变成Greater）。
+    pub fn set_reversed_z(&mut self, reversed: bool) {
+        if reversed == self.gv.en_reversed_z {
+            return;
+        }
+        self.gv.en_reversed_z = reversed;
+        self.update_viewport();
+        self.gv.depth.func = self.gv.depth.func.reversed();
+        self.clear_depth();
+    }
+
+    #[inline]
+    pub fn get_msaa(&self) -> EMsaa {
+        self.gv.msaa
+    }
+
+    /// 设置MSAA模式
+    ///
+    /// 开启MSAA时，重新分配每个采样点的颜色、深度和模板buffer。
+    pub fn set_msaa(&mut self, msaa: EMsaa) {
+        let max = if msaa == EMsaa::Off {
+            0
+        } else {
+            (self.rect.2 * self.rect.3) as usize * msaa.count()
+        };
+        self.gv.msaa = msaa;
+        self.gv.ms_cbuf = vec![[0.0; 4]; max];
+        self.gv.ms_zbuf = vec![self.depth_far(); max];
+        self.gv.ms_sbuf = vec![0; max];
+        self.gv.hiz.invalidate();
+    }
+
+    #[inline]
+    pub fn get_hdr(&self) -> bool {
+        !self.gv.hdr_cbuf.is_empty()
+    }
+
+    /// 开启或关闭浮点（HDR）颜色buffer
+    ///
+    /// 开启HDR时，片段颜色以f32保存，不做截断，由resolve()进行色调映射后输出到8位颜色buffer。
+    pub fn set_hdr(&mut self, hdr: bool) {
+        let max = if hdr { (self.rect.2 * self.rect.3) as usize } else { 0 };
+        self.gv.hdr_cbuf = vec![[0.0; 4]; max];
+    }
+
+    #[inline]
+    pub fn get_tonemap(&self) -> ETonemap {
+        self.gv.tonemap
+    }
+
+    #[inline]
+    pub fn set_tonemap(&mut self, tonemap: ETonemap) {
+        self.gv.tonemap = tonemap;
+    }
+
+    #[inline]
+    pub fn get_exposure(&self) -> f32 {
+        self.gv.exposure
+    }
+
+    #[inline]
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.gv.exposure = exposure.max(0.0);
+    }
+
+    #[inline]
+    pub fn get_blend(&self) -> BlendState {
+        self.gv.blend
+    }
+
+    /// 设置混合状态，作用于之后输出的片段颜色
+    #[inline]
+    pub fn set_blend(&mut self, blend: BlendState) {
+        self.gv.blend = blend;
+    }
+
+    #[inline]
+    pub fn get_oit(&self) -> EOit {
+        self.gv.oit
+    }
+
+    /// 设置透明片段的处理方式，作用于之后绘制的片段
+    ///
+    /// 开启OIT时，通过深度测试的片段不写入深度和颜色buffer（模板buffer照常更新），
+    /// 与当时的混合状态一起保存到逐像素链表中，由resolve()排序后混合；附加渲染目标不保存OIT片段。
+    #[inline]
+    pub fn set_oit(&mut self, oit: EOit) {
+        self.gv.oit = oit;
+    }
+
+    #[inline]
+    pub fn get_peel_layers(&self) -> usize {
+        self.gv.peel_layers
+    }
+
+    /// 设置深度剥离的最大层数
+    #[inline]
+    pub fn set_peel_layers(&mut self, layers: usize) {
+        self.gv.peel_layers = layers.max(1);
+    }
+
+    /// 返回开启OIT时保存的片段数量（resolve后清空）
+    #[inline]
+    pub fn get_oit_fragments(&self) -> usize {
+        self.gv.frags.len()
+    }
+
+    /// 将多重采样buffer和HDR颜色buffer解析到颜色和深度buffer
+    ///
+    /// - 开启OIT时保存的片段，先按深度从远到近与对应的采样点颜色混合，然后清空；
+    /// - 开启MSAA时，像素颜色为所有采样点颜色的均值，像素深度为采样点中离视点最近的深度，像素模板值为第一个采样点的模板值；
+    /// - 开启HDR时，像素颜色乘以曝光后进行色调映射，再（可选地）进行sRGB编码，输出到8位颜色buffer。
+    pub fn resolve(&mut self) {
+        let hdr = self.get_hdr();
+        if !self.gv.frags.is_empty() {
+            self.resolve_fragments();
+        }
+        let (far, reversed) = (self.depth_far(), self.gv.en_reversed_z);
+        if self.gv.msaa != EMsaa::Off {
+            let n = self.gv.msaa.count();
+            for (k, z) in self.gv.zbuf.iter_mut().enumerate() {
+                let mut sum = [0.0; 4];
+                for sc in &self.gv.ms_cbuf[k * n..(k + 1) * n] {
+                    for (s, v) in sum.iter_mut().zip(sc) {
+                        *s += *v;
+                    }
+                }
+                let c = sum.map(|s| s / n as f32);
+                if hdr {
+                    self.gv.hdr_cbuf[k] = c;
+                } else {
+                    self.gv.cbuf[k] = to_rgba8(&c);
+                }
+                let zs = self.gv.ms_zbuf[k * n..(k + 1) * n].iter();
+                *z = if reversed {
+                    zs.fold(far, |a, b| b.max(a))
+                } else {
+                    zs.fold(far, |a, b| b.min(a))
+                };
+                self.gv.sbuf[k] = self.gv.ms_sbuf[k * n];
+            }
+        }
+        if hdr {
+            let (tonemap, exposure, srgb) = (self.gv.tonemap, self.gv.exposure, self.gv.en_srgb);
+            for (c, h) in self.gv.cbuf.iter_mut().zip(&self.gv.hdr_cbuf) {
+                let mut ldr = h.map(|v| {
+                    let v = tonemap.map(v * exposure);
+                    if srgb {
+                        srgb_encode(v)
+                    } else {
+                        v
+                    }
+                });
+                // alpha不进行色调映射
+                ldr[3] = h[3];
+                *c = to_rgba8(&ldr);
+            }
+        }
+    }
+
+    /// 将OIT片段混合到颜色buffer（开启MSAA时混合到覆盖的采样点）
+    fn resolve_fragments(&mut self) {
+        let hdr = self.get_hdr();
+        let (reversed, layers) = (self.gv.en_reversed_z, self.gv.peel_layers);
+        let n = self.gv.msaa.count();
+        let gv = &mut self.gv;
+        for k in 0..gv.cbuf.len() {
+            let frags = gv.frags.sorted(k, reversed, layers);
+            if frags.is_empty() {
+                continue;
+            }
+            let composite = |dst: &mut [f32; 4], s: usize| {
+                for f in frags.iter().filter(|f| f.mask & (1 << s) != 0) {
+                    *dst = blend_color(&f.blend, &f.color, dst, f.coverage, hdr);
+                }
+            };
+            if gv.msaa != EMsaa::Off {
+                for s in 0..n {
+                    composite(&mut gv.ms_cbuf[k * n + s], s);
+                }
+            } else if hdr {
+                composite(&mut gv.hdr_cbuf[k], 0);
+            } else {
+                let mut c = from_rgba8(&gv.cbuf[k]);
+                composite(&mut c, 0);
+                gv.cbuf[k] = to_rgba8(&c);
+            }
+        }
+        gv.frags.clear();
+    }
+
+    /// 颜色buffer中保存的颜色值：开启HDR时保留原始值，否则截断到[0, 1]
+    #[inline]
+    fn color_value(&self, color: &Vec4) -> [f32; 4] {
+        let c = [color.x, color.y, color.z, color.w];
+        if self.get_hdr() {
+            c
+        } else {
+            c.map(|v| v.clamp(0.0, 1.0))
+        }
+    }
+
+    /// 输出采样点颜色
+    ///
+    /// 片段颜色与buffer中的颜色混合后，再按覆盖比例与buffer中的颜色插值，写入buffer。
+    ///
+    /// - mask: 需要输出的采样点掩码（未开启MSAA时，只有1个采样点）
+    /// - coverage: 像素被覆盖的比例（抗锯齿线段小于1）
+    #[inline]
+    fn set_sample_color(&mut self, i: u32, j: u32, mask: u32, color: &Vec4, coverage: Tyf) {
+        if let Some(k) = self.index(i, j) {
+            let src = self.color_value(color);
+            let hdr = self.get_hdr();
+            let blend = &self.gv.blend;
+            let out = |dst: &[f32; 4]| blend_color(blend, &src, dst, coverage, hdr);
+            if self.gv.msaa == EMsaa::Off {
+                if hdr {
+                    self.gv.hdr_cbuf[k] = out(&self.gv.hdr_cbuf[k]);
+                } else {
+                    self.gv.cbuf[k] = to_rgba8(&out(&from_rgba8(&self.gv.cbuf[k])));
+                }
+            } else {
+                let n = self.gv.msaa.count();
+                for s in 0..n {
+                    if mask & (1 << s) != 0 {
+                        self.gv.ms_cbuf[k * n + s] = out(&self.gv.ms_cbuf[k * n + s]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl IRasterizer for Rasterizer {
+    #[inline]
+    fn set_color(&mut self, i: u32, j: u32, color: &Vec4) {
+        self.set_sample_color(i, j, u32::MAX, color, 1.0);
+    }
+
+    #[inline]
+    fn plot(&mut self, i: u32, j: u32, color: &Vec4, coverage: Tyf) {
+        self.set_sample_color(i, j, u32::MAX, color, coverage);
+    }
+}
+
+impl IGlsl for Rasterizer {
+    #[inline]
+    fn frag_coord(&self) -> &(Vec4, Vec4, Vec4) {
+        &self.gv.gl_FragCoord
+    }
+
+    #[inline]
+    fn front_facing(&self) -> bool {
+        self.gv.gl_FrontFacing
+    }
+
+    #[inline]
+    fn instance(&mut self) -> &mut Instance {
+        &mut self.gv.gl_Instance
+    }
+
+    #[inline]
+    fn wire_frame(&mut self) -> &mut bool {
+        &mut self.gv.en_wire_frame
+    }
+
+    #[inline]
+    fn cull_face(&mut self) -> &mut bool {
+        &mut self.gv.en_cull_face
+    }
+
+    #[inline]
+    fn tiled(&mut self) -> &mut bool {
+        &mut self.gv.en_tiled
+    }
+
+    #[inline]
+    fn srgb(&mut self) -> &mut bool {
+        &mut self.gv.en_srgb
+    }
+
+    #[inline]
+    fn hiz(&mut self) -> &mut bool {
+        &mut self.gv.en_hiz
+    }
+
+    #[inline]
+    fn wire_overlay(&mut self) -> &mut bool {
+        &mut self.gv.en_wire_overlay
+    }
+}
+
+impl IPipeline for Rasterizer {
+    #[inline]
+    fn vertex(&mut self, primitive: &dyn IPrimitive, pidx: usize) {
+        let topology = primitive.topology();
+        if topology.vertices() == 3 {
+            self.gv.stats.submitted += 1;
+        }
+        if topology == ETopology::Triangles {
+            (self.gv.gl_Postion, self.gv.varyings) = primitive.vertex(pidx, &self.gv.gl_Instance);
+            return;
+        }
+        // 只对基本图元实际使用的顶点运行顶点着色器，其余顶点重复最后一个顶点
+        let start = primitive.indices().start;
+        let [ia, ib, ic] = topology.assemble(pidx);
+        let n = topology.vertices();
+        let inst = &self.gv.gl_Instance;
+        let (a, va) = primitive.vertex_at(start + ia, inst);
+        let (b, vb) = if n > 1 {
+            primitive.vertex_at(start + ib, inst)
+        } else {
+            (a, va)
+        };
+        let (c, vc) = if n > 2 {
+            primitive.vertex_at(start + ic, inst)
+        } else {
+            (b, vb)
+        };
+        (self.gv.gl_Postion, self.gv.varyings) = ((a, b, c), (va, vb, vc));
+    }
+
+    fn clipping(&mut self) -> Vec<ClipTriangle> {
+        let tris = clip_triangle(&self.gv.gl_Postion, self.gv.en_reversed_z);
+        // 完全在视锥体内的片段保持不变
+        if tris.len() != 1 || (0..3).any(|k| tris[0][k].bc[k] != 1.0) {
+            self.gv.stats.clipped += 1;
+        }
+        tris
+    }
+
+    fn mapping(&mut self, tri: &ClipTriangle) {
+        let (mut a, mut b, mut c) = (tri[0].pos, tri[1].pos, tri[2].pos);
+        let aw = a.w;
+        let bw = b.w;
+        let cw = c.w;
+
+        // 透视除法（裁剪后w > 0）
+        a = a / a.w;
+        b = b / b.w;
+        c = c / c.w;
+
+        // 视口变换，得到屏幕坐标
+        a = self.mat_viewport.mul_vec(&a);
+        b = self.mat_viewport.mul_vec(&b);
+        c = self.mat_viewport.mul_vec(&c);
+
+        self.gv.gl_FragCoord = (a, b, c);
+        // 保存值1/w到gl_FragCoord，用于透视逆运算（重心坐标校正）
+        self.gv.gl_FragCoord.0.w = aw;
+        self.gv.gl_FragCoord.1.w = bw;
+        self.gv.gl_FragCoord.2.w = cw;
+        self.gv.clip_bc = (tri[0].bc, tri[1].bc, tri[2].bc);
+    }
+
+    fn culling(&mut self) -> bool {
+        let (a, b, c) = self.gv.gl_FragCoord;
+        let a = a.to_vec2().to_vec3(0.0);
+        let b = b.to_vec2().to_vec3(0.0);
+        let c = c.to_vec2().to_vec3(0.0);
+
+        // 通过三角形顶点顺序判断片段的朝向
+        // 在屏幕坐标空间中，摄像头看向的方向即是-z方向，
+        // 所以三角形的法向量只需要比较z方向（即有向面积的符号）即可
+        let area = (b - a).cross(&(c - a)).z;
+        let front = self.gv.front_face.front_facing(area);
+        self.gv.gl_FrontFacing = front;
+        // 片段朝向随varying变量插值传递给片段着色器
+        self.gv.varyings.0.set_front_facing(front);
+        self.gv.varyings.1.set_front_facing(front);
+        self.gv.varyings.2.set_front_facing(front);
+
+        let visible = !(self.gv.en_cull_face && self.gv.cull_mode.culled(front));
+        if visible {
+            self.gv.stats.rasterized += 1;
+        } else {
+            self.gv.stats.culled += 1;
+        }
+        visible
+    }
+
+    fn rasterization(&mut self) -> Vec<Pixel> {
+        let (a, b, c) = self.gv.gl_FragCoord;
+        let (ca, cb, cc) = self.gv.clip_bc;
+
+        // 三个顶点转成定点坐标，计算三条边的边函数；
+        // 统一成逆时针方向，使三角形内部的边函数值均为正，
+        // edges[0], edges[1], edges[2]分别为顶点a, b, c所对边的边函数
+        let (pa, pb, pc) = (to_fixed(&a), to_fixed(&b), to_fixed(&c));
+        let area = EdgeFn::new(pa, pb).eval(pc);
+        if area == 0 {
+            return Vec::new();
+        }
+        let edges = if area > 0 {
+            [EdgeFn::new(pb, pc), EdgeFn::new(pc, pa), EdgeFn::new(pa, pb)]
+        } else {
+            [EdgeFn::new(pc, pb), EdgeFn::new(pa, pc), EdgeFn::new(pb, pa)]
+        };
+        let area = area.abs() as Tyf;
+        let offset = self.depth_offset(&a, &b, &c);
+
+        // 根据边函数值，计算原片段的重心坐标（透视校正的和屏幕空间的）和深度值；
+        // 点不在三角形内时，得到的是三角形所在平面上外插的值
+        let barycentric = |e: [i64; 3]| {
+            // 屏幕空间的重心坐标
+            let bc = Vec3::from(e[0] as Tyf, e[1] as Tyf, e[2] as Tyf) / area;
+            // 深度值在屏幕空间中是线性的，直接用屏幕空间的重心坐标插值
+            let z = interpolate(&bc, &a.z, &b.z, &c.z) as f32 + offset;
+            // 重心坐标校正
+            // - https://www.comp.nus.edu.sg/~lowkl/publications/lowk_persp_interp_techrep.pdf
+            // - https://zhuanlan.zhihu.com/p/144331875
+            // 这里除以w和z是等效的，因为三维空间中，w和z成线性关系（因为透视的原理即z起大，片段越小）
+            let bcc = Vec3::from(bc.x / a.w, bc.y / b.w, bc.z / c.w);
+            let bcp = bcc / (bcc.x + bcc.y + bcc.z);
+            // 重映射为原片段的重心坐标
+            (interpolate(&bcp, &ca, &cb, &cc), interpolate(&bc, &ca, &cb, &cc), z)
+        };
+        // 采样点在三角形内时才计算，否则返回None
+        let inside = |e: [i64; 3]| (0..3).all(|k| e[k] + edges[k].bias >= 0);
+        let sample = |e: [i64; 3]| if inside(e) { Some(barycentric(e)) } else { None };
+
+        // 丢弃视口（或tile）外的像素点（bound_box外扩后可能超出视口）
+        let (x, y, w, h) = self.rect;
+        let (lb, rt) = bound_box(&[a.to_vec2(), b.to_vec2(), c.to_vec2()]);
+        let xlo = lb.x.max(x as Tyf) as u32;
+        let ylo = lb.y.max(y as Tyf) as u32;
+        let xhi = rt.x.min((x + w) as Tyf) as u32;
+        let yhi = rt.y.min((y + h) as Tyf) as u32;
+        if xlo >= xhi || ylo >= yhi {
+            return Vec::new();
+        }
+
+        // 层次深度剃除：三角形的深度范围与覆盖区域的深度范围比较，一定不能通过深度测试时直接丢弃
+        let early = self.early_z();
+        let func = self.gv.depth.func;
+        let zmin = a.z.min(b.z).min(c.z) + offset;
+        let zmax = a.z.max(b.z).max(c.z) + offset;
+        if early && HiZ::occluded(func, zmin, zmax, self.gv.hiz.range(xlo - x, ylo - y, xhi - x, yhi - y)) {
+            return Vec::new();
+        }
+
+        // 采样点相对像素中心的定点偏移
+        let offsets: Vec<(i64, i64)> = self
+            .gv
+            .msaa
+            .offsets()
+            .iter()
+            .map(|o| ((o.x * SUBPIXEL as Tyf) as i64, (o.y * SUBPIXEL as Tyf) as i64))
+            .collect();
+        let n = offsets.len();
+        // 以2x2个像素（quad）为单位光栅化，quad与屏幕坐标的偶数行列对齐；
+        // quad中有像素需要着色时，在4个像素中心计算varying变量（包括三角形外的辅助像素，helper pixel），
+        // 用相邻像素的差值计算varying变量的导数
+        let mut pixels = Vec::new();
+        let (qxlo, qylo) = (xlo & !1, ylo & !1);
+        for qj in (qylo..yhi).step_by(2) {
+            let mut qi = qxlo;
+            while qi < xhi {
+                // 层次深度剃除：跳过被遮挡的单元在当前两行中的所有quad
+                if early && (qi == qxlo || (qi - x).is_multiple_of(HIZ_CELL)) {
+                    let end = ((qi - x) / HIZ_CELL + 1) * HIZ_CELL + x;
+                    let end = end.min(xhi);
+                    let (i0, j0, j1) = (qi.max(xlo), qj.max(ylo), (qj + 2).min(yhi));
+                    if HiZ::occluded(func, zmin, zmax, self.gv.hiz.range(i0 - x, j0 - y, end - x, j1 - y)) {
+                        qi = end;
+                        continue;
+                    }
+                }
+                // quad中4个像素（按左下、右下、左上、右上的顺序）中心的边函数值和测试结果
+                let mut es = [[0; 3]; 4];
+                let mut tested = [None; 4];
+                for (q, e) in es.iter_mut().enumerate() {
+                    let (i, j) = (qi + (q & 1) as u32, qj + (q >> 1) as u32);
+                    // 在像素中心(x+0.5, y+0.5)采样
+                    let px = i as i64 * SUBPIXEL + SUBPIXEL / 2;
+                    let py = j as i64 * SUBPIXEL + SUBPIXEL / 2;
+                    *e = edges.each_ref().map(|f| f.eval((px, py)));
+                    if i < xlo || i >= xhi || j < ylo || j >= yhi {
+                        continue;
+                    }
+                    let k = ((i - x) + (j - y) * w) as usize;
+                    let (mut mask, mut cover) = (0, 0);
+                    let mut zs = [0.0; MAX_SAMPLES];
+                    let mut ss = [0; MAX_SAMPLES];
+                    let mut bc = None;
+                    // 每个采样点单独计算覆盖和深度测试
+                    for (s, &(ox, oy)) in offsets.iter().enumerate() {
+                        let es = [0, 1, 2].map(|t| e[t] + edges[t].a * ox + edges[t].b * oy);
+                        if let Some((sbc, sbcl, z)) = sample(es) {
+                            let (pass, sv) = self.test_stencil(k * n + s, z);
+                            cover |= 1 << s;
+                            zs[s] = z;
+                            ss[s] = sv;
+                            if pass {
+                                mask |= 1 << s;
+                                bc.get_or_insert((sbc, sbcl));
+                            }
+                        }
+                    }
+                    if cover != 0 {
+                        self.gv.stats.fragments += 1;
+                    }
+                    if cover != 0 && mask == 0 {
+                        // 没有采样点通过测试，不需要着色，直接更新模板值
+                        self.write_stencil(k, cover, &ss);
+                        self.gv.stats.depth_rejected += 1;
+                    }
+                    tested[q] = bc.map(|bc| (i, j, mask, cover, zs, ss, bc));
+                }
+                if tested.iter().any(|t| t.is_some()) {
+                    let centers = Varyings::interpolate_quad(
+                        &self.gv.varyings,
+                        &es.map(|e| {
+                            let (bc, bcl, _) = barycentric(e);
+                            (bc, bcl)
+                        }),
+                    );
+                    for (q, t) in tested.iter().enumerate() {
+                        let Some((i, j, mask, cover, zs, ss, (bc, bcl))) = *t else {
+                            continue;
+                        };
+                        // 每个像素只着色一次：优先使用像素中心的varying变量，
+                        // 像素中心不在片段内时，使用第一个通过测试的采样点
+                        let v = if inside(es[q]) {
+                            centers[q]
+                        } else {
+                            let mut v = Varyings::interpolate(&self.gv.varyings, &bc, &bcl);
+                            v.set_derivatives(&centers[q]);
+                            v
+                        };
+
+                        // Test: 取三个点的均值，渲染出三角面模型效果
+                        //let v = Varyings::interpolate(&self.gv.varyings, &(Vec3::fill(1.0) / 3.0), &(Vec3::fill(1.0) / 3.0));
+
+                        pixels.push(Pixel {
+                            i,
+                            j,
+                            v,
+                            mask,
+                            cover,
+                            z: zs,
+                            s: ss,
+                            coverage: 1.0,
+                        });
+                    }
+                }
+                qi += 2;
+            }
+        }
+        pixels
+    }
+
+    fn rasterize_point(&mut self) -> Vec<Pixel> {
+        self.gv.gl_FrontFacing = true;
+        let p = self.gv.gl_Postion.0;
+        if !clip_point(&p, self.gv.en_reversed_z) {
+            return Vec::new();
+        }
+        let v = ClipVertex::new(p, Vec3::from(1.0, 0.0, 0.0));
+        self.mapping(&[v, v, v]);
+        let a = self.gv.gl_FragCoord.0;
+
+        // 像素中心在[c - r, c + r)内的像素
+        let r = self.gv.point_size / 2.0;
+        let lo = |c: Tyf| (c - r - 0.5).ceil().max(0.0) as u32;
+        let hi = |c: Tyf| (c + r - 0.5).ceil().max(0.0) as u32;
+        let v = self.gv.varyings.0;
+        let mut pixels = Vec::new();
+        for j in lo(a.y)..hi(a.y) {
+            for i in lo(a.x)..hi(a.x) {
+                if let Some(px) = self.test_pixel(i, j, a.z, &v) {
+                    pixels.push(px);
+                }
+            }
+        }
+        pixels
+    }
+
+    fn rasterize_line(&mut self) -> Vec<Pixel> {
+        self.gv.gl_FrontFacing = true;
+        let (p, q, _) = self.gv.gl_Postion;
+        let Some([p, q]) = clip_line(&(p, q), self.gv.en_reversed_z) else {
+            return Vec::new();
+        };
+        self.mapping(&[p, q, q]);
+        let (a, b, _) = self.gv.gl_FragCoord;
+        let (ca, cb, _) = self.gv.clip_bc;
+
+        // 按线段光栅化算法和宽度计算线段覆盖的像素
+        let mut pixels = Vec::new();
+        for p in line_pixels(&a.to_vec2(), &b.to_vec2(), self.gv.line_mode, self.gv.line_width) {
+            if p.i < 0 || p.j < 0 {
+                continue;
+            }
+            // 深度值在屏幕空间中是线性的，varying变量需要透视校正
+            let t = p.t;
+            let z = a.z + (b.z - a.z) * t;
+            let bcl = Vec3::from(1.0 - t, t, 0.0);
+            let bcc = Vec3::from(bcl.x / a.w, bcl.y / b.w, 0.0);
+            let bcp = bcc / (bcc.x + bcc.y);
+            let v = Varyings::interpolate(
+                &self.gv.varyings,
+                &interpolate(&bcp, &ca, &cb, &cb),
+                &interpolate(&bcl, &ca, &cb, &cb),
+            );
+            if let Some(mut px) = self.test_pixel(p.i as u32, p.j as u32, z, &v) {
+                px.coverage = p.coverage;
+                pixels.push(px);
+            }
+        }
+        pixels
+    }
+
+    #[inline]
+    fn fragment(&mut self, primitive: &dyn IPrimitive, pidx: usize, pixels: &[Pixel]) {
+        for px in pixels {
+            let out = primitive.fragment(pidx, &self.gv.gl_Instance, &px.v);
+            self.gv.stats.shaded += 1;
+            if let Some(k) = self.index(px.i, px.j) {
+                self.gv.overdraw[k] += 1;
+            }
+            if out.discarded() {
+                continue;
+            }
+            if self.gv.oit != EOit::Off {
+                // 保存到片段链表，不写入深度和颜色
+                if let Some(k) = self.index(px.i, px.j) {
+                    self.write_stencil(k, px.cover, &px.s);
+                    let n = self.gv.msaa.count();
+                    let zs: Vec<f32> = (0..n).filter(|s| px.mask & (1 << s) != 0).map(|s| px.z[s]).collect();
+                    let frag = Fragment {
+                        depth: zs.iter().sum::<f32>() / zs.len() as f32,
+                        color: self.color_value(&out.get(0)),
+                        mask: px.mask,
+                        coverage: px.coverage,
+                        blend: self.gv.blend,
+                    };
+                    let max = (self.rect.2 * self.rect.3) as usize;
+                    self.gv.frags.push(max, k, frag, self.gv.oit == EOit::DepthPeeling);
+                }
+                continue;
+            }
+            // 片段着色器之后才写入深度和模板值
+            if let Some(k) = self.index(px.i, px.j) {
+                self.write_stencil(k, px.cover, &px.s);
+                self.write_depth(k, px.mask, &px.z);
+            }
+            self.set_sample_color(px.i, px.j, px.mask, &out.get(0), px.coverage);
+            // 附加渲染目标，片段着色器未输出的location写入0
+            if let Some(k) = self.index(px.i, px.j) {
+                for (t, target) in self.gv.targets.iter_mut().enumerate() {
+                    let c = out.get(t + 1);
+                    target[k] = [c.x, c.y, c.z, c.w];
+                }
+            }
+        }
+    }
+
+    fn fragment_depth(&mut self, pixels: &[Pixel]) {
+        for px in pixels {
+            if let Some(k) = self.index(px.i, px.j) {
+                self.write_depth(k, px.mask, &px.z);
+            }
+        }
+    }
+
+    fn line_depth(&mut self, points: &[Vec4; 2], color: Vec4, slope: f32) {
+        let (mode, width) = (self.gv.line_mode, self.gv.line_width);
+        // 向视点方向偏移：线段上的像素中心与线段的距离不超过半宽加半个像素
+        let bias = slope * (width / 2.0 + 0.5);
+        let [mut a, mut b] = *points;
+        for p in [&mut a, &mut b] {
+            p.z = if self.gv.en_reversed_z {
+                (p.z + bias).min(1.0)
+            } else {
+                (p.z - bias).max(0.0)
+            };
+        }
+        let v = Varyings::new();
+        for p in line_pixels(&a.to_vec2(), &b.to_vec2(), mode, width) {
+            if p.i < 0 || p.j < 0 {
+                continue;
+            }
+            let z = a.z + (b.z - a.z) * p.t;
+            if let Some(px) = self.test_pixel(p.i as u32, p.j as u32, z, &v) {
+                if let Some(k) = self.index(px.i, px.j) {
+                    self.write_stencil(k, px.cover, &px.s);
+                    self.write_depth(k, px.mask, &px.z);
+                }
+                self.set_sample_color(px.i, px.j, px.mask, &color, p.coverage);
+            }
+        }
+    }
+
+    fn test_stencil(&self, i: usize, z: f32) -> (bool, u8) {
+        let stencil = self.gv.stencil;
+        let sbuf = if self.gv.msaa == EMsaa::Off {
+            &self.gv.sbuf
+        } else {
+            &self.gv.ms_sbuf
+        };
+        let s = sbuf[i];
+        if !stencil.test {
+            return (self.test_depth(i, z), s);
+        }
+        let face = stencil.face(self.gv.gl_FrontFacing);
+        if !face.test(s) {
+            return (false, face.update(face.fail, s));
+        }
+        let pass = self.test_depth(i, z);
+        (pass, face.update(if pass { face.pass } else { face.zfail }, s))
+    }
+
+    #[inline]
+    fn test_depth(&self, i: usize, z: f32) -> bool {
+        // 通过zbuffer剃除被遮挡的片段，z值通过重心坐标插值计算；
+        // 默认丢弃 深度值>=当前深度缓冲值 的片段（丢离视点更远的片段）；
+        // viewport()计算的z范围为[0.0, 1.0]。
+        let depth = self.gv.depth;
+        if !depth.test {
+            return true;
+        }
+        let zbuf = if self.gv.msaa == EMsaa::Off {
+            &self.gv.zbuf
+        } else {
+            &self.gv.ms_zbuf
+        };
+        (0.0..=1.0).contains(&z) && depth.func.compare(z, zbuf[i])
+    }
+
+    fn draw_tiled(&mut self, primitive: &dyn IPrimitive) {
+        // 几何处理：顶点着色、裁剪、屏幕映射和背面剃除
+        let mut tris = Vec::new();
+        for pidx in primitive.primitives() {
+            self.vertex(primitive, pidx);
+            for tri in self.clipping() {
+                self.mapping(&tri);
+                if self.culling() {
+                    tris.push(TileTriangle {
+                        pidx,
+                        frag_coord: self.gv.gl_FragCoord,
+                        clip_bc: self.gv.clip_bc,
+                        varyings: self.gv.varyings,
+                        front_facing: self.gv.gl_FrontFacing,
+                    });
+                }
+            }
+        }
+
+        // 按包围盒将三角形分组到tile中，每个tile中的三角形保持提交顺序
+        let (x, y, w, h) = self.rect;
+        let cols = w.div_ceil(TILE_SIZE);
+        let rows = h.div_ceil(TILE_SIZE);
+        let col = |v: Tyf| ((v.max(x as Tyf) as u32 - x) / TILE_SIZE).min(cols - 1);
+        let row = |v: Tyf| ((v.max(y as Tyf) as u32 - y) / TILE_SIZE).min(rows - 1);
+        let mut bins = vec![Vec::new(); (cols * rows) as usize];
+        for (k, t) in tris.iter().enumerate() {
+            let (a, b, c) = t.frag_coord;
+            let (lb, rt) = bound_box(&[a.to_vec2(), b.to_vec2(), c.to_vec2()]);
+            for ty in row(lb.y)..=row(rt.y) {
+                for tx in col(lb.x)..=col(rt.x) {
+                    bins[(tx + ty * cols) as usize].push(k);
+                }
+            }
+        }
+
+        // 多线程并行光栅化各个tile；每个像素只属于一个tile，所以结果与串行绘制一致
+        let next = AtomicUsize::new(0);
+        let this = &*self;
+        let tiles: Vec<Rasterizer> = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..this.threads.min(bins.len()))
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let k = next.fetch_add(1, Ordering::Relaxed);
+                            if k >= bins.len() {
+                                break;
+                            }
+                            if bins[k].is_empty() {
+                                continue;
+                            }
+                            let tx = x + (k as u32 % cols) * TILE_SIZE;
+                            let ty = y + (k as u32 / cols) * TILE_SIZE;
+                            let rect = (tx, ty, TILE_SIZE.min(x + w - tx), TILE_SIZE.min(y + h - ty));
+                            let mut tile = this.tile(rect);
+                            for &t in &bins[k] {
+                                let t = &tris[t];
+                                tile.gv.gl_FragCoord = t.frag_coord;
+                                tile.gv.clip_bc = t.clip_bc;
+                                tile.gv.varyings = t.varyings;
+                                tile.gv.gl_FrontFacing = t.front_facing;
+                                let pixels = tile.rasterization();
+                                tile.fragment(primitive, t.pidx, &pixels);
+                            }
+                            done.push(tile);
+                        }
+                        done
+                    })
+                })
+                .collect();
+            workers.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+        for tile in &tiles {
+            self.blit(tile);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::shader::{triangle_vertex, FragOutputs, IShader};
+
+#[cfg(test)]
+type VaryingFn = Box<dyn Fn(usize, usize, Vec4) -> Varyings + Send + Sync>;
+#[cfg(test)]
+type FragmentFn = Box<dyn Fn(usize, &Instance, &Varyings) -> FragOutputs + Send + Sync>;
+
+/// 测试用的三角形列表
+///
+/// 顶点按实例的模型变换，varying变量和片段输出（缺省为白色）由闭包给出。
+#[cfg(test)]
+struct Triangles {
+    tris: Vec<[Vec4; 3]>,
+    /// 第pidx个三角形第k个顶点的varying变量：(pidx, k, 顶点坐标)
+    varying: VaryingFn,
+    fragment: FragmentFn,
+    /// 用于遮挡查询的包围盒(最小值, 最大值)
+    bounds: Option<(Vec3, Vec3)>,
+}
+
+#[cfg(test)]
+impl Triangles {
+    fn new(tris: Vec<[Vec4; 3]>) -> Self {
+        Self {
+            tris,
+            varying: Box::new(|_, _, _| Varyings::new()),
+            fragment: Box::new(|_, _, _| Vec4::fill(1.0).into()),
+            bounds: None,
+        }
+    }
+
+    fn varying(mut self, f: impl Fn(usize, usize, Vec4) -> Varyings + Send + Sync + 'static) -> Self {
+        self.varying = Box::new(f);
+        self
+    }
+
+    fn fragment(mut self, f: impl Fn(usize, &Instance, &Varyings) -> FragOutputs + Send + Sync + 'static) -> Self {
+        self.fragment = Box::new(f);
+        self
+    }
+
+    fn color(self, color: Vec4) -> Self {
+        self.fragment(move |_, _, _| color.into())
+    }
+
+    fn bounds(mut self, lo: Vec3, hi: Vec3) -> Self {
+        self.bounds = Some((lo, hi));
+        self
+    }
+}
+
+#[cfg(test)]
+impl IPrimitive for Triangles {
+    fn indices(&self) -> std::ops::Range<usize> {
+        0..self.tris.len()
+    }
+
+    fn bounds(&self, _instances: &[Instance]) -> Option<[Vec4; 8]> {
+        let (lo, hi) = self.bounds?;
+        Some(std::array::from_fn(|k| {
+            let x = if k & 1 == 0 { lo.x } else { hi.x };
+            let y = if k & 2 == 0 { lo.y } else { hi.y };
+            let z = if k & 4 == 0 { lo.z } else { hi.z };
+            Vec4::from(x, y, z, 1.0)
+        }))
+    }
+}
+
+#[cfg(test)]
+impl IShader for Triangles {
+    fn vertex(&self, pidx: usize, inst: &Instance) -> ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)) {
+        let [a, b, c] = self.tris[pidx].map(|p| inst.model.mul_vec(&p));
+        let v = |k: usize, p: Vec4| (self.varying)(pidx, k, p);
+        ((a, b, c), (v(0, a), v(1, b), v(2, c)))
+    }
+
+    fn vertex_at(&self, vidx: usize, inst: &Instance) -> (Vec4, Varyings) {
+        triangle_vertex(self.vertex(vidx / 3, inst), vidx % 3)
+    }
+
+    fn fragment(&self, pidx: usize, inst: &Instance, v: &Varyings) -> FragOutputs {
+        (self.fragment)(pidx, inst, v)
+    }
+}
+
+/// 覆盖整个屏幕、深度为z的三角形
+#[cfg(test)]
+fn full_screen(z: Tyf) -> [Vec4; 3] {
+    [
+        Vec4::from(-1.0, -1.0, z, 1.0),
+        Vec4::from(3.0, -1.0, z, 1.0),
+        Vec4::from(-1.0, 3.0, z, 1.0),
+    ]
+}
+
+#[test]
+fn rasterizer_test() {
+    use magx::*;
+
+    struct R {
+        sz: (u32, u32),
+        cbuf: Vec<[u8; 4]>,
+    }
+
+    impl R {
+        pub fn new(sz: (u32, u32)) -> Self {
+            let max = (sz.0 * sz.1) as usize;
+            Self {
+                sz,
+                cbuf: vec![[0; 4]; max],
+            }
+        }
+
+        pub fn get_color(&self) -> &Vec<[u8; 4]> {
+            &self.cbuf
+        }
+    }
+
+    impl IRasterizer for R {
+        #[inline]
+        fn set_color(&mut self, i: u32, j: u32, color: &Vec4) {
+            if i < self.sz.0 && j < self.sz.1 {
+                let c = (*color) * 255.0;
+                self.cbuf[(i + j * self.sz.0) as usize] = [c.x as u8, c.y as u8, c.z as u8, c.w as u8];
+            }
+        }
+    }
+
+    let wid = 640;
+    let hei = 480;
+    let mut r = R::new((wid, hei));
+    let a = Vec2::from(10.0, 10.0);
+    let b = Vec2::from(50.0, 460.0);
+    let c = Vec2::from(630.0, 430.0);
+    let d = Vec2::from(600.0, 100.0);
+    let colors = [
+        Vec4::from(1.0, 0.0, 0.0, 1.0),
+        Vec4::from(0.0, 1.0, 0.0, 1.0),
+        Vec4::from(0.0, 0.0, 1.0, 1.0),
+    ];
+    r.line(&[a, b], colors[0]);
+    r.line(&[b, c], colors[1]);
+    r.triangle(&[a, c, d], &colors);
+
+    let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/../../assets/golden/rasterizer.png");
+    let diff = crate::compare::golden(golden, r.sz, r.get_color(), &crate::compare::Tolerance::default());
+    assert!(diff.is_ok(), "{}", diff.err().unwrap());
+}
+
+#[test]
+fn tiled_test() {
+    use crate::varying::EInterp;
+
+    // 随机三角形，部分三角形会穿过近平面和视口边界
+    let tris = (0..100)
+        .map(|_| [(); 3].map(|_| ((Vec3::random() * 2.0 - 1.0) * 1.5).to_vec4(1.0)))
+        .collect();
+    let primitive: Box<dyn IPrimitive> = Box::new(
+        Triangles::new(tris)
+            .varying(|_, k, _| {
+                let color = [
+                    Vec3::from(1.0, 0.0, 0.0),
+                    Vec3::from(0.0, 1.0, 0.0),
+                    Vec3::from(0.0, 0.0, 1.0),
+                ];
+                let mut v = Varyings::new();
+                v.push(EInterp::Smooth, color[k]);
+                v
+            })
+            .fragment(|pidx, _, v| {
+                let mut out = FragOutputs::new();
+                out.set(0, v.get::<Vec3>(0).to_vec4(1.0));
+                out.set(1, Vec4::from(pidx as Tyf, 0.0, 0.0, 1.0));
+                out
+            }),
+    );
+
+    for (msaa, hdr) in [(EMsaa::Off, false), (EMsaa::X4, false), (EMsaa::X4, true)] {
+        let render = |tiled: bool| {
+            let mut r = Rasterizer::new((150, 100));
+            r.set_msaa(msaa);
+            r.set_hdr(hdr);
+            r.set_threads(4);
+            *r.wire_frame() = false;
+            *r.cull_face() = false;
+            *r.tiled() = tiled;
+            r.set_targets(2);
+            r.clear_color(&Vec4::fill(0.0));
+            r.clear_depth();
+            r.draw(primitive.as_ref());
+            r.resolve();
+            r
+        };
+        let serial = render(false);
+        let tiled = render(true);
+        assert!(serial.get_color() == tiled.get_color());
+        assert!(serial
+            .get_depth()
+            .iter()
+            .zip(tiled.get_depth())
+            .all(|(a, b)| a.to_bits() == b.to_bits()));
+        // 附加渲染目标：location 1保存图元index，location 2未输出，保持为0
+        assert!(serial.get_target(1) == tiled.get_target(1));
+        assert!(serial.get_target(1).iter().any(|c| c[0] > 0.0));
+        assert!(serial.get_target(2).iter().all(|c| *c == [0.0; 4]));
+    }
+}
+
+#[test]
+fn hdr_test() {
+    use float_cmp::approx_eq;
+
+    assert_eq!(ETonemap::Clamp.map(2.0), 1.0);
+    assert!(approx_eq!(f32, ETonemap::Reinhard.map(1.0), 0.5, epsilon = 0.000001));
+    assert_eq!(ETonemap::Aces.map(0.0), 0.0);
+    assert!(ETonemap::Aces.map(0.5) < ETonemap::Aces.map(1.0));
+    assert!(ETonemap::Aces.map(100.0) <= 1.0);
+
+    let bright = Vec4::from(4.0, 0.5, 0.0, 1.0);
+    let mut r = Rasterizer::new((4, 4));
+    // 未开启HDR时，颜色直接截断
+    r.set_color(1, 1, &bright);
+    assert_eq!(r.get_color()[5], [255, 128, 0, 255]);
+
+    r.set_hdr(true);
+    r.set_color(1, 1, &bright);
+    assert_eq!(r.get_hdr_color()[5], [4.0, 0.5, 0.0, 1.0]);
+    r.resolve();
+    assert_eq!(r.get_color()[5], [255, 128, 0, 255]);
+    r.set_tonemap(ETonemap::Reinhard);
+    r.resolve();
+    assert_eq!(r.get_color()[5], [204, 85, 0, 255]);
+    r.set_exposure(0.25);
+    r.resolve();
+    assert_eq!(r.get_color()[5], [128, 28, 0, 255]);
+    *r.srgb() = true;
+    r.resolve();
+    assert_eq!(r.get_color()[5], [188, 94, 0, 255]);
+}
+
+#[test]
+fn depth_test() {
+    use crate::state::ECompareFunc;
+    use crate::varying::EInterp;
+
+    // 相机坐标中，先绘制近处的红色三角形，再绘制远处的绿色三角形
+    let red = Vec3::from(1.0, 0.0, 0.0);
+    let green = Vec3::from(0.0, 1.0, 0.0);
+    let tris = [
+        (
+            [
+                Vec3::from(-0.5, -0.5, -2.0),
+                Vec3::from(0.5, -0.5, -2.0),
+                Vec3::from(0.0, 0.5, -2.0),
+            ],
+            red,
+        ),
+        (
+            [
+                Vec3::from(-150.0, -100.0, -90.0),
+                Vec3::from(150.0, -100.0, -90.0),
+                Vec3::from(0.0, 150.0, -90.0),
+            ],
+            green,
+        ),
+    ];
+    let render = |reversed: bool, depth: DepthState| {
+        let proj = if reversed {
+            persp_reversed(Angle::Ang(45.0), 1.0, 0.1, 100.0)
+        } else {
+            persp(Angle::Ang(45.0), 1.0, 0.1, 100.0)
+        };
+        let colors: Vec<Vec3> = tris.iter().map(|t| t.1).collect();
+        let primitive: Box<dyn IPrimitive> = Box::new(
+            Triangles::new(tris.iter().map(|t| t.0.map(|p| proj.mul_vec(&p.to_vec4(1.0)))).collect())
+                .varying(move |pidx, _, _| {
+                    let mut v = Varyings::new();
+                    v.push(EInterp::Flat, colors[pidx]);
+                    v
+                })
+                .fragment(|_, _, v| v.get::<Vec3>(0).to_vec4(1.0).into()),
+        );
+        let mut r = Rasterizer::new((32, 32));
+        *r.wire_frame() = false;
+        *r.tiled() = false;
+        r.set_reversed_z(reversed);
+        r.set_depth_state(DepthState {
+            func: if reversed { depth.func.reversed() } else { depth.func },
+            ..depth
+        });
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        r.draw(primitive.as_ref());
+        r
+    };
+
+    // 反向Z与正常深度的结果一致：近处的片段遮挡远处的片段
+    let r = render(false, DepthState::new());
+    let mut rr = render(true, DepthState::new());
+    assert_eq!(r.get_color()[16 + 16 * 32], [255, 0, 0, 255]);
+    assert_eq!(r.get_color()[2 + 16 * 32], [0, 255, 0, 255]);
+    assert!(r.get_color() == rr.get_color());
+    assert!(r.get_depth()[16 + 16 * 32] < r.get_depth()[2 + 16 * 32]);
+    assert!(rr.get_depth()[16 + 16 * 32] > rr.get_depth()[2 + 16 * 32]);
+    rr.clear_depth();
+    assert!(rr.get_depth().iter().all(|z| *z == 0.0));
+
+    // Always且不写入深度：后绘制的片段覆盖颜色，深度buffer保持不变
+    let r = render(
+        false,
+        DepthState {
+            test: true,
+            write: false,
+            func: ECompareFunc::Always,
+        },
+    );
+    assert_eq!(r.get_color()[16 + 16 * 32], [0, 255, 0, 255]);
+    assert!(r.get_depth().iter().all(|z| *z == 1.0));
+
+    // 关闭深度测试的结果相同
+    let r2 = render(
+        false,
+        DepthState {
+            test: false,
+            ..DepthState::new()
+        },
+    );
+    assert!(r.get_color() == r2.get_color());
+    assert!(r2.get_depth().iter().all(|z| *z == 1.0));
+
+    // Greater：保留离视点更远的片段
+    let r = render(
+        false,
+        DepthState {
+            func: ECompareFunc::Greater,
+            ..DepthState::new()
+        },
+    );
+    assert_eq!(r.get_color()[16 + 16 * 32], [0, 0, 0, 0]);
+}
+
+#[test]
+fn stencil_test() {
+    use crate::state::{ECompareFunc, EStencilOp, StencilFace};
+
+    // 覆盖整个屏幕的三角形，以及左下角的小三角形（z更大，被全屏三角形遮挡）
+    let full = full_screen(0.0);
+    let small = [
+        Vec4::from(-1.0, -1.0, 0.5, 1.0),
+        Vec4::from(0.0, -1.0, 0.5, 1.0),
+        Vec4::from(-1.0, 0.0, 0.5, 1.0),
+    ];
+    let wid = 16;
+    let mut r = Rasterizer::new((wid, wid));
+    *r.wire_frame() = false;
+    *r.tiled() = false;
+    let draw = |r: &mut Rasterizer, tri: [Vec4; 3]| {
+        let primitive: Box<dyn IPrimitive> = Box::new(Triangles::new(vec![tri]));
+        r.draw(primitive.as_ref());
+    };
+    let inside = |i: u32, j: u32| i + j < wid / 2 - 1;
+
+    // 写入模板值，不写入颜色和深度
+    r.clear_color(&Vec4::fill(0.0));
+    r.clear_depth();
+    r.clear_stencil();
+    r.set_blend(BlendState {
+        mask: [false; 4],
+        ..BlendState::new()
+    });
+    r.set_depth_state(DepthState {
+        write: false,
+        ..DepthState::new()
+    });
+    r.set_stencil_state(StencilState::both(StencilFace {
+        reference: 1,
+        pass: EStencilOp::Replace,
+        ..StencilFace::new()
+    }));
+    draw(&mut r, small);
+    // 只在模板值不等于1的位置绘制
+    r.set_blend(BlendState::new());
+    r.set_depth_state(DepthState::new());
+    r.set_stencil_state(StencilState::both(StencilFace {
+        func: ECompareFunc::NotEqual,
+        reference: 1,
+        ..StencilFace::new()
+    }));
+    draw(&mut r, full);
+    for i in 0..wid {
+        for j in 0..wid {
+            let k = (i + j * wid) as usize;
+            if inside(i, j) {
+                assert_eq!(r.get_stencil()[k], 1);
+                assert_eq!(r.get_color()[k], [0, 0, 0, 0]);
+            } else if i + j > wid / 2 {
+                assert_eq!(r.get_stencil()[k], 0);
+                assert_eq!(r.get_color()[k], [255; 4]);
+            }
+        }
+    }
+
+    // 更远的全屏三角形：在已绘制的位置深度测试失败，执行zfail操作（模板值加1）；
+    // 在未绘制的位置通过测试，执行pass操作（模板值置0）
+    r.set_stencil_state(StencilState::both(StencilFace {
+        zfail: EStencilOp::Incr,
+        pass: EStencilOp::Zero,
+        ..StencilFace::new()
+    }));
+    draw(&mut r, full_screen(0.5));
+    for i in 0..wid {
+        for j in 0..wid {
+            let k = (i + j * wid) as usize;
+            if inside(i, j) {
+                assert_eq!(r.get_stencil()[k], 0);
+            } else if i + j > wid / 2 {
+                assert_eq!(r.get_stencil()[k], 1);
+            }
+        }
+    }
+    r.clear_stencil();
+    assert!(r.get_stencil().iter().all(|s| *s == 0));
+}
+
+#[test]
+fn discard_test() {
+    use crate::state::{EStencilOp, StencilFace};
+    use crate::varying::EInterp;
+
+    let wid = 16;
+    for (msaa, tiled) in [(EMsaa::Off, false), (EMsaa::X4, false), (EMsaa::X4, true)] {
+        let mut r = Rasterizer::new((wid, wid));
+        *r.wire_frame() = false;
+        *r.tiled() = tiled;
+        r.set_msaa(msaa);
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        r.clear_stencil();
+        r.set_stencil_state(StencilState::both(StencilFace {
+            reference: 1,
+            pass: EStencilOp::Replace,
+            ..StencilFace::new()
+        }));
+        // 丢弃x < 0的片段
+        let primitive: Box<dyn IPrimitive> = Box::new(
+            Triangles::new(vec![full_screen(0.0)])
+                .varying(|_, _, p| {
+                    let mut v = Varyings::new();
+                    v.push(EInterp::Smooth, p.x);
+                    v
+                })
+                .fragment(|_, _, v| {
+                    if v.get::<Tyf>(0) < 0.0 {
+                        FragOutputs::discard()
+                    } else {
+                        Vec4::fill(1.0).into()
+                    }
+                }),
+        );
+        r.draw(primitive.as_ref());
+        r.resolve();
+        for i in 0..wid {
+            for j in 0..wid {
+                let k = (i + j * wid) as usize;
+                if i < wid / 2 - 1 {
+                    // 被丢弃的像素不修改颜色、深度和模板值
+                    assert_eq!(r.get_color()[k], [0, 0, 0, 0]);
+                    assert_eq!(r.get_depth()[k], 1.0);
+                    assert_eq!(r.get_stencil()[k], 0);
+                } else if i > wid / 2 {
+                    assert_eq!(r.get_color()[k], [255; 4]);
+                    assert_eq!(r.get_depth()[k], 0.5);
+                    assert_eq!(r.get_stencil()[k], 1);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn hiz_test() {
+    use crate::varying::EInterp;
+
+    // 颜色为图元index / n
+    let shaded = |tris: Vec<[Vec4; 3]>| {
+        let n = tris.len() as Tyf;
+        Triangles::new(tris)
+            .varying(move |pidx, _, _| {
+                let mut v = Varyings::new();
+                v.push(EInterp::Flat, Vec3::fill(pidx as Tyf / n));
+                v
+            })
+            .fragment(|_, _, v| v.get::<Vec3>(0).to_vec4(1.0).into())
+    };
+
+    // 开启和关闭深度金字塔的绘制结果需要完全一致
+    let tris = (0..100)
+        .map(|_| [(); 3].map(|_| ((Vec3::random() * 2.0 - 1.0) * 1.5).to_vec4(1.0)))
+        .collect();
+    let primitive: Box<dyn IPrimitive> = Box::new(shaded(tris));
+    for (msaa, tiled) in [(EMsaa::Off, false), (EMsaa::X4, false), (EMsaa::X4, true)] {
+        let render = |hiz: bool| {
+            let mut r = Rasterizer::new((150, 100));
+            r.set_msaa(msaa);
+            *r.wire_frame() = false;
+            *r.cull_face() = false;
+            *r.tiled() = tiled;
+            *r.hiz() = hiz;
+            r.clear_color(&Vec4::fill(0.0));
+            r.clear_depth();
+            r.draw(primitive.as_ref());
+            r.resolve();
+            r
+        };
+        let on = render(true);
+        let off = render(false);
+        assert!(on.get_color() == off.get_color());
+        assert!(on
+            .get_depth()
+            .iter()
+            .zip(off.get_depth())
+            .all(|(a, b)| a.to_bits() == b.to_bits()));
+    }
+
+    // 遮挡查询：全屏三角形之后的包围盒被完全遮挡
+    let query = |r: &mut Rasterizer, lo: Vec3, hi: Vec3| r.query_occlusion(&Triangles::new(Vec::new()).bounds(lo, hi), &[]);
+    for hiz in [true, false] {
+        let mut r = Rasterizer::new((32, 32));
+        *r.wire_frame() = false;
+        *r.tiled() = false;
+        *r.hiz() = hiz;
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        assert!(query(&mut r, Vec3::from(-0.5, -0.5, 0.5), Vec3::from(0.5, 0.5, 0.8)));
+        let primitive: Box<dyn IPrimitive> = Box::new(shaded(vec![full_screen(0.0)]));
+        r.draw(primitive.as_ref());
+        let (color, depth) = (r.get_color().clone(), r.get_depth().clone());
+        let (stats, overdraw) = (r.get_stats(), r.get_overdraw().clone());
+        r.gv.gl_FrontFacing = false;
+        let state = (r.gv.gl_Postion, r.gv.gl_FragCoord, r.gv.clip_bc);
+        let varying = r.gv.varyings.0.get::<Vec3>(0);
+        assert!(!query(&mut r, Vec3::from(-0.5, -0.5, 0.5), Vec3::from(0.5, 0.5, 0.8)));
+        assert!(query(&mut r, Vec3::from(-0.5, -0.5, -0.5), Vec3::from(0.5, 0.5, 0.8)));
+        // 包围盒跨过近平面
+        assert!(query(&mut r, Vec3::from(-0.5, -0.5, -2.0), Vec3::from(0.5, 0.5, 0.8)));
+        // 查询不修改任何buffer、管线统计和当前绘制的图元状态
+        assert!(*r.get_color() == color);
+        assert!(*r.get_depth() == depth);
+        assert_eq!(r.get_stats(), stats);
+        assert!(*r.get_overdraw() == overdraw);
+        assert!(!r.gv.gl_FrontFacing);
+        assert!((r.gv.gl_Postion, r.gv.gl_FragCoord, r.gv.clip_bc) == state);
+        assert_eq!(r.gv.varyings.0.get::<Vec3>(0), varying);
+    }
+}
+
+#[test]
+fn topology_test() {
+    use crate::varying::EInterp;
+    use std::ops::Range;
+
+    assert_eq!(ETopology::Lines.count(5), 2);
+    assert_eq!(ETopology::LineStrip.count(5), 4);
+    assert_eq!(ETopology::TriangleStrip.count(2), 0);
+    assert_eq!(ETopology::TriangleStrip.assemble(1), [2, 1, 3]);
+    assert_eq!(ETopology::TriangleFan.assemble(2), [0, 3, 4]);
+
+    /// 按拓扑组装的顶点列表，varying变量为顶点index
+    struct Vertices(Vec<Vec4>, ETopology);
+
+    impl IPrimitive for Vertices {
+        fn indices(&self) -> Range<usize> {
+            if self.1 == ETopology::Triangles {
+                0..self.0.len() / 3
+            } else {
+                0..self.0.len()
+            }
+        }
+
+        fn topology(&self) -> ETopology {
+            self.1
+        }
+    }
+
+    impl IShader for Vertices {
+        fn vertex_at(&self, vidx: usize, _inst: &Instance) -> (Vec4, Varyings) {
+            let mut v = Varyings::new();
+            v.push(EInterp::Smooth, vidx as Tyf);
+            (self.0[vidx], v)
+        }
+
+        fn fragment(&self, _pidx: usize, _inst: &Instance, v: &Varyings) -> FragOutputs {
+            Vec4::from(1.0, v.get::<Tyf>(0) / 4.0, 0.0, 1.0).into()
+        }
+    }
+
+    let wid = 16;
+    let mut r = Rasterizer::new((wid, wid));
+    *r.wire_frame() = false;
+    *r.tiled() = false;
+    *r.cull_face() = false;
+    let draw = |r: &mut Rasterizer, vertices: Vec<Vec4>, topology: ETopology| {
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        let primitive: Box<dyn IPrimitive> = Box::new(Vertices(vertices, topology));
+        r.draw(primitive.as_ref());
+        r.get_color().iter().filter(|c| c[0] > 0).count()
+    };
+
+    // 水平线段覆盖一整行像素，varying变量沿线段插值
+    let line = vec![Vec4::from(-1.0, 0.0, 0.0, 1.0), Vec4::from(1.0, 0.0, 0.0, 1.0)];
+    assert_eq!(draw(&mut r, line.clone(), ETopology::Lines), wid as usize);
+    let row = &r.get_color()[(wid * wid / 2) as usize..(wid * wid / 2 + wid) as usize];
+    assert!(row.windows(2).all(|c| c[0][1] <= c[1][1]));
+    assert!(row[0][1] < 5);
+    assert!(row[wid as usize - 1][1] > 50);
+    // 被深度测试剃除的线段
+    r.clear_color(&Vec4::fill(0.0));
+    r.clear_depth();
+    r.set_blend(BlendState {
+        mask: [false; 4],
+        ..BlendState::new()
+    });
+    r.draw(&Vertices(full_screen(0.0).to_vec(), ETopology::Triangles));
+    r.set_blend(BlendState::new());
+    for (z, visible) in [(0.5, false), (-0.5, true)] {
+        let line: Box<dyn IPrimitive> = Box::new(Vertices(line.iter().map(|v| v.z(z)).collect(), ETopology::Lines));
+        r.draw(line.as_ref());
+        assert_eq!(r.get_color().iter().any(|c| c[0] > 0), visible);
+    }
+
+    // 相连的线段不重复绘制端点；两条线段穿过近平面被裁剪
+    let strip = vec![
+        Vec4::from(-1.0, -0.5, 0.0, 1.0),
+        Vec4::from(0.0, -0.5, 0.0, 1.0),
+        Vec4::from(1.0, -0.5, 0.0, 1.0),
+    ];
+    assert_eq!(draw(&mut r, strip, ETopology::LineStrip), wid as usize);
+    let clipped = vec![Vec4::from(-1.0, 0.0, -3.0, 1.0), Vec4::from(1.0, 0.0, 1.0, 1.0)];
+    assert_eq!(draw(&mut r, clipped, ETopology::Lines), wid as usize / 2);
+
+    // 点的大小
+    let points = vec![Vec4::from(0.0, 0.0, 0.0, 1.0), Vec4::from(0.5, 0.5, 2.0, 1.0)];
+    assert_eq!(draw(&mut r, points.clone(), ETopology::Points), 1);
+    r.set_point_size(3.0);
+    assert_eq!(draw(&mut r, points, ETopology::Points), 9);
+
+    // 三角形带和三角形扇覆盖整个屏幕
+    let quad = vec![
+        Vec4::from(-1.0, -1.0, 0.0, 1.0),
+        Vec4::from(1.0, -1.0, 0.0, 1.0),
+        Vec4::from(-1.0, 1.0, 0.0, 1.0),
+        Vec4::from(1.0, 1.0, 0.0, 1.0),
+    ];
+    *r.cull_face() = true;
+    assert_eq!(draw(&mut r, quad.clone(), ETopology::TriangleStrip), (wid * wid) as usize);
+    let fan = vec![quad[0], quad[1], quad[3], quad[2]];
+    assert_eq!(draw(&mut r, fan, ETopology::TriangleFan), (wid * wid) as usize);
+    assert_eq!(
+        draw(&mut r, quad[..3].to_vec(), ETopology::Triangles),
+        (wid * (wid - 1) / 2) as usize
+    );
+}
+
+#[test]
+fn line_test() {
+    // Bresenham：x为主方向时每列一个像素，像素中心与直线的距离不超过半个像素
+    let (a, b) = (Vec2::from(0.5, 0.5), Vec2::from(10.5, 4.5));
+    let pixels = line_pixels(&a, &b, ELineMode::Bresenham, 1.0);
+    assert_eq!(pixels.len(), 10);
+    for (k, p) in pixels.iter().enumerate() {
+        assert_eq!(p.i, k as i64);
+        assert!((p.j as Tyf - 0.4 * p.i as Tyf).abs() <= 0.5);
+    }
+    let pixels = line_pixels(&Vec2::from(2.5, 0.5), &Vec2::from(4.5, 8.5), ELineMode::Bresenham, 1.0);
+    assert!((0..8).all(|j| pixels[j as usize].j == j));
+
+    // Wu：每列像素的覆盖比例之和等于线段宽度；宽线段每列覆盖width个像素
+    for width in [1.0, 3.0] {
+        let pixels = line_pixels(&a, &b, ELineMode::Wu, width);
+        for i in 0..10 {
+            let sum: Tyf = pixels.iter().filter(|p| p.i == i).map(|p| p.coverage).sum();
+            assert!((sum - width).abs() < 1e-4);
+        }
+    }
+    assert_eq!(line_pixels(&a, &b, ELineMode::Dda, 3.0).len(), 30);
+    assert_eq!(line_pixels(&a, &b, ELineMode::Bresenham, 3.0).len(), 30);
+
+    // 深度测试：深度值沿线段插值，被遮挡的部分不绘制
+    let wid = 16;
+    let mut r = Rasterizer::new((wid, wid));
+    r.clear_color(&Vec4::fill(0.0));
+    r.clear_depth();
+    let red = Vec4::from(1.0, 0.0, 0.0, 1.0);
+    let green = Vec4::from(0.0, 1.0, 0.0, 1.0);
+    r.line_depth(&[Vec4::from(0.0, 8.5, 0.5, 1.0), Vec4::from(16.0, 8.5, 0.5, 1.0)], red, 0.0);
+    r.line_depth(&[Vec4::from(8.5, 0.0, 0.2, 1.0), Vec4::from(8.5, 16.0, 0.8, 1.0)], green, 0.0);
+    let at = |i: u32, j: u32| (i + j * wid) as usize;
+    assert_eq!(r.get_color()[at(8, 8)], [255, 0, 0, 255]);
+    assert_eq!(r.get_color()[at(8, 2)], [0, 255, 0, 255]);
+    assert_eq!(r.get_color()[at(8, 12)], [0, 255, 0, 255]);
+    assert!((r.get_depth()[at(8, 12)] - (0.2 + 0.6 * 12.5 / 16.0)).abs() < 1e-4);
+
+    // 抗锯齿线段按覆盖比例与背景颜色插值
+    r.clear_color(&Vec4::fill(0.0));
+    r.clear_depth();
+    r.set_line_mode(ELineMode::Wu);
+    r.line_depth(&[Vec4::from(0.0, 4.0, 0.5, 1.0), Vec4::from(16.0, 4.0, 0.5, 1.0)], red, 0.0);
+    assert_eq!(r.get_color()[at(5, 3)][0], 128);
+    assert_eq!(r.get_color()[at(5, 4)][0], 128);
+    assert_eq!(r.get_color()[at(5, 5)][0], 0);
+}
+
+#[test]
+fn overlay_test() {
+    let wid = 16;
+    let mut r = Rasterizer::new((wid, wid));
+    *r.wire_frame() = false;
+    // 覆盖整个屏幕、深度值相同的矩形（2个三角形），颜色为红色
+    let p = |x: Tyf, y: Tyf| Vec4::from(x, y, 0.5, 1.0);
+    let quad: Box<dyn IPrimitive> = Box::new(
+        Triangles::new(vec![
+            [p(-1.0, -1.0), p(1.0, -1.0), p(1.0, 1.0)],
+            [p(-1.0, -1.0), p(1.0, 1.0), p(-1.0, 1.0)],
+        ])
+        .color(Vec4::from(1.0, 0.0, 0.0, 1.0)),
+    );
+    // 边的像素数量：对角线、左边和下边（右边和上边在屏幕外）
+    let n = (3 * wid - 2) as usize;
+    let edges = |r: &mut Rasterizer| {
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        r.draw(quad.as_ref());
+        r.get_color().iter().filter(|c| c[1] == 255).count()
+    };
+    assert_eq!(edges(&mut r), 0);
+    let k = (12 + 3 * wid) as usize;
+    let z = r.get_depth()[k];
+
+    // 叠加网格：实体模型按多边形偏移向远处移动，边通过深度测试
+    *r.wire_overlay() = true;
+    assert_eq!(edges(&mut r), n);
+    for tiled in [false, true] {
+        *r.tiled() = tiled;
+        assert_eq!(edges(&mut r), n);
+        assert!(r.get_depth()[k] > z);
+    }
+    r.set_reversed_z(true);
+    *r.wire_overlay() = false;
+    assert_eq!(edges(&mut r), 0);
+    let z = r.get_depth()[k];
+    *r.wire_overlay() = true;
+    assert_eq!(edges(&mut r), n);
+    assert!(r.get_depth()[k] < z);
+    r.set_reversed_z(false);
+
+    // 没有多边形偏移时，边与实体模型的深度值相同，不能通过Less深度测试
+    r.set_polygon_offset(0.0, 0.0);
+    assert_eq!(edges(&mut r), 0);
+}
+
+#[test]
+fn derivative_test() {
+    use crate::varying::EInterp;
+
+    // 半个屏幕的三角形，斜边上的2x2像素块包含三角形外的辅助像素
+    let half = [
+        Vec4::from(-1.0, -1.0, 0.0, 1.0),
+        Vec4::from(1.0, -1.0, 0.0, 1.0),
+        Vec4::from(-1.0, 1.0, 0.0, 1.0),
+    ];
+    let wid = 16;
+    for (interp, msaa, tiled) in [
+        (EInterp::NoPerspective, EMsaa::Off, false),
+        (EInterp::Smooth, EMsaa::Off, true),
+        (EInterp::Smooth, EMsaa::X4, false),
+    ] {
+        let mut r = Rasterizer::new((wid, wid));
+        *r.wire_frame() = false;
+        *r.tiled() = tiled;
+        r.set_msaa(msaa);
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        // 输出NDC坐标的屏幕空间导数
+        let primitive: Box<dyn IPrimitive> = Box::new(
+            Triangles::new(vec![half])
+                .varying(move |_, _, p| {
+                    let mut v = Varyings::new();
+                    v.push(interp, Vec2::from(p.x / p.w, p.y / p.w));
+                    v
+                })
+                .fragment(move |_, _, v| {
+                    // 一个像素对应的NDC坐标变化量为2/wid
+                    let dx = v.dfdx::<Vec2>(0) * (wid as Tyf / 2.0);
+                    let dy = v.dfdy::<Vec2>(0) * (wid as Tyf / 2.0);
+                    Vec4::from(dx.x, dx.y.abs(), dy.y, 1.0 - dy.x.abs()).into()
+                }),
+        );
+        r.draw(primitive.as_ref());
+        r.resolve();
+        let mut covered = 0;
+        for k in 0..(wid * wid) as usize {
+            let c = r.get_color()[k];
+            if c[3] != 255 {
+                // 未覆盖或MSAA部分覆盖的像素
+                continue;
+            }
+            covered += 1;
+            // 所有片段（包括斜边上的片段）的导数都等于2/wid
+            for (ch, expect) in c.iter().zip([255u8, 0, 255, 255]) {
+                assert!(ch.abs_diff(expect) <= 1, "pixel {}: {:?}", k, c);
+            }
+        }
+        assert!(covered > 0);
+    }
+}
+
+#[test]
+fn cull_test() {
+    // 左半屏幕为逆时针三角形，右半屏幕为顺时针三角形
+    let ccw = [
+        Vec4::from(-1.0, -1.0, 0.0, 1.0),
+        Vec4::from(0.0, -1.0, 0.0, 1.0),
+        Vec4::from(-1.0, 1.0, 0.0, 1.0),
+    ];
+    let cw = [
+        Vec4::from(0.0, -1.0, 0.0, 1.0),
+        Vec4::from(0.0, 1.0, 0.0, 1.0),
+        Vec4::from(1.0, -1.0, 0.0, 1.0),
+    ];
+    let wid = 16;
+    let (white, red, none) = ([255u8; 4], [255, 0, 0, 255], [0u8; 4]);
+    for tiled in [false, true] {
+        for (enable, mode, front_face, expect) in [
+            (false, ECullMode::Back, EFrontFace::Ccw, (white, red)),
+            (true, ECullMode::None, EFrontFace::Ccw, (white, red)),
+            (true, ECullMode::Back, EFrontFace::Ccw, (white, none)),
+            (true, ECullMode::Back, EFrontFace::Cw, (none, white)),
+            (true, ECullMode::Front, EFrontFace::Ccw, (none, red)),
+            (true, ECullMode::Front, EFrontFace::Cw, (red, none)),
+            (true, ECullMode::FrontAndBack, EFrontFace::Ccw, (none, none)),
+        ] {
+            let mut r = Rasterizer::new((wid, wid));
+            *r.wire_frame() = false;
+            *r.tiled() = tiled;
+            *r.cull_face() = enable;
+            r.set_cull_mode(mode);
+            r.set_front_face(front_face);
+            r.clear_color(&Vec4::fill(0.0));
+            r.clear_depth();
+            // 正面片段输出白色，背面片段输出红色
+            let primitive: Box<dyn IPrimitive> = Box::new(Triangles::new(vec![ccw, cw]).fragment(|_, _, v| {
+                if v.front_facing() {
+                    Vec4::fill(1.0).into()
+                } else {
+                    Vec4::from(1.0, 0.0, 0.0, 1.0).into()
+                }
+            }));
+            r.draw(primitive.as_ref());
+            r.resolve();
+            // 分别检查两个三角形内部的像素
+            assert_eq!(r.get_color()[(1 + wid) as usize], expect.0, "{:?} {:?}", mode, front_face);
+            assert_eq!(
+                r.get_color()[(wid - 2 + wid) as usize],
+                expect.1,
+                "{:?} {:?}",
+                mode,
+                front_face
+            );
+        }
+    }
+}
+
+#[test]
+fn instanced_test() {
+    let wid = 16;
+    let instances: Vec<Instance> = (0..3)
+        .map(|k| {
+            let offset = Vec3::from(0.5 * k as Tyf, 0.5 * k as Tyf, 0.0);
+            Instance::from(translate(&Mat4::eye(1.0), &offset), Vec4::from(0.0, 1.0, k as Tyf / 2.0, 1.0))
+        })
+        .collect();
+    for tiled in [false, true] {
+        let mut r = Rasterizer::new((wid, wid));
+        *r.wire_frame() = false;
+        *r.tiled() = tiled;
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        // 左下角的小三角形，按实例的模型变换平移，片段输出实例颜色，红色分量为实例index
+        let primitive: Box<dyn IPrimitive> = Box::new(
+            Triangles::new(vec![[
+                Vec4::from(-1.0, -1.0, 0.0, 1.0),
+                Vec4::from(-0.5, -1.0, 0.0, 1.0),
+                Vec4::from(-1.0, -0.5, 0.0, 1.0),
+            ]])
+            .fragment(|_, inst, _| inst.tint.x(inst.id as Tyf / 255.0).into()),
+        );
+        r.draw_instanced(primitive.as_ref(), &instances);
+        // 每个实例平移wid / 4个像素
+        for k in 0..3 {
+            let p = 1 + k * wid / 4;
+            let c = r.get_color()[(p + p * wid) as usize];
+            assert_eq!(c, [k as u8, 255, [0, 128, 255][k as usize], 255], "instance {}", k);
+        }
+        // 绘制完成后恢复为非实例化绘制
+        assert_eq!(r.instance().id, 0);
+        assert_eq!(r.instance().tint, Vec4::fill(1.0));
+    }
+}
+
+#[test]
+fn stats_test() {
+    let v = |x: Tyf, y: Tyf, z: Tyf| Vec4::from(x, y, z, 1.0);
+    let quad = |z: Tyf| {
+        [
+            [v(-1.0, -1.0, z), v(1.0, -1.0, z), v(1.0, 1.0, z)],
+            [v(-1.0, -1.0, z), v(1.0, 1.0, z), v(-1.0, 1.0, z)],
+        ]
+    };
+    let mut tris = Vec::new();
+    // 覆盖整个屏幕的两个quad，后面的quad所有片段都不能通过深度测试
+    tris.extend(quad(0.0));
+    tris.extend(quad(0.5));
+    // 背面
+    tris.push([v(-0.5, -0.5, 0.0), v(-0.5, 0.5, 0.0), v(0.5, -0.5, 0.0)]);
+    // 完全在视锥体外
+    tris.push([v(2.0, -0.5, 0.0), v(3.0, -0.5, 0.0), v(2.0, 0.5, 0.0)]);
+
+    let wid = 8;
+    let pixels = (wid * wid) as usize;
+    for (hiz, tiled) in [(false, false), (false, true), (true, false)] {
+        let mut r = Rasterizer::new((wid, wid));
+        *r.wire_frame() = false;
+        *r.tiled() = tiled;
+        *r.hiz() = hiz;
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        let primitive: Box<dyn IPrimitive> = Box::new(Triangles::new(tris.clone()));
+        r.draw(primitive.as_ref());
+        // 开启层次深度剃除时，后面的quad被提前剃除，不产生片段
+        let rejected = if hiz { 0 } else { pixels };
+        assert_eq!(
+            r.get_stats(),
+            PipelineStats {
+                submitted: 6,
+                clipped: 1,
+                culled: 1,
+                rasterized: 4,
+                fragments: pixels + rejected,
+                depth_rejected: rejected,
+                shaded: pixels,
+            }
+        );
+        assert!(r.get_overdraw().iter().all(|n| *n == 1));
+
+        // 再绘制一次前面的quad，每个像素着色两次
+        let primitive: Box<dyn IPrimitive> = Box::new(Triangles::new(quad(-0.5).to_vec()));
+        r.draw(primitive.as_ref());
+        assert_eq!(r.get_stats().shaded, pixels * 2);
+        assert!(r.get_overdraw().iter().all(|n| *n == 2));
+
+        r.clear_stats();
+        assert_eq!(r.get_stats(), PipelineStats::default());
+        assert!(r.get_overdraw().iter().all(|n| *n == 0));
+    }
+}
+
+#[test]
+fn resize_test() {
+    let primitive: Box<dyn IPrimitive> = Box::new(Triangles::new(vec![full_screen(0.5)]).fragment(|_, _, _| {
+        let mut out = FragOutputs::new();
+        out.set(0, Vec4::from(0.0, 1.0, 0.0, 1.0));
+        out.set(1, Vec4::fill(1.0));
+        out
+    }));
+    let mut r = Rasterizer::new((16, 8));
+    *r.wire_frame() = false;
+    r.set_msaa(EMsaa::X4);
+    r.set_hdr(true);
+    r.set_targets(1);
+    r.set_reversed_z(true);
+
+    for sz in [(7, 5), (33, 20), (1, 1)] {
+        r.resize(sz);
+        let max = (sz.0 * sz.1) as usize;
+        assert_eq!(r.sz, sz);
+        assert_eq!(r.get_msaa(), EMsaa::X4);
+        assert!(r.get_hdr() && r.get_reversed_z());
+        assert_eq!(
+            (r.get_color().len(), r.get_depth().len(), r.get_overdraw().len()),
+            (max, max, max)
+        );
+        assert_eq!((r.get_hdr_color().len(), r.get_target(1).len()), (max, max));
+        assert!(r.get_depth().iter().all(|z| *z == 0.0));
+
+        // 视口随大小改变，覆盖屏幕的三角形仍然覆盖所有像素
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        r.draw(primitive.as_ref());
+        r.resolve();
+        assert!(r.get_color().iter().all(|c| *c == [0, 255, 0, 255]));
+        assert!(r.get_target(1).iter().all(|c| *c == [1.0; 4]));
+    }
+}
+
+#[test]
+fn oit_test() {
+    use crate::state::BlendState;
+
+    // 半透明的quad，左右两边的深度分别为z0和z1
+    let quad = |z0: Tyf, z1: Tyf, color: Vec4| -> Box<dyn IPrimitive> {
+        let a = Vec4::from(-1.0, -0.5, z0, 1.0);
+        let b = Vec4::from(1.0, -0.5, z1, 1.0);
+        let c = Vec4::from(1.0, 0.5, z1, 1.0);
+        let d = Vec4::from(-1.0, 0.5, z0, 1.0);
+        Box::new(Triangles::new(vec![[a, b, c], [a, c, d]]).color(color))
+    };
+
+    // 红色quad从左到右穿过蓝色quad：左半边红色在前，右半边蓝色在前
+    let red = quad(-0.5, 0.5, Vec4::from(1.0, 0.0, 0.0, 0.5));
+    let blue = quad(0.0, 0.0, Vec4::from(0.0, 0.0, 1.0, 0.5));
+    let (wid, hei) = (32, 16);
+    let render = |oit: EOit, layers: usize, msaa: EMsaa, tiled: bool, blue_first: bool| {
+        let mut r = Rasterizer::new((wid, hei));
+        r.set_msaa(msaa);
+        *r.wire_frame() = false;
+        *r.cull_face() = false;
+        *r.tiled() = tiled;
+        r.clear_color(&Vec4::from(0.0, 0.0, 0.0, 1.0));
+        r.clear_depth();
+        // 与排序后混合相同，透明片段不写入深度
+        r.set_blend(BlendState::alpha());
+        r.set_depth_state(DepthState {
+            write: false,
+            ..r.get_depth_state()
+        });
+        r.set_oit(oit);
+        r.set_peel_layers(layers);
+        let order = if blue_first { [&blue, &red] } else { [&red, &blue] };
+        for primitive in order {
+            r.draw(primitive.as_ref());
+        }
+        if oit != EOit::Off {
+            // 片段保存到链表，没有写入深度和颜色buffer
+            assert!(r.get_oit_fragments() >= (wid * hei / 2) as usize * 2);
+            assert!(r.get_depth().iter().all(|z| *z == 1.0));
+            assert!(r.get_color().iter().all(|c| *c == [0, 0, 0, 255]));
+        }
+        r.resolve();
+        assert_eq!(r.get_oit_fragments(), 0);
+        let rgb = |i: u32| {
+            let c = r.get_color()[(i + hei / 2 * wid) as usize];
+            [c[0], c[1], c[2]]
+        };
+        (rgb(wid / 4), rgb(wid * 3 / 4))
+    };
+    let front_red = [128, 0, 64];
+    let front_blue = [64, 0, 128];
+
+    // 直接混合时结果依赖于绘制顺序
+    assert_eq!(render(EOit::Off, 1, EMsaa::Off, false, false), (front_blue, front_blue));
+    assert_eq!(render(EOit::Off, 1, EMsaa::Off, false, true), (front_red, front_red));
+
+    // A-buffer和足够层数的深度剥离对每个像素的片段排序，结果与绘制顺序无关
+    for (oit, layers) in [(EOit::ABuffer, 1), (EOit::DepthPeeling, 2)] {
+        for msaa in [EMsaa::Off, EMsaa::X4] {
+            for tiled in [false, true] {
+                for blue_first in [false, true] {
+                    assert_eq!(render(oit, layers, msaa, tiled, blue_first), (front_red, front_blue));
+                }
+            }
+        }
+    }
+
+    // 只剥离一层时，只保留最近的片段
+    assert_eq!(
+        render(EOit::DepthPeeling, 1, EMsaa::Off, false, false),
+        ([128, 0, 0], [0, 0, 128])
+    );
+}
+
+#[test]
+fn fill_rule_test() {
+    // 正方形网格，内部顶点都在像素中心上，三角形的共边会穿过大量像素中心
+    let wid = 40;
+    let hei = 40;
+    let xs = [2.3, 9.5, 16.5, 23.5, 37.7];
+    let mut tris = Vec::new();
+    for u in 0..4 {
+        for v in 0..4 {
+            let p = |du: usize, dv: usize| Vec4::from(xs[u + du], xs[v + dv], 0.0, 1.0);
+            // 交替使用两个方向的对角线，同时包含顺时针和逆时针的三角形
+            if (u + v) % 2 == 0 {
+                tris.push((p(0, 0), p(1, 0), p(1, 1)));
+                tris.push((p(0, 0), p(0, 1), p(1, 1)));
+            } else {
+                tris.push((p(1, 0), p(1, 1), p(0, 1)));
+                tris.push((p(1, 0), p(0, 0), p(0, 1)));
+            }
+        }
+    }
+
+    let mut r = Rasterizer::new((wid, hei));
+    let mut count = vec![0; (wid * hei) as usize];
+    for tri in tris {
+        r.clear_depth();
+        r.gv.gl_FragCoord = tri;
+        for Pixel { i, j, .. } in r.rasterization() {
+            count[(i + j * wid) as usize] += 1;
+        }
+    }
+    for i in 0..wid {
+        for j in 0..hei {
+            // 正方形内部的像素中心被覆盖1次，外部的像素中心不被覆盖
+            let inside = |v: u32| 2.3 < v as Tyf + 0.5 && (v as Tyf + 0.5) < 37.7;
+            let expect = if inside(i) && inside(j) { 1 } else { 0 };
+            assert_eq!(count[(i + j * wid) as usize], expect, "pixel ({}, {})", i, j);
         }
     }
-    img.save("../../test_rasterizer_color.tga").unwrap();
 }
@@ -1,12 +1,24 @@
 //! Shader Language
 
+use crate::hiz::HiZ;
+use crate::oit::{EOit, FragLists};
+use crate::pipeline::PipelineStats;
+use crate::rasterizer::{ELineMode, EMsaa, ETonemap};
+use crate::state::{BlendState, DepthState, ECullMode, EFrontFace, StencilState};
+use crate::varying::Varyings;
 use magx::*;
 use std::any::Any;
 
 pub trait IGlsl {
     fn frag_coord(&self) -> &(Vec4, Vec4, Vec4);
+    fn front_facing(&self) -> bool;
+    fn instance(&mut self) -> &mut Instance;
     fn wire_frame(&mut self) -> &mut bool;
     fn cull_face(&mut self) -> &mut bool;
+    fn tiled(&mut self) -> &mut bool;
+    fn srgb(&mut self) -> &mut bool;
+    fn hiz(&mut self) -> &mut bool;
+    fn wire_overlay(&mut self) -> &mut bool;
 }
 
 /// 着色器内建变量
@@ -14,52 +26,404 @@ pub trait IGlsl {
 /// 类似于GLSL中的内建变量，但这里以片段（三角面）为基础。
 #[allow(non_snake_case)]
 pub struct GlslVars {
-    // 当前绘制的片段索引
-    //pub gl_InstanceID: usize,
+    /// 当前绘制的实例
+    pub gl_Instance: Instance,
     /// 顶点着色器输出片段顶点（在投影坐标中）的位置向量
     pub gl_Postion: (Vec4, Vec4, Vec4),
+    /// 顶点着色器输出片段顶点的varying变量
+    pub varyings: (Varyings, Varyings, Varyings),
     /// 当前片段面的朝向(正面或背面朝向摄像头)
     pub gl_FrontFacing: bool,
     /// 片段着色器中片段的屏幕坐标
     pub gl_FragCoord: (Vec4, Vec4, Vec4),
+    /// 裁剪后三角形的顶点在原片段中的重心坐标
+    pub clip_bc: (Vec3, Vec3, Vec3),
 
     /// 颜色buffer
     pub cbuf: Vec<[u8; 4]>,
     /// 深度buffer
     pub zbuf: Vec<f32>,
+    /// 模板buffer
+    pub sbuf: Vec<u8>,
+    /// 浮点（HDR）颜色buffer，开启HDR时片段颜色不截断，resolve时经色调映射输出到cbuf
+    pub hdr_cbuf: Vec<[f32; 4]>,
+    /// MSAA模式
+    pub msaa: EMsaa,
+    /// 多重采样颜色buffer（开启MSAA时，每个像素有msaa.count()个采样点）
+    pub ms_cbuf: Vec<[f32; 4]>,
+    /// 多重采样深度buffer
+    pub ms_zbuf: Vec<f32>,
+    /// 多重采样模板buffer
+    pub ms_sbuf: Vec<u8>,
+    /// 点的大小（像素）
+    pub point_size: f32,
+    /// 线段光栅化算法
+    pub line_mode: ELineMode,
+    /// 线段的宽度（像素）
+    pub line_width: f32,
+    /// 多边形偏移(factor, units)，叠加网格时实体模型的深度偏移（类似`glPolygonOffset`）
+    pub polygon_offset: (f32, f32),
+    /// 深度金字塔（开启MSAA时对应多重采样深度buffer）
+    pub hiz: HiZ,
+    /// 附加渲染目标，依次对应片段着色器location为1, 2, ...的输出（不进行多重采样）
+    pub targets: Vec<Vec<[f32; 4]>>,
+    /// overdraw buffer（每个像素调用片段着色器的次数）
+    pub overdraw: Vec<u32>,
+    /// 管线统计
+    pub stats: PipelineStats,
+    /// 色调映射算子
+    pub tonemap: ETonemap,
+    /// 曝光（色调映射前颜色乘以的系数）
+    pub exposure: f32,
+    /// 混合状态
+    pub blend: BlendState,
+    /// 透明片段的处理方式
+    pub oit: EOit,
+    /// 深度剥离的最大层数
+    pub peel_layers: usize,
+    /// 开启OIT时保存的逐像素片段链表
+    pub frags: FragLists,
+    /// 深度状态
+    pub depth: DepthState,
+    /// 模板状态
+    pub stencil: StencilState,
+    /// 正面的顶点顺序
+    pub front_face: EFrontFace,
+    /// 开启面剃除时剃除的面
+    pub cull_mode: ECullMode,
     pub en_wire_frame: bool,
-    pub en_cull_back_face: bool,
+    /// 开启面剃除（类似`glEnable(GL_CULL_FACE)`）
+    pub en_cull_face: bool,
+    /// 分块多线程光栅化
+    pub en_tiled: bool,
+    /// 色调映射后进行sRGB编码
+    pub en_srgb: bool,
+    /// 反向Z（深度buffer清除为0，离视点越近深度值越大）
+    pub en_reversed_z: bool,
+    /// 使用深度金字塔提前剃除被遮挡的三角形和单元
+    pub en_hiz: bool,
+    /// 在实体模型上叠加网格
+    pub en_wire_overlay: bool,
 }
 
 impl GlslVars {
     pub fn new(sz: (u32, u32)) -> Self {
         let max = (sz.0 * sz.1) as usize;
         Self {
+            gl_Instance: Instance::new(),
             gl_Postion: (Vec4::new(), Vec4::new(), Vec4::new()),
+            varyings: (Varyings::new(), Varyings::new(), Varyings::new()),
             gl_FrontFacing: true,
             gl_FragCoord: (Vec4::new(), Vec4::new(), Vec4::new()),
+            clip_bc: (
+                Vec3::from(1.0, 0.0, 0.0),
+                Vec3::from(0.0, 1.0, 0.0),
+                Vec3::from(0.0, 0.0, 1.0),
+            ),
             cbuf: vec![[0; 4]; max],
             zbuf: vec![1.0; max],
+            sbuf: vec![0; max],
+            hdr_cbuf: Vec::new(),
+            msaa: EMsaa::Off,
+            ms_cbuf: Vec::new(),
+            ms_zbuf: Vec::new(),
+            ms_sbuf: Vec::new(),
+            point_size: 1.0,
+            line_mode: ELineMode::Dda,
+            line_width: 1.0,
+            polygon_offset: (1.0, 1.0),
+            hiz: HiZ::new(),
+            targets: Vec::new(),
+            overdraw: vec![0; max],
+            stats: PipelineStats::default(),
+            tonemap: ETonemap::Clamp,
+            exposure: 1.0,
+            blend: BlendState::new(),
+            oit: EOit::Off,
+            peel_layers: 4,
+            frags: FragLists::new(),
+            depth: DepthState::new(),
+            stencil: StencilState::new(),
+            front_face: EFrontFace::Ccw,
+            cull_mode: ECullMode::Back,
             en_wire_frame: true,
-            en_cull_back_face: true,
+            en_cull_face: true,
+            en_tiled: true,
+            en_srgb: false,
+            en_reversed_z: false,
+            en_hiz: true,
+            en_wire_overlay: false,
         }
     }
+
+    /// 复制rect区域(x, y, w, h)内的buffer和渲染状态，用于分块渲染
+    ///
+    /// - w: 当前buffer的宽度
+    pub fn tile(&self, w: u32, rect: (u32, u32, u32, u32)) -> Self {
+        let n = self.msaa.count();
+        Self {
+            gl_Instance: self.gl_Instance,
+            gl_Postion: self.gl_Postion,
+            varyings: self.varyings,
+            gl_FrontFacing: self.gl_FrontFacing,
+            gl_FragCoord: self.gl_FragCoord,
+            clip_bc: self.clip_bc,
+            cbuf: copy_rect(&self.cbuf, w, rect, 1),
+            zbuf: copy_rect(&self.zbuf, w, rect, 1),
+            sbuf: copy_rect(&self.sbuf, w, rect, 1),
+            hdr_cbuf: copy_rect(&self.hdr_cbuf, w, rect, 1),
+            msaa: self.msaa,
+            ms_cbuf: copy_rect(&self.ms_cbuf, w, rect, n),
+            ms_zbuf: copy_rect(&self.ms_zbuf, w, rect, n),
+            ms_sbuf: copy_rect(&self.ms_sbuf, w, rect, n),
+            point_size: self.point_size,
+            line_mode: self.line_mode,
+            line_width: self.line_width,
+            polygon_offset: self.polygon_offset,
+            hiz: HiZ::new(),
+            targets: self.targets.iter().map(|t| copy_rect(t, w, rect, 1)).collect(),
+            overdraw: copy_rect(&self.overdraw, w, rect, 1),
+            stats: PipelineStats::default(),
+            tonemap: self.tonemap,
+            exposure: self.exposure,
+            blend: self.blend,
+            oit: self.oit,
+            peel_layers: self.peel_layers,
+            frags: FragLists::new(),
+            depth: self.depth,
+            stencil: self.stencil,
+            front_face: self.front_face,
+            cull_mode: self.cull_mode,
+            en_wire_frame: self.en_wire_frame,
+            en_cull_face: self.en_cull_face,
+            en_tiled: false,
+            en_srgb: self.en_srgb,
+            en_reversed_z: self.en_reversed_z,
+            en_hiz: self.en_hiz,
+            en_wire_overlay: self.en_wire_overlay,
+        }
+    }
+
+    /// 将分块渲染的buffer写回rect区域
+    ///
+    /// - w: 当前buffer的宽度
+    pub fn blit(&mut self, w: u32, rect: (u32, u32, u32, u32), tile: &Self) {
+        let n = self.msaa.count();
+        paste_rect(&mut self.cbuf, w, rect, 1, &tile.cbuf);
+        paste_rect(&mut self.zbuf, w, rect, 1, &tile.zbuf);
+        paste_rect(&mut self.sbuf, w, rect, 1, &tile.sbuf);
+        paste_rect(&mut self.hdr_cbuf, w, rect, 1, &tile.hdr_cbuf);
+        paste_rect(&mut self.ms_cbuf, w, rect, n, &tile.ms_cbuf);
+        paste_rect(&mut self.ms_zbuf, w, rect, n, &tile.ms_zbuf);
+        paste_rect(&mut self.ms_sbuf, w, rect, n, &tile.ms_sbuf);
+        self.hiz.mark_rect(rect);
+        for (t, src) in self.targets.iter_mut().zip(&tile.targets) {
+            paste_rect(t, w, rect, 1, src);
+        }
+        paste_rect(&mut self.overdraw, w, rect, 1, &tile.overdraw);
+        self.frags.merge(w, self.cbuf.len(), rect, &tile.frags);
+        self.stats += tile.stats;
+    }
+}
+
+/// 复制buffer中rect区域的数据
+///
+/// - w: buffer的宽度
+/// - n: 每个像素的数据个数
+fn copy_rect<T: Copy>(buf: &[T], w: u32, rect: (u32, u32, u32, u32), n: usize) -> Vec<T> {
+    if buf.is_empty() {
+        return Vec::new();
+    }
+    let (x, y, rw, rh) = rect;
+    let mut out = Vec::with_capacity((rw * rh) as usize * n);
+    for j in y..y + rh {
+        let start = (x + j * w) as usize * n;
+        out.extend_from_slice(&buf[start..start + rw as usize * n]);
+    }
+    out
+}
+
+/// 将数据写回buffer中的rect区域
+fn paste_rect<T: Copy>(buf: &mut [T], w: u32, rect: (u32, u32, u32, u32), n: usize, src: &[T]) {
+    let (x, y, rw, _) = rect;
+    let row = rw as usize * n;
+    for (k, line) in src.chunks(row).enumerate() {
+        let start = (x + (y + k as u32) * w) as usize * n;
+        buf[start..start + row].copy_from_slice(line);
+    }
+}
+
+/// 片段着色器的最大输出数量
+pub const MAX_OUTPUTS: usize = 8;
+
+/// 片段着色器的输出
+///
+/// 类似GLSL中的`layout(location = n) out vec4`：location为0的输出写入颜色buffer，
+/// 其它location的输出写入对应的附加渲染目标（见`Rasterizer::set_targets`）。
+#[derive(Debug, Copy, Clone)]
+pub struct FragOutputs {
+    len: usize,
+    data: [Vec4; MAX_OUTPUTS],
+    discard: bool,
+}
+
+impl Default for FragOutputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Vec4> for FragOutputs {
+    fn from(color: Vec4) -> Self {
+        let mut out = Self::new();
+        out.set(0, color);
+        out
+    }
+}
+
+impl FragOutputs {
+    pub fn new() -> Self {
+        Self {
+            len: 0,
+            data: [Vec4::new(); MAX_OUTPUTS],
+            discard: false,
+        }
+    }
+
+    /// 丢弃片段（类似GLSL中的`discard`），不修改颜色、深度和模板buffer
+    pub fn discard() -> Self {
+        Self {
+            discard: true,
+            ..Self::new()
+        }
+    }
+
+    /// 片段是否被丢弃
+    #[inline]
+    pub fn discarded(&self) -> bool {
+        self.discard
+    }
+
+    /// 输出的数量（最大的location + 1）
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 设置location处的输出
+    #[inline]
+    pub fn set(&mut self, location: usize, v: Vec4) {
+        assert!(location < MAX_OUTPUTS, "Too many fragment outputs");
+        self.data[location] = v;
+        self.len = self.len.max(location + 1);
+    }
+
+    /// 读取location处的输出，未设置的输出为0
+    #[inline]
+    pub fn get(&self, location: usize) -> Vec4 {
+        self.data[location]
+    }
 }
 
 /// 着色器接口
-pub trait IShader {
+///
+/// 分块渲染时，多个线程会同时调用同一个着色器，所以需要满足`Send + Sync`。
+pub trait IShader: Send + Sync {
     /// 设置uniform变量
     fn set_uniforms(&mut self, _u: Box<dyn Any>) {}
 
-    /// 顶点着色器
+    /// 顶点着色器（Triangles拓扑）
+    ///
+    /// 返回片段三个顶点的投影坐标和varying变量；
+    /// 缺省依次对第3 * pidx, 3 * pidx + 1, 3 * pidx + 2个顶点调用`vertex_at`。
     ///
     /// - pidx: 图元index
-    fn vertex(&self, pidx: usize) -> (Vec4, Vec4, Vec4);
+    /// - inst: 当前绘制的实例（非实例化绘制时为`Instance::new()`）
+    fn vertex(&self, pidx: usize, inst: &Instance) -> ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)) {
+        let (a, va) = self.vertex_at(3 * pidx, inst);
+        let (b, vb) = self.vertex_at(3 * pidx + 1, inst);
+        let (c, vc) = self.vertex_at(3 * pidx + 2, inst);
+        ((a, b, c), (va, vb, vc))
+    }
+
+    /// 单个顶点的顶点着色器（Triangles以外的拓扑）
+    ///
+    /// 返回顶点的投影坐标和varying变量；
+    /// 实现了`vertex`的着色器可以用`triangle_vertex`将第vidx / 3个片段的三个顶点依次展开（例如将三角形网格绘制成点云）。
+    ///
+    /// - vidx: 顶点index
+    /// - inst: 当前绘制的实例
+    fn vertex_at(&self, vidx: usize, inst: &Instance) -> (Vec4, Varyings);
 
     /// 片段着色器
     ///
+    /// 返回每个location的输出颜色，只有一个输出时可以直接用`Vec4::into()`；
+    /// 返回`FragOutputs::discard()`则丢弃片段（例如alpha测试）。
+    ///
     /// - pidx: 图元index
-    fn fragment(&self, pidx: usize, bc: &Vec3) -> Vec4;
+    /// - inst: 当前绘制的实例
+    /// - v: 插值后的varying变量
+    fn fragment(&self, pidx: usize, inst: &Instance, v: &Varyings) -> FragOutputs;
+}
+
+/// 取出片段顶点着色结果中的第k个顶点
+///
+/// 用于只按片段着色的着色器实现`IShader::vertex_at`：
+/// `triangle_vertex(self.vertex(vidx / 3, inst), vidx % 3)`
+pub fn triangle_vertex(tri: ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)), k: usize) -> (Vec4, Varyings) {
+    let (pos, var) = tri;
+    match k {
+        0 => (pos.0, var.0),
+        1 => (pos.1, var.1),
+        _ => (pos.2, var.2),
+    }
+}
+
+/// 实例化绘制的实例数据
+///
+/// 类似GLSL中的`gl_InstanceID`和per-instance的顶点属性；如何使用实例数据由着色器决定。
+#[derive(Debug, Copy, Clone)]
+pub struct Instance {
+    /// 实例index，由`IPipeline::draw_instanced`设置
+    pub id: usize,
+    /// 实例的模型变换矩阵（在模型自身的模型变换之后应用）
+    pub model: Mat4,
+    /// model的inverse-transpose矩阵，用于校正法向量
+    pub mit: Mat3,
+    /// 实例颜色（与片段颜色相乘）
+    pub tint: Vec4,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Instance {
+    /// 单位变换、白色的实例（非实例化绘制时使用）
+    pub fn new() -> Self {
+        Self {
+            id: 0,
+            model: Mat4::eye(1.0),
+            mit: Mat3::eye(1.0),
+            tint: Vec4::fill(1.0),
+        }
+    }
+
+    pub fn from(model: Mat4, tint: Vec4) -> Self {
+        Self {
+            id: 0,
+            model,
+            mit: model.inverse().transpose().to_mat3(),
+            tint,
+        }
+    }
 }
 
 /// 着色器基本变换矩阵变量
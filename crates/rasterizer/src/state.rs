@@ -0,0 +1,479 @@
+//! 渲染状态
+//!
+//! 类似OpenGL中通过`glEnable`、`glBlendFunc`等设置的固定管线状态，在片段着色之后作用于buffer的写入；
+//! 面剃除状态在光栅化之前作用于三角形。
+
+use magx::*;
+
+/// 混合因子（类似`glBlendFunc`的参数）
+///
+/// src为片段着色器输出的颜色，dst为颜色buffer中已有的颜色，constant为BlendState::color。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EBlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+    ConstantColor,
+    OneMinusConstantColor,
+    ConstantAlpha,
+    OneMinusConstantAlpha,
+}
+
+impl EBlendFactor {
+    /// 计算颜色分量k的混合因子
+    #[inline]
+    fn factor(&self, k: usize, src: &[f32; 4], dst: &[f32; 4], constant: &[f32; 4]) -> f32 {
+        match self {
+            EBlendFactor::Zero => 0.0,
+            EBlendFactor::One => 1.0,
+            EBlendFactor::SrcColor => src[k],
+            EBlendFactor::OneMinusSrcColor => 1.0 - src[k],
+            EBlendFactor::DstColor => dst[k],
+            EBlendFactor::OneMinusDstColor => 1.0 - dst[k],
+            EBlendFactor::SrcAlpha => src[3],
+            EBlendFactor::OneMinusSrcAlpha => 1.0 - src[3],
+            EBlendFactor::DstAlpha => dst[3],
+            EBlendFactor::OneMinusDstAlpha => 1.0 - dst[3],
+            EBlendFactor::ConstantColor => constant[k],
+            EBlendFactor::OneMinusConstantColor => 1.0 - constant[k],
+            EBlendFactor::ConstantAlpha => constant[3],
+            EBlendFactor::OneMinusConstantAlpha => 1.0 - constant[3],
+        }
+    }
+}
+
+/// 混合方程（类似`glBlendEquation`的参数）
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EBlendEquation {
+    /// src * sf + dst * df
+    Add,
+    /// src * sf - dst * df
+    Subtract,
+    /// dst * df - src * sf
+    ReverseSubtract,
+    /// min(src, dst)，忽略混合因子
+    Min,
+    /// max(src, dst)，忽略混合因子
+    Max,
+}
+
+impl EBlendEquation {
+    #[inline]
+    fn apply(&self, s: f32, d: f32, sf: f32, df: f32) -> f32 {
+        match self {
+            EBlendEquation::Add => s * sf + d * df,
+            EBlendEquation::Subtract => s * sf - d * df,
+            EBlendEquation::ReverseSubtract => d * df - s * sf,
+            EBlendEquation::Min => s.min(d),
+            EBlendEquation::Max => s.max(d),
+        }
+    }
+}
+
+/// 混合状态
+///
+/// rgb和alpha分别使用各自的混合因子和混合方程（类似`glBlendFuncSeparate`和`glBlendEquationSeparate`）；
+/// 写入掩码（类似`glColorMask`）不论是否开启混合都会生效。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BlendState {
+    /// 开启混合
+    pub enable: bool,
+    pub src_rgb: EBlendFactor,
+    pub dst_rgb: EBlendFactor,
+    pub src_alpha: EBlendFactor,
+    pub dst_alpha: EBlendFactor,
+    pub eq_rgb: EBlendEquation,
+    pub eq_alpha: EBlendEquation,
+    /// 混合常量颜色（类似`glBlendColor`）
+    pub color: Vec4,
+    /// rgba各通道的写入掩码
+    pub mask: [bool; 4],
+}
+
+impl Default for BlendState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlendState {
+    /// 关闭混合，片段颜色直接覆盖颜色buffer
+    pub fn new() -> Self {
+        Self {
+            enable: false,
+            src_rgb: EBlendFactor::One,
+            dst_rgb: EBlendFactor::Zero,
+            src_alpha: EBlendFactor::One,
+            dst_alpha: EBlendFactor::Zero,
+            eq_rgb: EBlendEquation::Add,
+            eq_alpha: EBlendEquation::Add,
+            color: Vec4::new(),
+            mask: [true; 4],
+        }
+    }
+
+    /// 使用相同的rgb和alpha混合因子开启混合
+    pub fn func(src: EBlendFactor, dst: EBlendFactor) -> Self {
+        Self {
+            enable: true,
+            src_rgb: src,
+            dst_rgb: dst,
+            src_alpha: src,
+            dst_alpha: dst,
+            ..Self::new()
+        }
+    }
+
+    /// 常用的透明度混合：src * src.a + dst * (1 - src.a)
+    pub fn alpha() -> Self {
+        Self::func(EBlendFactor::SrcAlpha, EBlendFactor::OneMinusSrcAlpha)
+    }
+
+    /// 混合片段颜色和颜色buffer中的颜色，返回需要写入颜色buffer的颜色
+    ///
+    /// - src: 片段颜色
+    /// - dst: 颜色buffer中的颜色
+    pub fn blend(&self, src: &[f32; 4], dst: &[f32; 4]) -> [f32; 4] {
+        let constant = [self.color.x, self.color.y, self.color.z, self.color.w];
+        let mut out = *dst;
+        for k in 0..4 {
+            if !self.mask[k] {
+                continue;
+            }
+            out[k] = if self.enable {
+                let (sf, df, eq) = if k < 3 {
+                    (self.src_rgb, self.dst_rgb, self.eq_rgb)
+                } else {
+                    (self.src_alpha, self.dst_alpha, self.eq_alpha)
+                };
+                eq.apply(
+                    src[k],
+                    dst[k],
+                    sf.factor(k, src, dst, &constant),
+                    df.factor(k, src, dst, &constant),
+                )
+            } else {
+                src[k]
+            };
+        }
+        out
+    }
+}
+
+/// 比较函数（类似`glDepthFunc`和`glStencilFunc`的参数）
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ECompareFunc {
+    Never,
+    Less,
+    LEqual,
+    Equal,
+    NotEqual,
+    Greater,
+    GEqual,
+    Always,
+}
+
+impl ECompareFunc {
+    /// 比较片段的值v（深度值或模板参考值）和buffer中的值d，返回true表示通过测试
+    #[inline]
+    pub fn compare<T: PartialOrd>(&self, v: T, d: T) -> bool {
+        match self {
+            ECompareFunc::Never => false,
+            ECompareFunc::Less => v < d,
+            ECompareFunc::LEqual => v <= d,
+            ECompareFunc::Equal => v == d,
+            ECompareFunc::NotEqual => v != d,
+            ECompareFunc::Greater => v > d,
+            ECompareFunc::GEqual => v >= d,
+            ECompareFunc::Always => true,
+        }
+    }
+
+    /// 深度方向反转后的比较函数（用于反向Z）
+    pub fn reversed(&self) -> Self {
+        match self {
+            ECompareFunc::Less => ECompareFunc::Greater,
+            ECompareFunc::LEqual => ECompareFunc::GEqual,
+            ECompareFunc::Greater => ECompareFunc::Less,
+            ECompareFunc::GEqual => ECompareFunc::LEqual,
+            f => *f,
+        }
+    }
+}
+
+/// 深度状态
+///
+/// 与OpenGL相同，关闭深度测试时，片段总是通过测试，且不会写入深度buffer。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DepthState {
+    /// 开启深度测试（类似`glEnable(GL_DEPTH_TEST)`）
+    pub test: bool,
+    /// 通过深度测试后写入深度buffer（类似`glDepthMask`）
+    pub write: bool,
+    /// 深度比较函数
+    pub func: ECompareFunc,
+}
+
+impl Default for DepthState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DepthState {
+    /// 开启深度测试和写入，保留离视点更近（深度值更小）的片段
+    pub fn new() -> Self {
+        Self {
+            test: true,
+            write: true,
+            func: ECompareFunc::Less,
+        }
+    }
+}
+
+/// 模板操作（类似`glStencilOp`的参数）
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EStencilOp {
+    /// 保持原值
+    Keep,
+    /// 置为0
+    Zero,
+    /// 置为参考值
+    Replace,
+    /// 加1，最大为255
+    Incr,
+    /// 加1，255加1后为0
+    IncrWrap,
+    /// 减1，最小为0
+    Decr,
+    /// 减1，0减1后为255
+    DecrWrap,
+    /// 按位取反
+    Invert,
+}
+
+impl EStencilOp {
+    #[inline]
+    fn apply(&self, s: u8, reference: u8) -> u8 {
+        match self {
+            EStencilOp::Keep => s,
+            EStencilOp::Zero => 0,
+            EStencilOp::Replace => reference,
+            EStencilOp::Incr => s.saturating_add(1),
+            EStencilOp::IncrWrap => s.wrapping_add(1),
+            EStencilOp::Decr => s.saturating_sub(1),
+            EStencilOp::DecrWrap => s.wrapping_sub(1),
+            EStencilOp::Invert => !s,
+        }
+    }
+}
+
+/// 单个朝向（正面或背面）片段的模板状态
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StencilFace {
+    /// 比较函数，比较`reference & read_mask`和`stencil & read_mask`
+    pub func: ECompareFunc,
+    /// 参考值
+    pub reference: u8,
+    /// 比较时的掩码
+    pub read_mask: u8,
+    /// 写入模板buffer时的掩码（类似`glStencilMask`）
+    pub write_mask: u8,
+    /// 模板测试失败时的操作
+    pub fail: EStencilOp,
+    /// 模板测试通过，深度测试失败时的操作
+    pub zfail: EStencilOp,
+    /// 模板测试和深度测试都通过时的操作
+    pub pass: EStencilOp,
+}
+
+impl Default for StencilFace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StencilFace {
+    /// 总是通过模板测试，且不修改模板buffer
+    pub fn new() -> Self {
+        Self {
+            func: ECompareFunc::Always,
+            reference: 0,
+            read_mask: 0xff,
+            write_mask: 0xff,
+            fail: EStencilOp::Keep,
+            zfail: EStencilOp::Keep,
+            pass: EStencilOp::Keep,
+        }
+    }
+
+    /// 模板测试
+    ///
+    /// - s: 模板buffer中的值
+    #[inline]
+    pub fn test(&self, s: u8) -> bool {
+        self.func.compare(self.reference & self.read_mask, s & self.read_mask)
+    }
+
+    /// 按操作更新模板值，只修改write_mask中的位
+    ///
+    /// - s: 模板buffer中的值
+    #[inline]
+    pub fn update(&self, op: EStencilOp, s: u8) -> u8 {
+        (op.apply(s, self.reference) & self.write_mask) | (s & !self.write_mask)
+    }
+}
+
+/// 模板状态
+///
+/// 正面和背面片段分别使用各自的模板状态（类似`glStencilFuncSeparate`和`glStencilOpSeparate`），
+/// 便于用一个pass实现shadow volume。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StencilState {
+    /// 开启模板测试（类似`glEnable(GL_STENCIL_TEST)`）
+    pub test: bool,
+    pub front: StencilFace,
+    pub back: StencilFace,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StencilState {
+    /// 关闭模板测试
+    pub fn new() -> Self {
+        Self {
+            test: false,
+            front: StencilFace::new(),
+            back: StencilFace::new(),
+        }
+    }
+
+    /// 正面和背面使用相同的模板状态，开启模板测试
+    pub fn both(face: StencilFace) -> Self {
+        Self {
+            test: true,
+            front: face,
+            back: face,
+        }
+    }
+
+    /// 片段朝向对应的模板状态
+    #[inline]
+    pub fn face(&self, front_facing: bool) -> &StencilFace {
+        if front_facing {
+            &self.front
+        } else {
+            &self.back
+        }
+    }
+}
+
+/// 正面的顶点顺序（类似`glFrontFace`的参数）
+///
+/// 在屏幕空间（y轴向上）中判断三角形顶点的顺序。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EFrontFace {
+    /// 逆时针为正面
+    Ccw,
+    /// 顺时针为正面
+    Cw,
+}
+
+impl EFrontFace {
+    /// 屏幕空间中有向面积为area的三角形是否是正面（面积为0的三角形为背面）
+    #[inline]
+    pub fn front_facing(&self, area: f32) -> bool {
+        match self {
+            EFrontFace::Ccw => area > 0.0,
+            EFrontFace::Cw => area < 0.0,
+        }
+    }
+}
+
+/// 剃除的面（类似`glCullFace`的参数）
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ECullMode {
+    /// 不剃除
+    None,
+    /// 剃除背面
+    Back,
+    /// 剃除正面
+    Front,
+    /// 剃除正面和背面，只绘制点和线段
+    FrontAndBack,
+}
+
+impl ECullMode {
+    /// 朝向为front_facing的三角形是否被剃除
+    #[inline]
+    pub fn culled(&self, front_facing: bool) -> bool {
+        match self {
+            ECullMode::None => false,
+            ECullMode::Back => !front_facing,
+            ECullMode::Front => front_facing,
+            ECullMode::FrontAndBack => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::approx_eq;
+
+    #[test]
+    fn blending() {
+        let src = [1.0, 0.5, 0.0, 0.25];
+        let dst = [0.0, 0.5, 1.0, 1.0];
+        assert_eq!(BlendState::new().blend(&src, &dst), src);
+
+        let out = BlendState::alpha().blend(&src, &dst);
+        let expect = [0.25, 0.5, 0.75, 0.8125];
+        for k in 0..4 {
+            assert!(approx_eq!(f32, out[k], expect[k], epsilon = 0.000001));
+        }
+
+        let mut bs = BlendState::func(EBlendFactor::One, EBlendFactor::One);
+        bs.eq_rgb = EBlendEquation::ReverseSubtract;
+        bs.eq_alpha = EBlendEquation::Max;
+        bs.mask = [true, true, false, true];
+        assert_eq!(bs.blend(&src, &dst), [-1.0, 0.0, 1.0, 1.0]);
+
+        let mut bs = BlendState::func(EBlendFactor::ConstantAlpha, EBlendFactor::OneMinusConstantAlpha);
+        bs.color = Vec4::fill(0.5);
+        bs.eq_alpha = EBlendEquation::Min;
+        assert_eq!(bs.blend(&src, &dst), [0.5, 0.5, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn stencil() {
+        let mut sf = StencilFace::new();
+        sf.func = ECompareFunc::Equal;
+        sf.reference = 0b1010_0001;
+        sf.read_mask = 0b0000_1111;
+        assert!(sf.test(0b0101_0001));
+        assert!(!sf.test(0b0101_0011));
+
+        sf.write_mask = 0b0000_1111;
+        assert_eq!(sf.update(EStencilOp::Replace, 0b1111_0000), 0b1111_0001);
+        assert_eq!(sf.update(EStencilOp::Invert, 0b1111_0000), 0b1111_1111);
+        sf.write_mask = 0xff;
+        assert_eq!(sf.update(EStencilOp::Incr, 255), 255);
+        assert_eq!(sf.update(EStencilOp::IncrWrap, 255), 0);
+        assert_eq!(sf.update(EStencilOp::Decr, 0), 0);
+        assert_eq!(sf.update(EStencilOp::DecrWrap, 0), 255);
+        assert_eq!(sf.update(EStencilOp::Zero, 7), 0);
+        assert_eq!(sf.update(EStencilOp::Keep, 7), 7);
+    }
+} /* tests */
@@ -0,0 +1,236 @@
+//! Varying变量
+//!
+//! 顶点着色器为每个顶点输出一组varying变量，管线在光栅化时对其插值，
+//! 片段着色器直接读取插值后的varying变量，不再需要自己根据重心坐标插值。
+//!
+//! 每个varying变量占用一个location（按添加的顺序），统一用Vec4保存；
+//! 同一个片段的三个顶点，需要按相同的顺序和插值方式添加varying变量。
+//!
+//! 光栅化时以2x2个像素（quad）为单位插值，通过相邻像素的差值得到varying变量的屏幕空间导数，
+//! 片段着色器可以通过`dfdx`和`dfdy`读取（类似GLSL中的`dFdx`和`dFdy`）。
+
+use magx::*;
+
+/// varying变量的最大数量
+pub const MAX_VARYINGS: usize = 8;
+
+/// 插值方式（类似GLSL中的插值限定符）
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EInterp {
+    /// 透视校正插值（smooth）
+    Smooth,
+    /// 不插值，使用片段第一个顶点（provoking vertex）的值（flat）
+    Flat,
+    /// 在屏幕空间中线性插值（noperspective）
+    NoPerspective,
+}
+
+/// 可以作为varying变量的类型
+pub trait IVarying: Copy {
+    fn to_slot(&self) -> Vec4;
+    fn from_slot(v: &Vec4) -> Self;
+}
+
+impl IVarying for Tyf {
+    #[inline]
+    fn to_slot(&self) -> Vec4 {
+        Vec4::from(*self, 0.0, 0.0, 0.0)
+    }
+
+    #[inline]
+    fn from_slot(v: &Vec4) -> Self {
+        v.x
+    }
+}
+
+impl IVarying for Vec2 {
+    #[inline]
+    fn to_slot(&self) -> Vec4 {
+        Vec4::from(self.x, self.y, 0.0, 0.0)
+    }
+
+    #[inline]
+    fn from_slot(v: &Vec4) -> Self {
+        v.to_vec2()
+    }
+}
+
+impl IVarying for Vec3 {
+    #[inline]
+    fn to_slot(&self) -> Vec4 {
+        self.to_vec4(0.0)
+    }
+
+    #[inline]
+    fn from_slot(v: &Vec4) -> Self {
+        v.to_vec3()
+    }
+}
+
+impl IVarying for Vec4 {
+    #[inline]
+    fn to_slot(&self) -> Vec4 {
+        *self
+    }
+
+    #[inline]
+    fn from_slot(v: &Vec4) -> Self {
+        *v
+    }
+}
+
+/// 一个顶点（或片段）的varying变量
+#[derive(Debug, Copy, Clone)]
+pub struct Varyings {
+    len: usize,
+    interp: [EInterp; MAX_VARYINGS],
+    data: [Vec4; MAX_VARYINGS],
+    /// 屏幕空间x方向的导数
+    dx: [Vec4; MAX_VARYINGS],
+    /// 屏幕空间y方向的导数
+    dy: [Vec4; MAX_VARYINGS],
+    /// 片段的朝向
+    front_facing: bool,
+}
+
+impl Default for Varyings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Varyings {
+    pub fn new() -> Self {
+        Self {
+            len: 0,
+            interp: [EInterp::Smooth; MAX_VARYINGS],
+            data: [Vec4::new(); MAX_VARYINGS],
+            dx: [Vec4::new(); MAX_VARYINGS],
+            dy: [Vec4::new(); MAX_VARYINGS],
+            front_facing: true,
+        }
+    }
+
+    /// varying变量的数量
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 添加varying变量，返回其location
+    pub fn push<T: IVarying>(&mut self, interp: EInterp, v: T) -> usize {
+        assert!(self.len < MAX_VARYINGS, "Too many varyings");
+        self.interp[self.len] = interp;
+        self.data[self.len] = v.to_slot();
+        self.len += 1;
+        self.len - 1
+    }
+
+    /// 读取location处的varying变量
+    #[inline]
+    pub fn get<T: IVarying>(&self, location: usize) -> T {
+        assert!(location < self.len);
+        T::from_slot(&self.data[location])
+    }
+
+    /// 读取location处的varying变量在屏幕空间x方向的导数（类似GLSL中的`dFdx`）
+    ///
+    /// 只有三角形的片段有导数，点和线段的片段导数为0。
+    #[inline]
+    pub fn dfdx<T: IVarying>(&self, location: usize) -> T {
+        assert!(location < self.len);
+        T::from_slot(&self.dx[location])
+    }
+
+    /// 读取location处的varying变量在屏幕空间y方向（向上）的导数（类似GLSL中的`dFdy`）
+    #[inline]
+    pub fn dfdy<T: IVarying>(&self, location: usize) -> T {
+        assert!(location < self.len);
+        T::from_slot(&self.dy[location])
+    }
+
+    /// 片段是否是正面（类似GLSL中的`gl_FrontFacing`）
+    ///
+    /// 点和线段的片段总是正面。
+    #[inline]
+    pub fn front_facing(&self) -> bool {
+        self.front_facing
+    }
+
+    /// 设置片段的朝向，插值时传递给片段
+    #[inline]
+    pub fn set_front_facing(&mut self, front_facing: bool) {
+        self.front_facing = front_facing;
+    }
+
+    /// 使用other的导数
+    #[inline]
+    pub fn set_derivatives(&mut self, other: &Varyings) {
+        self.dx = other.dx;
+        self.dy = other.dy;
+    }
+
+    /// 对片段三个顶点的varying变量插值
+    ///
+    /// - abc: 三个顶点的varying变量
+    /// - bc: 透视校正后的重心坐标
+    /// - bcl: 屏幕空间的重心坐标
+    pub fn interpolate(abc: &(Varyings, Varyings, Varyings), bc: &Vec3, bcl: &Vec3) -> Self {
+        let (a, b, c) = abc;
+        let mut out = *a;
+        for k in 0..a.len {
+            out.data[k] = match a.interp[k] {
+                EInterp::Smooth => interpolate(bc, &a.data[k], &b.data[k], &c.data[k]),
+                EInterp::Flat => a.data[k],
+                EInterp::NoPerspective => interpolate(bcl, &a.data[k], &b.data[k], &c.data[k]),
+            };
+        }
+        out
+    }
+
+    /// 对quad中4个像素中心的varying变量插值，并用相邻像素的差值计算导数
+    ///
+    /// - abc: 三个顶点的varying变量
+    /// - bcs: 4个像素中心（按左下、右下、左上、右上的顺序）透视校正后的和屏幕空间的重心坐标
+    pub fn interpolate_quad(abc: &(Varyings, Varyings, Varyings), bcs: &[(Vec3, Vec3); 4]) -> [Self; 4] {
+        let mut out = bcs.map(|(bc, bcl)| Self::interpolate(abc, &bc, &bcl));
+        for q in 0..4 {
+            let (row, col) = (q & 2, q & 1);
+            for k in 0..abc.0.len {
+                out[q].dx[k] = out[row + 1].data[k] - out[row].data[k];
+                out[q].dy[k] = out[col + 2].data[k] - out[col].data[k];
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::approx_eq;
+
+    #[test]
+    fn interpolation() {
+        let v = |k: Tyf| {
+            let mut v = Varyings::new();
+            v.push(EInterp::Smooth, k);
+            v.push(EInterp::Flat, Vec2::fill(k));
+            v.push(EInterp::NoPerspective, Vec3::fill(k));
+            v
+        };
+        let abc = (v(1.0), v(2.0), v(3.0));
+        let bc = Vec3::from(0.5, 0.5, 0.0);
+        let bcl = Vec3::from(0.0, 0.5, 0.5);
+        let o = Varyings::interpolate(&abc, &bc, &bcl);
+        assert_eq!(o.len(), 3);
+        assert!(approx_eq!(Tyf, o.get::<Tyf>(0), 1.5, epsilon = 0.000001));
+        assert!(approx_eq!(Vec2, o.get(1), Vec2::fill(1.0), epsilon = 0.000001));
+        assert!(approx_eq!(Vec3, o.get(2), Vec3::fill(2.5), epsilon = 0.000001));
+    }
+} /* tests */
@@ -12,11 +12,19 @@ pub struct Camera {
     pub up: Vec3,
     /// 摄像机屏幕大小(w, h)
     pub sz: (u32, u32),
+    /// 使用反向Z的投影矩阵
+    pub reversed_z: bool,
 }
 
 impl Camera {
     pub fn new(eye: Vec3, center: Vec3, up: Vec3, sz: (u32, u32)) -> Self {
-        Self { eye, center, up, sz }
+        Self {
+            eye,
+            center,
+            up,
+            sz,
+            reversed_z: false,
+        }
     }
 
     #[inline]
@@ -27,8 +35,24 @@ impl Camera {
 
     #[inline]
     pub fn proj(&self) -> Mat4 {
-        let aspect = (self.sz.0 as Tyf) / (self.sz.1 as Tyf);
-        persp(Angle::Ang(45.0), aspect, 0.1, 100.0)
+        let aspect = self.aspect();
+        if self.reversed_z {
+            persp_reversed(Angle::Ang(45.0), aspect, 0.1, 100.0)
+        } else {
+            persp(Angle::Ang(45.0), aspect, 0.1, 100.0)
+        }
+    }
+
+    /// 屏幕宽高比
+    #[inline]
+    pub fn aspect(&self) -> Tyf {
+        (self.sz.0 as Tyf) / (self.sz.1 as Tyf)
+    }
+
+    /// 改变屏幕大小，投影矩阵的宽高比随之改变
+    #[inline]
+    pub fn resize(&mut self, sz: (u32, u32)) {
+        self.sz = sz;
     }
 
     /// 以过center的x轴方向，对eye旋转
@@ -0,0 +1,170 @@
+//! 延迟渲染（deferred shading）
+//!
+//! 几何pass中，mesh的片段着色器不计算光照，只将材质、法向量等输出到多个渲染目标（G-buffer）；
+//! 光照pass再对G-buffer中的每个像素计算一次光照，避免被遮挡的片段（overdraw）重复计算光照。
+
+use crate::light::Light;
+use crate::shadow::ShadowMap;
+use magx::*;
+use rasterizer::{
+    rasterizer::{IRasterizer, Rasterizer},
+    shader::FragOutputs,
+};
+
+/// G-buffer附件
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EGBuffer {
+    /// 漫反射颜色
+    Albedo,
+    /// 世界坐标系中的法向量
+    Normal,
+    /// 镜面反射颜色
+    Specular,
+    /// 世界坐标系中的片段坐标
+    Position,
+    /// mesh id（x分量）和是否需要计算光照（y分量）
+    Mesh,
+}
+
+impl EGBuffer {
+    /// 所有的G-buffer附件
+    pub const ALL: [EGBuffer; 5] = [
+        EGBuffer::Albedo,
+        EGBuffer::Normal,
+        EGBuffer::Specular,
+        EGBuffer::Position,
+        EGBuffer::Mesh,
+    ];
+
+    /// 附件在片段着色器输出中的location
+    #[inline]
+    pub fn location(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// 几何pass中，片段着色器的G-buffer输出
+///
+/// - albedo: 漫反射颜色
+/// - spec: 镜面反射颜色
+/// - norm: 世界坐标系中的法向量
+/// - pos: 世界坐标系中的片段坐标
+/// - id: mesh id（从1开始，0表示没有被延迟渲染的mesh覆盖）
+/// - lit: 是否需要计算光照（否则直接使用albedo）
+pub fn gbuffer_outputs(albedo: &Vec4, spec: &Vec4, norm: &Vec3, pos: &Vec3, id: usize, lit: bool) -> FragOutputs {
+    let mut out = FragOutputs::new();
+    out.set(EGBuffer::Albedo.location(), *albedo);
+    out.set(EGBuffer::Normal.location(), norm.to_vec4(0.0));
+    out.set(EGBuffer::Specular.location(), *spec);
+    out.set(EGBuffer::Position.location(), pos.to_vec4(1.0));
+    out.set(
+        EGBuffer::Mesh.location(),
+        Vec4::from(id as Tyf, if lit { 1.0 } else { 0.0 }, 0.0, 1.0),
+    );
+    out
+}
+
+/// G-buffer
+pub struct GBuffer {
+    /// 屏幕大小(w, h)
+    pub sz: (u32, u32),
+    /// 依次为EGBuffer::ALL中的附件
+    attachments: Vec<Vec<Vec4>>,
+}
+
+impl GBuffer {
+    pub fn new(sz: (u32, u32)) -> Self {
+        let max = (sz.0 * sz.1) as usize;
+        Self {
+            sz,
+            attachments: vec![vec![Vec4::new(); max]; EGBuffer::ALL.len()],
+        }
+    }
+
+    /// 几何pass需要的附加渲染目标数量（albedo写入颜色buffer）
+    #[inline]
+    pub fn targets() -> usize {
+        EGBuffer::ALL.len() - 1
+    }
+
+    #[inline]
+    pub fn get(&self, e: EGBuffer) -> &Vec<Vec4> {
+        &self.attachments[e.location()]
+    }
+
+    /// 从rasterizer中读取几何pass的结果（需要先resolve）
+    pub fn capture(&mut self, r: &Rasterizer) {
+        let albedo = &mut self.attachments[EGBuffer::Albedo.location()];
+        if r.get_hdr() {
+            for (a, c) in albedo.iter_mut().zip(r.get_hdr_color()) {
+                *a = Vec4::from(c[0], c[1], c[2], c[3]);
+            }
+        } else {
+            for (a, c) in albedo.iter_mut().zip(r.get_color()) {
+                *a = Vec4::from(c[0] as Tyf, c[1] as Tyf, c[2] as Tyf, c[3] as Tyf) / 255.0;
+            }
+        }
+        for e in &EGBuffer::ALL[1..] {
+            for (a, c) in self.attachments[e.location()].iter_mut().zip(r.get_target(e.location())) {
+                *a = Vec4::from(c[0], c[1], c[2], c[3]);
+            }
+        }
+    }
+
+    /// 光照pass：对每个被延迟渲染的mesh覆盖的像素计算一次光照，输出到颜色buffer
+    ///
+    /// 开启MSAA时，像素中所有的采样点使用相同的颜色（延迟渲染的边缘没有抗锯齿）。
+    ///
+    /// - eye: 摄像机位置
+    /// - shadow: 光源的阴影贴图
+    pub fn lighting(&self, r: &mut Rasterizer, light: &Light, eye: &Vec3, shadow: &ShadowMap) {
+        let albedo = self.get(EGBuffer::Albedo);
+        let norm = self.get(EGBuffer::Normal);
+        let spec = self.get(EGBuffer::Specular);
+        let pos = self.get(EGBuffer::Position);
+        let mesh = self.get(EGBuffer::Mesh);
+        for j in 0..self.sz.1 {
+            for i in 0..self.sz.0 {
+                let k = (i + j * self.sz.0) as usize;
+                if mesh[k].x < 1.0 {
+                    continue;
+                }
+                let color = if mesh[k].y > 0.0 {
+                    let (n, p) = (norm[k].to_vec3(), pos[k].to_vec3());
+                    let ldir = light.ldir(&p);
+                    light
+                        .calc_blinn_phong(&albedo[k], &spec[k], &n, &(*eye - p), &ldir, shadow.visibility(&p, &n, &ldir))
+                        .w(albedo[k].w)
+                } else {
+                    albedo[k]
+                };
+                r.set_color(i, j, &color);
+            }
+        }
+    }
+
+    /// 将附件转成可以显示的颜色
+    ///
+    /// 法向量和片段坐标从[-1, 1]映射到[0, 1]，mesh id映射成不同的颜色。
+    pub fn view(&self, e: EGBuffer) -> Vec<[u8; 4]> {
+        let rgba = |c: Vec4| {
+            let c = c * 255.0;
+            [c.x as u8, c.y as u8, c.z as u8, 255]
+        };
+        self.get(e)
+            .iter()
+            .map(|c| match e {
+                EGBuffer::Albedo | EGBuffer::Specular => rgba(*c),
+                EGBuffer::Normal | EGBuffer::Position => rgba((*c + 1.0) / 2.0),
+                EGBuffer::Mesh => {
+                    let id = c.x as u32;
+                    if id == 0 {
+                        [0, 0, 0, 255]
+                    } else {
+                        [(id * 97 % 256) as u8, (id * 57 % 256) as u8, (id * 151 % 256) as u8, 255]
+                    }
+                }
+            })
+            .collect()
+    }
+}
@@ -5,6 +5,8 @@
 //! Mesh是内部渲染单位，一个Model可包含多个Mesh，渲染Model时，依次渲染其中的Mesh。
 
 pub mod camera;
+pub mod gbuffer;
 pub mod light;
 pub mod model;
 pub mod scene;
+pub mod shadow;
@@ -9,9 +9,20 @@ const specular: Vec4 = Vec4::from(1.0, 1.0, 1.0, 1.0);
 /// 影响镜面高光的散身/半径
 const shininess: i32 = 32;
 
+/// 光源类型
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ELight {
+    /// 定向光：所有片段的光照方向都为dir
+    Directional,
+    /// 点光源：光照方向为从pos指向片段的方向
+    Point,
+}
+
 /// 定向光或点光源
 #[derive(Debug, Copy, Clone)]
 pub struct Light {
+    /// 光源类型
+    pub kind: ELight,
     /// 世界坐标系中的光源方向
     pub dir: Vec3,
     /// 世界坐标系中的光原位置（只在点光源时使用，同时作为光源的示意坐标）
@@ -21,31 +32,42 @@ pub struct Light {
 impl Light {
     pub fn new() -> Self {
         Self {
+            kind: ELight::Directional,
             dir: Vec3::fill(0.0),
             pos: Vec3::fill(0.0),
         }
     }
 
-    /// 计算Blinn-Phong光照模型（基于定向光）
+    /// 从片段看向光源的方向
+    ///
+    /// - pos: 世界坐标系中的片段坐标
+    #[inline]
+    pub fn ldir(&self, pos: &Vec3) -> Vec3 {
+        match self.kind {
+            ELight::Directional => -self.dir,
+            ELight::Point => (self.pos - *pos).normalize(),
+        }
+    }
+
+    /// 计算Blinn-Phong光照模型
     ///
     /// - diff: 在漫反射光照下物体的颜色
     /// - spec: 镜面光照下物体的颜色
     /// - norm: 片段法线向量（需要归一化）
     /// - vdir: 观察方向（从片段看向摄像机eye的方向）
-    pub fn calc_blinn_phong(&self, diff: &Vec4, spec: &Vec4, norm: &Vec3, vdir: &Vec3) -> Vec4 {
-        // 从片段看向光源的方向
-        let ldir = -self.dir;
-
+    /// - ldir: 光照方向（从片段看向光源的方向，见ldir()）
+    /// - visibility: 光源的可见度（来自阴影贴图，0为完全处于阴影中），只影响漫反射和镜面反射分量
+    pub fn calc_blinn_phong(&self, diff: &Vec4, spec: &Vec4, norm: &Vec3, vdir: &Vec3, ldir: &Vec3, visibility: Tyf) -> Vec4 {
         // 环境光照分量（光源照不到的表面，环境光颜色几乎等于漫反射颜色）
         let a = ambient * (*diff);
 
         // 漫反射光照分量
-        let d = diffuse * (*diff) * norm.dot(&ldir).max(0.0);
+        let d = diffuse * (*diff) * norm.dot(ldir).max(0.0);
 
         // 镜面反射分量
-        let hdir = (*vdir + ldir).normalize(); // 半程向量
+        let hdir = (*vdir + *ldir).normalize(); // 半程向量
         let s = specular * (*spec) * norm.dot(&hdir).max(0.0).powi(shininess);
 
-        a + d + s
+        a + d * visibility + s * visibility
     }
 }
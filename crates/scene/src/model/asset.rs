@@ -1,5 +1,6 @@
 use image::RgbaImage;
 use magx::*;
+use rasterizer::framebuffer::Framebuffer;
 use std::fmt::{Display, Formatter};
 use std::{error, fs, io, io::BufRead};
 
@@ -136,7 +137,14 @@ impl Display for Obj {
 }
 
 /// Texture贴图
-pub struct Tex(pub Option<RgbaImage>);
+///
+/// 加载时生成mipmap，采样时可以根据纹理坐标的屏幕空间导数自动选择mip层。
+pub struct Tex(
+    /// 原始贴图（第0层）
+    pub Option<RgbaImage>,
+    /// 第1层及以上的mipmap，每层宽高减半（最小为1），直到1x1
+    Vec<RgbaImage>,
+);
 
 impl Tex {
     /// 加载贴图
@@ -144,12 +152,51 @@ impl Tex {
     /// 贴图需要翻转y轴，以左下角为坐标原点，且转成RGBA格式。
     pub fn new(filename: &str) -> Self {
         if let Ok(img) = image::open(&filename) {
-            Self(Some(img.flipv().to_rgba8()))
+            let img = img.flipv().to_rgba8();
+            let mips = Self::mipmaps(&img);
+            Self(Some(img), mips)
         } else {
-            Self(None)
+            Self(None, vec![])
         }
     }
 
+    /// 使用帧缓冲的颜色buffer创建贴图（render-to-texture）
+    ///
+    /// 颜色buffer同样以左下角为坐标原点，不需要翻转y轴。
+    pub fn from_framebuffer(fbo: &Framebuffer) -> Self {
+        let (w, h) = fbo.get_size();
+        let raw = fbo.get_color().iter().flatten().copied().collect();
+        let img = RgbaImage::from_raw(w, h, raw).unwrap();
+        let mips = Self::mipmaps(&img);
+        Self(Some(img), mips)
+    }
+
+    /// 生成mipmap：每层对上一层的2x2个texel取平均（box filter）
+    fn mipmaps(img: &RgbaImage) -> Vec<RgbaImage> {
+        let mut mips: Vec<RgbaImage> = vec![];
+        loop {
+            let next = {
+                let src = mips.last().unwrap_or(img);
+                let (sw, sh) = (src.width(), src.height());
+                if sw <= 1 && sh <= 1 {
+                    break;
+                }
+                RgbaImage::from_fn((sw / 2).max(1), (sh / 2).max(1), |x, y| {
+                    let mut sum = [0u32; 4];
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let c = src.get_pixel((x * 2 + dx).min(sw - 1), (y * 2 + dy).min(sh - 1));
+                        for k in 0..4 {
+                            sum[k] += c[k] as u32;
+                        }
+                    }
+                    image::Rgba(sum.map(|s| ((s + 2) / 4) as u8))
+                })
+            };
+            mips.push(next);
+        }
+        mips
+    }
+
     pub fn width(&self) -> u32 {
         if let Some(ref img) = self.0 {
             img.width()
@@ -166,23 +213,73 @@ impl Tex {
         }
     }
 
-    /// 从贴图读取浮点颜色
-    pub fn color(&self, u: Tyf, v: Tyf) -> Option<Vec4> {
-        if let Some(ref img) = self.0 {
-            let x = ((u * img.width() as Tyf) as u32).clamp(0, img.width() - 1);
-            let y = ((v * img.height() as Tyf) as u32).clamp(0, img.height() - 1);
-            let c = img.get_pixel(x, y);
-            Some(Vec4::from(
-                (c[0] as Tyf) / 255.0,
-                (c[1] as Tyf) / 255.0,
-                (c[2] as Tyf) / 255.0,
-                (c[3] as Tyf) / 255.0,
-            ))
+    /// mip层数（包括原始贴图）
+    pub fn levels(&self) -> usize {
+        if self.0.is_some() {
+            self.1.len() + 1
         } else {
-            None
+            0
         }
     }
 
+    /// 第level层mipmap
+    fn level(&self, level: usize) -> Option<&RgbaImage> {
+        if level == 0 {
+            self.0.as_ref()
+        } else {
+            self.1.get(level - 1)
+        }
+    }
+
+    /// 最近点采样，读取浮点颜色
+    fn texel(img: &RgbaImage, u: Tyf, v: Tyf) -> Vec4 {
+        let x = ((u * img.width() as Tyf) as u32).clamp(0, img.width() - 1);
+        let y = ((v * img.height() as Tyf) as u32).clamp(0, img.height() - 1);
+        let c = img.get_pixel(x, y);
+        Vec4::from(
+            (c[0] as Tyf) / 255.0,
+            (c[1] as Tyf) / 255.0,
+            (c[2] as Tyf) / 255.0,
+            (c[3] as Tyf) / 255.0,
+        )
+    }
+
+    /// 从贴图读取浮点颜色
+    pub fn color(&self, u: Tyf, v: Tyf) -> Option<Vec4> {
+        self.0.as_ref().map(|img| Self::texel(img, u, v))
+    }
+
+    /// 根据纹理坐标的屏幕空间导数计算mip层（LOD）
+    ///
+    /// 取一个像素在x, y方向上覆盖的texel数量的最大值，LOD = log2(max)。
+    ///
+    /// - duv_dx, duv_dy: 纹理坐标对屏幕空间x, y的导数
+    pub fn lod(&self, duv_dx: &Vec2, duv_dy: &Vec2) -> Tyf {
+        let (w, h) = (self.width() as Tyf, self.height() as Tyf);
+        let dx = Vec2::from(duv_dx.x * w, duv_dx.y * h);
+        let dy = Vec2::from(duv_dy.x * w, duv_dy.y * h);
+        let rho = dx.squared_norm().max(dy.squared_norm()).sqrt();
+        rho.max(Tyf::MIN_POSITIVE).log2().max(0.0)
+    }
+
+    /// 按LOD从mipmap读取浮点颜色
+    ///
+    /// 每层使用最近点采样，相邻两层之间线性插值（类似GL_NEAREST_MIPMAP_LINEAR）。
+    pub fn color_lod(&self, u: Tyf, v: Tyf, lod: Tyf) -> Option<Vec4> {
+        let top = self.levels().checked_sub(1)?;
+        let lod = lod.clamp(0.0, top as Tyf);
+        let l0 = lod.floor() as usize;
+        let l1 = (l0 + 1).min(top);
+        let c0 = Self::texel(self.level(l0)?, u, v);
+        let c1 = Self::texel(self.level(l1)?, u, v);
+        Some(c0 + (c1 - c0) * (lod - l0 as Tyf))
+    }
+
+    /// 根据纹理坐标的屏幕空间导数自动选择mip层，读取浮点颜色
+    pub fn color_grad(&self, u: Tyf, v: Tyf, duv_dx: &Vec2, duv_dy: &Vec2) -> Option<Vec4> {
+        self.color_lod(u, v, self.lod(duv_dx, duv_dy))
+    }
+
     /// 计算模型空间的法线纹理（object-space normal map）
     pub fn o_vec(&self, u: Tyf, v: Tyf) -> Option<Vec3> {
         if let Some(ref img) = self.0 {
@@ -276,3 +373,109 @@ impl Display for Mtl {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rasterizer::pipeline::{IPipeline, IPrimitive};
+    use rasterizer::rasterizer::Rasterizer;
+    use rasterizer::shader::{triangle_vertex, FragOutputs, IGlsl, IShader, Instance};
+    use rasterizer::varying::{EInterp, Varyings};
+    use std::ops::Range;
+
+    /// 覆盖整个屏幕的三角形，左半边为红色，右半边为绿色，下半边加上蓝色
+    struct Quadrants;
+
+    impl IPrimitive for Quadrants {
+        fn indices(&self) -> Range<usize> {
+            0..1
+        }
+    }
+
+    impl IShader for Quadrants {
+        fn vertex(&self, _pidx: usize, _inst: &Instance) -> ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)) {
+            let a = Vec4::from(-1.0, -1.0, 0.0, 1.0);
+            let b = Vec4::from(3.0, -1.0, 0.0, 1.0);
+            let c = Vec4::from(-1.0, 3.0, 0.0, 1.0);
+            let v = |p: Vec4| {
+                let mut v = Varyings::new();
+                v.push(EInterp::Smooth, p.to_vec2());
+                v
+            };
+            ((a, b, c), (v(a), v(b), v(c)))
+        }
+
+        fn vertex_at(&self, vidx: usize, inst: &Instance) -> (Vec4, Varyings) {
+            triangle_vertex(self.vertex(vidx / 3, inst), vidx % 3)
+        }
+
+        fn fragment(&self, _pidx: usize, _inst: &Instance, v: &Varyings) -> FragOutputs {
+            let p = v.get::<Vec2>(0);
+            let c = if p.x < 0.0 {
+                Vec4::from(1.0, 0.0, 0.0, 1.0)
+            } else {
+                Vec4::from(0.0, 1.0, 0.0, 1.0)
+            };
+            c.z(if p.y < 0.0 { 1.0 } else { 0.0 }).into()
+        }
+    }
+
+    /// 4x2的贴图，红色分量为x * 60 + y * 20
+    fn ramp() -> Tex {
+        let img = RgbaImage::from_fn(4, 2, |x, y| image::Rgba([(x * 60 + y * 20) as u8, 0, 0, 255]));
+        let mips = Tex::mipmaps(&img);
+        Tex(Some(img), mips)
+    }
+
+    #[test]
+    fn mipmap_test() {
+        let tex = ramp();
+        // 每层宽高减半（最小为1），直到1x1
+        assert_eq!(tex.levels(), 3);
+        let sizes: Vec<_> = (0..3).map(|l| tex.level(l).unwrap().dimensions()).collect();
+        assert_eq!(sizes, [(4, 2), (2, 1), (1, 1)]);
+        // 每个texel为上一层2x2个texel的平均值（四舍五入）
+        let red = |l: usize, x: u32| tex.level(l).unwrap().get_pixel(x, 0)[0];
+        assert_eq!((red(1, 0), red(1, 1)), (40, 160));
+        assert_eq!(red(2, 0), 100);
+        assert_eq!(tex.level(2).unwrap().get_pixel(0, 0)[3], 255);
+
+        // 一个像素覆盖的texel越多，选择的mip层越粗糙
+        let dy = Vec2::new();
+        let lods: Vec<_> = [0.25, 0.5, 1.0, 4.0]
+            .iter()
+            .map(|d| tex.lod(&Vec2::from(*d, 0.0), &dy))
+            .collect();
+        assert_eq!(lods, [0.0, 1.0, 2.0, 4.0]);
+        let sample = |d: Tyf| tex.color_grad(0.1, 0.1, &Vec2::from(d, 0.0), &dy).unwrap().x;
+        assert_eq!(sample(0.25), 0.0);
+        assert_eq!(sample(0.5), 40.0 / 255.0);
+        // 超过最高层时使用1x1的mipmap
+        assert_eq!(sample(4.0), 100.0 / 255.0);
+        // 相邻两层之间线性插值
+        assert!((tex.color_lod(0.1, 0.1, 1.5).unwrap().x - 70.0 / 255.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn render_to_texture_test() {
+        let mut r = Rasterizer::new((16, 16));
+        *r.wire_frame() = false;
+        let screen = r.bind_framebuffer(Framebuffer::new((8, 4)));
+        r.clear_color(&Vec4::fill(0.0));
+        r.clear_depth();
+        r.draw(&Quadrants);
+        r.resolve();
+        let fbo = r.bind_framebuffer(screen);
+
+        // 贴图与帧缓冲的大小相同，纹理坐标原点在左下角
+        let tex = Tex::from_framebuffer(&fbo);
+        assert_eq!((tex.width(), tex.height(), tex.levels()), (8, 4, 4));
+        assert_eq!(tex.color(0.2, 0.2), Some(Vec4::from(1.0, 0.0, 1.0, 1.0)));
+        assert_eq!(tex.color(0.2, 0.8), Some(Vec4::from(1.0, 0.0, 0.0, 1.0)));
+        assert_eq!(tex.color(0.8, 0.2), Some(Vec4::from(0.0, 1.0, 1.0, 1.0)));
+        assert_eq!(tex.color(0.8, 0.8), Some(Vec4::from(0.0, 1.0, 0.0, 1.0)));
+        // 最粗糙的mip层为四种颜色的平均值
+        let c = tex.color_lod(0.5, 0.5, 3.0).unwrap();
+        assert_eq!(c, Vec4::from(128.0, 128.0, 128.0, 255.0) / 255.0);
+    }
+} /* tests */
@@ -1,12 +1,21 @@
 use super::asset::*;
 use super::ModelUniformVarsRef;
+use crate::gbuffer::gbuffer_outputs;
 use magx::*;
 use rasterizer::{
-    pipeline::IPrimitive,
-    shader::{IShader, UniformMatrix},
+    pipeline::{ETopology, IPrimitive},
+    shader::{triangle_vertex, FragOutputs, IShader, Instance, UniformMatrix},
+    varying::{EInterp, Varyings},
 };
 use std::any::Any;
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// 下一个mesh的id（从1开始，0表示没有mesh）
+static MESH_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Lite材质不计算光照，完全处于阴影中时diffuse颜色的亮度
+const LITE_SHADOW: Tyf = 0.5;
 
 /// mesh类型
 pub enum EMesh {
@@ -21,10 +30,20 @@ pub enum EMesh {
 /// mesh模型数据
 pub struct Mesh {
     name: String,
+    /// mesh id，用于G-buffer
+    id: usize,
     e: EMesh,
     o: Obj,
     m: Mtl,
     uniforms: ModelUniformVarsRef,
+    /// 模型坐标系中的包围盒(最小值, 最大值)
+    bound: (Vec3, Vec3),
+    /// 不透明度（小于1.0时为半透明mesh）
+    alpha: Tyf,
+    /// alpha测试阈值，alpha小于阈值的片段被丢弃（用于镂空材质）
+    alpha_test: Option<Tyf>,
+    /// 图元拓扑（Points时绘制成点云）
+    topology: ETopology,
 }
 
 impl Mesh {
@@ -39,79 +58,201 @@ impl Mesh {
             name, o, m
         );
 
+        let (lo, hi) = o.v.iter().fold((Vec3::fill(Tyf::MAX), Vec3::fill(Tyf::MIN)), |(lo, hi), v| {
+            (
+                Vec3::from(lo.x.min(v.x), lo.y.min(v.y), lo.z.min(v.z)),
+                Vec3::from(hi.x.max(v.x), hi.y.max(v.y), hi.z.max(v.z)),
+            )
+        });
+
         Self {
             name: name.to_string(),
+            id: MESH_ID.fetch_add(1, Ordering::Relaxed),
             e,
             o,
             m,
             uniforms,
+            bound: (lo, hi),
+            alpha: 1.0,
+            alpha_test: None,
+            topology: ETopology::Triangles,
         }
     }
+
+    /// 设置mesh的不透明度
+    pub fn set_alpha(&mut self, alpha: Tyf) {
+        self.alpha = alpha.clamp(0.0, 1.0);
+    }
+
+    /// 设置alpha测试阈值，None表示关闭alpha测试
+    pub fn set_alpha_test(&mut self, cutoff: Option<Tyf>) {
+        self.alpha_test = cutoff;
+    }
+
+    /// 设置图元拓扑
+    ///
+    /// 三角面的顶点依次展开成顶点列表，例如Points拓扑将mesh绘制成点云。
+    pub fn set_topology(&mut self, topology: ETopology) {
+        self.topology = topology;
+    }
 }
 
 impl IPrimitive for Mesh {
     fn indices(&self) -> Range<usize> {
+        let n = self.o.f.len();
         return Range {
             start: 0,
-            end: self.o.f.len(),
+            end: if self.topology == ETopology::Triangles { n } else { n * 3 },
+        };
+    }
+
+    fn topology(&self) -> ETopology {
+        self.topology
+    }
+
+    fn transparent(&self) -> bool {
+        self.alpha < 1.0
+    }
+
+    fn center(&self) -> Vec3 {
+        let uni = self.uniforms.read().unwrap();
+        let center = (self.bound.0 + self.bound.1) / 2.0;
+        uni.mat.model.mul_vec(&center.to_vec4(1.0)).to_vec3()
+    }
+
+    fn bounds(&self, instances: &[Instance]) -> Option<[Vec4; 8]> {
+        let uni = self.uniforms.read().unwrap();
+        let corners = |(lo, hi): (Vec3, Vec3)| -> [Vec4; 8] {
+            std::array::from_fn(|k| {
+                let x = if k & 1 == 0 { lo.x } else { hi.x };
+                let y = if k & 2 == 0 { lo.y } else { hi.y };
+                let z = if k & 4 == 0 { lo.z } else { hi.z };
+                Vec4::from(x, y, z, 1.0)
+            })
         };
+        if instances.is_empty() {
+            return Some(corners(self.bound).map(|p| uni.mat.mvp.mul_vec(&p)));
+        }
+        // 在世界坐标系中合并所有实例的包围盒
+        let (mut lo, mut hi) = (Vec3::fill(Tyf::MAX), Vec3::fill(Tyf::MIN));
+        for inst in instances {
+            let model = inst.model.mul_mat(&uni.mat.model);
+            for p in corners(self.bound) {
+                let v = model.mul_vec(&p).to_vec3();
+                lo = Vec3::from(lo.x.min(v.x), lo.y.min(v.y), lo.z.min(v.z));
+                hi = Vec3::from(hi.x.max(v.x), hi.y.max(v.y), hi.z.max(v.z));
+            }
+        }
+        let vp = uni.mat.proj.mul_mat(&uni.mat.view);
+        Some(corners((lo, hi)).map(|p| vp.mul_vec(&p)))
     }
 }
 
+/// Mesh着色器的varying变量location
+const VAR_TEXCOORD: usize = 0;
+const VAR_NORMAL: usize = 1;
+const VAR_FRAG_POS: usize = 2;
+
 impl IShader for Mesh {
-    fn vertex(&self, pidx: usize) -> (Vec4, Vec4, Vec4) {
+    fn vertex(&self, pidx: usize, inst: &Instance) -> ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)) {
         let idx = &self.o.f[pidx];
-        let uni = self.uniforms.borrow();
-        (
-            uni.mat.mvp.mul_vec(&self.o.v[idx.v.0].to_vec4(1.0)),
-            uni.mat.mvp.mul_vec(&self.o.v[idx.v.1].to_vec4(1.0)),
-            uni.mat.mvp.mul_vec(&self.o.v[idx.v.2].to_vec4(1.0)),
-        )
+        let uni = self.uniforms.read().unwrap();
+        // 实例的模型变换在mesh自身的模型变换之后应用
+        let model = inst.model.mul_mat(&uni.mat.model);
+        let mvp = uni.mat.proj.mul_mat(&uni.mat.view).mul_mat(&model);
+        let vertex = |v: usize, vt: usize, vn: usize| {
+            let pos = self.o.v[v].to_vec4(1.0);
+            let mut var = Varyings::new();
+            // 纹理坐标
+            var.push(EInterp::Smooth, self.o.vt[vt].to_vec2());
+            // 法向量
+            var.push(EInterp::Smooth, self.o.vn[vn]);
+            // 通过模型变换，将顶点的坐标变换世界坐标系中，用于计算光照
+            var.push(EInterp::Smooth, model.mul_vec(&pos).to_vec3());
+            (mvp.mul_vec(&pos), var)
+        };
+        let (a, va) = vertex(idx.v.0, idx.vt.0, idx.vn.0);
+        let (b, vb) = vertex(idx.v.1, idx.vt.1, idx.vn.1);
+        let (c, vc) = vertex(idx.v.2, idx.vt.2, idx.vn.2);
+        ((a, b, c), (va, vb, vc))
     }
 
-    fn fragment(&self, pidx: usize, bc: &Vec3) -> Vec4 {
-        let idx = &self.o.f[pidx];
-        let uni = self.uniforms.borrow();
-        // 片段三个顶点的纹理坐标插值
-        let u = interpolate(&bc, &self.o.vt[idx.vt.0].x, &self.o.vt[idx.vt.1].x, &self.o.vt[idx.vt.2].x);
-        let v = interpolate(&bc, &self.o.vt[idx.vt.0].y, &self.o.vt[idx.vt.1].y, &self.o.vt[idx.vt.2].y);
+    fn vertex_at(&self, vidx: usize, inst: &Instance) -> (Vec4, Varyings) {
+        triangle_vertex(self.vertex(vidx / 3, inst), vidx % 3)
+    }
+
+    fn fragment(&self, _pidx: usize, inst: &Instance, var: &Varyings) -> FragOutputs {
+        let uni = self.uniforms.read().unwrap();
+        // 校正法向量的矩阵（包括实例的模型变换）
+        let mit = inst.mit.mul_mat(&uni.mat.mit);
+        // 片段的纹理坐标
+        let Vec2 { x: u, y: v } = var.get(VAR_TEXCOORD);
+        // 纹理坐标的屏幕空间导数，用于选择mip层
+        let (duv_dx, duv_dy): (Vec2, Vec2) = (var.dfdx(VAR_TEXCOORD), var.dfdy(VAR_TEXCOORD));
         // 片段的缺省diffuse颜色
         let dd = Vec4::from(0.0, 0.0, 1.0, 1.0);
         // 片段的缺省specular颜色
         let ss = Vec4::fill(1.0);
-        // 片段三个顶点的法向量插值
-        let nn = interpolate(&bc, &self.o.vn[idx.vn.0], &self.o.vn[idx.vn.1], &self.o.vn[idx.vn.2]);
-        // 通过模型变换，将片段的坐标变换世界坐标系中，用于计算光照
-        let frag_pos = uni
-            .mat
-            .model
-            .mul_vec(&interpolate(&bc, &self.o.v[idx.v.0], &self.o.v[idx.v.1], &self.o.v[idx.v.2]).to_vec4(1.0))
-            .to_vec3();
-
-        match self.e {
+        // 片段的法向量
+        let nn: Vec3 = var.get(VAR_NORMAL);
+        // 片段在世界坐标系中的坐标
+        let frag_pos: Vec3 = var.get(VAR_FRAG_POS);
+
+        // 片段的材质(diffuse颜色, specular颜色, 法向量, 是否计算光照)
+        let (d, s, n, lit) = match self.e {
             EMesh::Standard => {
-                let d = self.m.diff.color(u, v).unwrap_or(dd);
-                let s = self.m.spec.color(u, v).unwrap_or(ss);
-                let n = uni.mat.mit.mul_vec(&self.m.norm.o_vec(u, v).unwrap_or(nn)).normalize();
-                uni.comps
-                    .borrow()
-                    .light
-                    .calc_blinn_phong(&d, &s, &n, &(uni.comps.borrow().camera.eye - frag_pos))
-                    .w(1.0)
+                let d = self.m.diff.color_grad(u, v, &duv_dx, &duv_dy).unwrap_or(dd);
+                let s = self.m.spec.color_grad(u, v, &duv_dx, &duv_dy).unwrap_or(ss);
+                let n = mit.mul_vec(&self.m.norm.o_vec(u, v).unwrap_or(nn)).normalize();
+                (d, s, n, true)
             }
             EMesh::Lite => {
-                // 只用diffuse贴图，渲染出“光滑”的模型
-                self.m.diff.color(u, v).unwrap_or(dd)
+                // 只用diffuse贴图，渲染出“光滑”的模型；接收阴影时按可见度压暗颜色
+                let n = mit.mul_vec(&nn).normalize();
+                let d = self.m.diff.color_grad(u, v, &duv_dx, &duv_dy).unwrap_or(dd);
+                let comps = uni.comps.read().unwrap();
+                let d = if comps.shadow.rendered() {
+                    let ldir = comps.light.ldir(&frag_pos);
+                    let vis = comps.shadow.visibility(&frag_pos, &n, &ldir);
+                    (d.to_vec3() * (LITE_SHADOW + (1.0 - LITE_SHADOW) * vis)).to_vec4(d.w)
+                } else {
+                    d
+                };
+                (d, ss, n, false)
             }
             EMesh::Debug => {
-                let n = uni.mat.mit.mul_vec(&nn).normalize();
+                let n = mit.mul_vec(&nn).normalize();
 
                 // Test: 可视化法向量
-                return ((n + Vec3::fill(1.0)) / 2.0).to_vec4(1.0);
+                (((n + Vec3::fill(1.0)) / 2.0).to_vec4(1.0), ss, n, false)
 
                 // Test: 可视化切线空间的法向量
-                //return self.m.norm.t_vec(u, v, &n).to_vec4(1.0);
+                //(self.m.norm.t_vec(u, v, &n).to_vec4(1.0), ss, n, false)
             }
+        };
+        // 双面光照：背面的片段（关闭面剃除时可见）使用反向的法向量
+        let n = if var.front_facing() { n } else { -n };
+        let d = d * inst.tint;
+        let d = d.w(d.w * self.alpha);
+        if self.alpha_test.is_some_and(|cutoff| d.w < cutoff) {
+            return FragOutputs::discard();
+        }
+
+        // 延迟渲染的几何pass只输出G-buffer，光照在光照pass中计算（半透明mesh仍然使用前向渲染）
+        if uni.deferred && !self.transparent() {
+            return gbuffer_outputs(&d, &s, &n, &frag_pos, self.id, lit);
+        }
+        if lit {
+            let comps = uni.comps.read().unwrap();
+            let ldir = comps.light.ldir(&frag_pos);
+            let vis = comps.shadow.visibility(&frag_pos, &n, &ldir);
+            comps
+                .light
+                .calc_blinn_phong(&d, &s, &n, &(comps.camera.eye - frag_pos), &ldir, vis)
+                .w(d.w)
+                .into()
+        } else {
+            d.into()
         }
     }
 }
@@ -284,18 +425,88 @@ impl IShader for MFrustum {
         }
     }
 
-    fn vertex(&self, pidx: usize) -> (Vec4, Vec4, Vec4) {
+    fn vertex(&self, pidx: usize, _inst: &Instance) -> ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)) {
         let idx = &self.faces[pidx];
-        (
-            self.mvp.mul_vec(&self.pos[idx.v.0].to_vec4(1.0)),
-            self.mvp.mul_vec(&self.pos[idx.v.1].to_vec4(1.0)),
-            self.mvp.mul_vec(&self.pos[idx.v.2].to_vec4(1.0)),
-        )
+        let vertex = |v: usize| {
+            let mut var = Varyings::new();
+            var.push(EInterp::Smooth, self.color[v]);
+            (self.mvp.mul_vec(&self.pos[v].to_vec4(1.0)), var)
+        };
+        let (a, va) = vertex(idx.v.0);
+        let (b, vb) = vertex(idx.v.1);
+        let (c, vc) = vertex(idx.v.2);
+        ((a, b, c), (va, vb, vc))
     }
 
-    fn fragment(&self, pidx: usize, bc: &Vec3) -> Vec4 {
-        let idx = &self.faces[pidx];
-        // 基于顶点颜色插值
-        interpolate(&bc, &self.color[idx.v.0], &self.color[idx.v.1], &self.color[idx.v.2]).to_vec4(1.0)
+    fn vertex_at(&self, vidx: usize, inst: &Instance) -> (Vec4, Varyings) {
+        triangle_vertex(self.vertex(vidx / 3, inst), vidx % 3)
+    }
+
+    fn fragment(&self, _pidx: usize, _inst: &Instance, var: &Varyings) -> FragOutputs {
+        // 顶点颜色插值
+        var.get::<Vec3>(0).to_vec4(1.0).into()
+    }
+}
+
+/// 坐标轴模型
+///
+/// 使用Lines拓扑绘制x, y, z轴（分别为红、绿、蓝色）。
+pub struct MAxes {
+    /// 线段的端点和颜色
+    vertices: Vec<(Vec3, Vec3)>,
+    /// 来自model的mvp
+    mvp: Mat4,
+}
+
+impl MAxes {
+    pub fn new() -> Self {
+        let mut vertices = Vec::new();
+        for axis in 0..3 {
+            let mut v = Vec3::new();
+            v[axis] = 1.0;
+            vertices.push((Vec3::new(), v));
+            vertices.push((v, v));
+        }
+        Self {
+            vertices,
+            mvp: Mat4::eye(1.0),
+        }
+    }
+}
+
+impl Default for MAxes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IPrimitive for MAxes {
+    fn indices(&self) -> Range<usize> {
+        0..self.vertices.len()
+    }
+
+    fn topology(&self) -> ETopology {
+        ETopology::Lines
+    }
+}
+
+impl IShader for MAxes {
+    fn set_uniforms(&mut self, u: Box<dyn Any>) {
+        if let Ok(u) = u.downcast::<UniformMatrix>() {
+            self.mvp = u.mvp;
+        } else {
+            println!("Failed to set uniforms to mesh 'Axes'");
+        }
+    }
+
+    fn vertex_at(&self, vidx: usize, _inst: &Instance) -> (Vec4, Varyings) {
+        let (pos, color) = self.vertices[vidx];
+        let mut var = Varyings::new();
+        var.push(EInterp::Flat, color);
+        (self.mvp.mul_vec(&pos.to_vec4(1.0)), var)
+    }
+
+    fn fragment(&self, _pidx: usize, _inst: &Instance, var: &Varyings) -> FragOutputs {
+        var.get::<Vec3>(0).to_vec4(1.0).into()
     }
 }
@@ -3,15 +3,17 @@
 pub mod asset;
 pub mod mesh;
 
-use self::mesh::{EMesh, MFrustum, Mesh};
+use self::mesh::{EMesh, MAxes, MFrustum, Mesh};
 use crate::camera::Camera;
 use crate::light::Light;
 use crate::scene::SceneComponentsRef;
 use magx::*;
-use rasterizer::{pipeline::IPrimitive, shader::UniformMatrix};
-use std::cell::RefCell;
+use rasterizer::{
+    pipeline::{ETopology, IPrimitive},
+    shader::{Instance, UniformMatrix},
+};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
 
 /// 场景模型需要的uniform变量
 pub struct ModelUniformVars {
@@ -19,15 +21,18 @@ pub struct ModelUniformVars {
     pub mat: UniformMatrix,
     /// 来自scene的场景组件
     pub comps: SceneComponentsRef,
+    /// 是否输出G-buffer（延迟渲染的几何pass）
+    pub deferred: bool,
 }
 
-pub type ModelUniformVarsRef = Rc<RefCell<ModelUniformVars>>;
+pub type ModelUniformVarsRef = Arc<RwLock<ModelUniformVars>>;
 
 impl ModelUniformVars {
     pub fn new(comps: SceneComponentsRef) -> ModelUniformVarsRef {
-        Rc::new(RefCell::new(ModelUniformVars {
+        Arc::new(RwLock::new(ModelUniformVars {
             mat: UniformMatrix::new(),
             comps,
+            deferred: false,
         }))
     }
 }
@@ -40,17 +45,19 @@ pub struct Model {
     pub meshes: HashMap<&'static str, Box<dyn IPrimitive>>,
     /// model需要使用uniform变量
     pub uniforms: ModelUniformVarsRef,
+    /// 需要实例化绘制的mesh及其实例数据
+    pub instances: HashMap<&'static str, Vec<Instance>>,
 }
 
 macro_rules! load_mesh {
     (standard, $name:tt, $uniforms:ident) => {
-        Mesh::new(EMesh::Standard, $name, Rc::clone(&$uniforms))
+        Mesh::new(EMesh::Standard, $name, Arc::clone(&$uniforms))
     };
     (lite, $name:tt, $uniforms:ident) => {
-        Mesh::new(EMesh::Lite, $name, Rc::clone(&$uniforms))
+        Mesh::new(EMesh::Lite, $name, Arc::clone(&$uniforms))
     };
     (debug, $name:tt, $uniforms:ident) => {
-        Mesh::new(EMesh::Debug, $name, Rc::clone(&$uniforms))
+        Mesh::new(EMesh::Debug, $name, Arc::clone(&$uniforms))
     };
     (frustum) => {
         MFrustum::new()
@@ -58,6 +65,9 @@ macro_rules! load_mesh {
     (cube) => {
         MFrustum::new_cube()
     };
+    (axes) => {
+        MAxes::new()
+    };
 }
 
 impl Model {
@@ -66,13 +76,19 @@ impl Model {
         let uniforms = ModelUniformVars::new(comps);
 
         meshes.insert("african_head", Box::new(load_mesh!(standard, "african_head", uniforms)));
-        meshes.insert(
-            "african_head_eye",
-            Box::new(load_mesh!(standard, "african_head_eye_inner", uniforms)),
-        );
+        // 内层眼球为镂空材质：alpha（贴图alpha乘以实例颜色的alpha）小于0.5的片段被丢弃
+        let mut eye = load_mesh!(standard, "african_head_eye_inner", uniforms);
+        eye.set_alpha_test(Some(0.5));
+        meshes.insert("african_head_eye", Box::new(eye));
         meshes.insert("diablo3", Box::new(load_mesh!(standard, "diablo3_pose", uniforms)));
         meshes.insert("floor", Box::new(load_mesh!(lite, "floor", uniforms)));
         meshes.insert("sphere", Box::new(load_mesh!(debug, "sphere", uniforms)));
+        let mut glass = load_mesh!(standard, "sphere", uniforms);
+        glass.set_alpha(0.4);
+        meshes.insert("sphere_glass", Box::new(glass));
+        let mut points = load_mesh!(debug, "sphere", uniforms);
+        points.set_topology(ETopology::Points);
+        meshes.insert("sphere_points", Box::new(points));
 
         meshes.insert("spot", Box::new(load_mesh!(standard, "spot", uniforms)));
         meshes.insert("spot_lite", Box::new(load_mesh!(lite, "spot", uniforms)));
@@ -80,24 +96,59 @@ impl Model {
 
         meshes.insert("cube", Box::new(load_mesh!(cube)));
         meshes.insert("frustum", Box::new(load_mesh!(frustum)));
+        meshes.insert("axes", Box::new(load_mesh!(axes)));
+
+        // 实例化绘制：只加载一次模型数据，每个实例使用各自的模型变换和颜色
+        let mut instances = HashMap::new();
+        meshes.insert("spot_herd", Box::new(load_mesh!(standard, "spot", uniforms)));
+        instances.insert("spot_herd", Self::herd(10));
+
+        Self {
+            meshes,
+            uniforms,
+            instances,
+        }
+    }
 
-        Self { meshes, uniforms }
+    /// 在floor上排列n x n个实例
+    fn herd(n: usize) -> Vec<Instance> {
+        let step = 2.0 / n as Tyf;
+        let size = step * 0.75;
+        (0..n * n)
+            .map(|k| {
+                let (x, z) = ((k % n) as Tyf, (k / n) as Tyf);
+                // spot的最低点约为y = -0.74，floor位于y = -1
+                let pos = Vec3::from(-1.0 + step * (x + 0.5), -1.0 + 0.74 * size, -1.0 + step * (z + 0.5));
+                let mat = translate(&Mat4::eye(1.0), &pos);
+                let mat = scale(&mat, &Vec3::fill(size));
+                let tint = Vec4::from(0.5 + 0.5 * x / n as Tyf, 0.5 + 0.5 * z / n as Tyf, 1.0, 1.0);
+                Instance::from(mat, tint)
+            })
+            .collect()
     }
 
-    /// 更新model的变换矩阵和光照数据
+    /// 使用摄像机的视图和投影矩阵更新所有mesh的变换矩阵
     pub fn update(&mut self) {
+        let (view, proj) = {
+            let u = self.uniforms.read().unwrap();
+            let comps = u.comps.read().unwrap();
+            (comps.camera.view(), comps.camera.proj())
+        };
+        self.set_view_proj(&view, &proj);
+    }
+
+    /// 使用指定的视图和投影矩阵更新所有mesh的变换矩阵（如从光源的视角渲染阴影贴图）
+    pub fn set_view_proj(&mut self, view: &Mat4, proj: &Mat4) {
         let mat_model = Mat4::eye(1.0);
         //let mat_model = translate(&mat_model, &Vec3::from(-1.0, -2.0, -5.0));
         //let mat_model = scale(&mat_model, &Vec3::from(0.5, 0.5, 0.5));
         let mat_model = rotate(&mat_model, &Vec3::from(0.0, 1.0, 0.0), Angle::Ang(-5.0));
         //let mat_model = rotate(&mat_model, &Vec3::from(0.0, 1.0, 0.0), Angle::Ang(140.0));
 
-        let mut u = self.uniforms.borrow_mut();
-        let view = u.comps.borrow().camera.view();
-        let proj = u.comps.borrow().camera.proj();
+        let mut u = self.uniforms.write().unwrap();
         u.mat.model = mat_model;
-        u.mat.view = view;
-        u.mat.proj = proj;
+        u.mat.view = *view;
+        u.mat.proj = *proj;
         u.mat.calc_mit();
         u.mat.calc_mvp();
 
@@ -1,11 +1,18 @@
 use crate::camera::Camera;
+use crate::gbuffer::GBuffer;
 use crate::light::Light;
 use crate::model::{Model, ModelLight};
+use crate::shadow::ShadowMap;
 use magx::*;
-use rasterizer::{pipeline::IPipeline, rasterizer::Rasterizer};
-use std::cell::RefCell;
+use rasterizer::{
+    oit::EOit,
+    pipeline::{IPipeline, IPrimitive},
+    rasterizer::Rasterizer,
+    shader::Instance,
+    state::{BlendState, DepthState},
+};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
 
 /// 背景颜色
 const COLOR_BG: Vec4 = Vec4::from(0.4, 0.2, 0.3, 1.0);
@@ -14,15 +21,21 @@ const COLOR_BG: Vec4 = Vec4::from(0.4, 0.2, 0.3, 1.0);
 pub struct SceneComponents {
     /// 摄像机
     pub camera: Camera,
-    /// 光照（定向光）
+    /// 光源（定向光或点光源）
     pub light: Light,
+    /// 光源的阴影贴图（没有渲染时不产生阴影）
+    pub shadow: ShadowMap,
 }
 
-pub type SceneComponentsRef = Rc<RefCell<SceneComponents>>;
+pub type SceneComponentsRef = Arc<RwLock<SceneComponents>>;
 
 impl SceneComponents {
     pub fn new(camera: Camera, light: Light) -> SceneComponentsRef {
-        Rc::new(RefCell::new(SceneComponents { camera, light }))
+        Arc::new(RwLock::new(SceneComponents {
+            camera,
+            light,
+            shadow: ShadowMap::default(),
+        }))
     }
 }
 
@@ -35,6 +48,16 @@ pub struct Scene {
     pub comps: SceneComponentsRef,
     /// 屏幕大小(w, h)
     pub sz: (u32, u32),
+    /// 是否使用延迟渲染（半透明的mesh仍然使用前向渲染）
+    pub deferred: bool,
+    /// 延迟渲染的G-buffer
+    pub gbuffer: GBuffer,
+    /// 绘制不透明的mesh前进行遮挡查询，跳过被完全遮挡的mesh
+    pub occlusion_query: bool,
+    /// 是否渲染阴影贴图（不透明的mesh投射阴影）
+    pub shadows: bool,
+    /// 半透明mesh的顺序无关透明方式（EOit::Off时按mesh从远到近排序后混合）
+    pub oit: EOit,
 }
 
 impl Scene {
@@ -57,11 +80,28 @@ impl Scene {
         );
 
         Self {
-            model: Model::new(Rc::clone(&comps)),
+            model: Model::new(Arc::clone(&comps)),
             model_light: ModelLight::new(),
             comps,
             sz,
+            deferred: false,
+            gbuffer: GBuffer::new(sz),
+            occlusion_query: false,
+            shadows: false,
+            oit: EOit::Off,
+        }
+    }
+
+    /// 改变场景屏幕大小
+    ///
+    /// 更新摄像机的宽高比并重新分配G-buffer；rasterizer需要通过`Rasterizer::resize`改变成相同的大小。
+    pub fn resize(&mut self, sz: (u32, u32)) {
+        if sz == self.sz {
+            return;
         }
+        self.sz = sz;
+        self.comps.write().unwrap().camera.resize(sz);
+        self.gbuffer = GBuffer::new(sz);
     }
 
     /// 获取所有mesh列表
@@ -77,20 +117,214 @@ impl Scene {
     /// 更新场景
     ///
     /// - meshes: 需要更新的mesh列表
+    ///
+    /// 开启阴影时，先从光源的视角渲染不透明mesh的阴影贴图；
+    /// 再绘制不透明的mesh（开启遮挡查询时从近到远绘制；开启延迟渲染时，绘制完成后从G-buffer计算光照），再开启混合、关闭深度写入，按从远到近的顺序绘制半透明的mesh。
     pub fn update(&mut self, r: &mut Rasterizer, meshes: &HashMap<&'static str, bool>) {
+        // 投影矩阵需要与rasterizer的深度范围一致
+        self.comps.write().unwrap().camera.reversed_z = r.get_reversed_z();
         self.model.update();
-        self.model_light
-            .update(&self.comps.borrow().camera, &self.comps.borrow().light);
+        self.model.uniforms.write().unwrap().deferred = self.deferred;
+        let (eye, light) = {
+            let comps = self.comps.read().unwrap();
+            self.model_light.update(&comps.camera, &comps.light);
+            (comps.camera.eye, comps.light)
+        };
+        if self.shadows {
+            // 渲染期间不持有场景组件的锁
+            let mut shadow = std::mem::take(&mut self.comps.write().unwrap().shadow);
+            let model = &mut self.model;
+            shadow.render(r, &light, |r, view, proj| {
+                model.set_view_proj(view, proj);
+                for (name, visible) in meshes {
+                    match model.meshes.get(name) {
+                        Some(mesh) if *visible && !mesh.transparent() => match model.instances.get(name) {
+                            Some(instances) => r.draw_depth_instanced(mesh.as_ref(), instances),
+                            None => r.draw_depth(mesh.as_ref()),
+                        },
+                        _ => {}
+                    }
+                }
+            });
+            self.model.update();
+            self.comps.write().unwrap().shadow = shadow;
+        } else {
+            self.comps.write().unwrap().shadow.clear();
+        }
+        let targets = if self.deferred { GBuffer::targets() } else { 0 };
+        if r.get_targets() != targets {
+            r.set_targets(targets);
+        }
 
         r.clear_color(&COLOR_BG);
         r.clear_depth();
+        r.clear_stencil();
+        r.clear_stats();
+        let dist = |mesh: &dyn IPrimitive| (mesh.center() - eye).squared_norm();
+        let (mut opaque, mut transparent) = (Vec::new(), Vec::new());
         for (name, visible) in meshes {
             if *visible {
                 if let Some(mesh) = self.model.meshes.get(name) {
-                    r.draw(mesh);
+                    let instances = self.model.instances.get(name);
+                    if mesh.transparent() {
+                        transparent.push((mesh, instances));
+                    } else {
+                        opaque.push((mesh, instances));
+                    }
                 }
             }
         }
-        r.draw(&self.model_light.cube);
+        if self.occlusion_query {
+            // 从近到远绘制，被先绘制的mesh完全遮挡的mesh不需要绘制
+            opaque.sort_by(|a, b| dist(a.0.as_ref()).total_cmp(&dist(b.0.as_ref())));
+        }
+        for (mesh, instances) in opaque {
+            let visible = |r: &mut Rasterizer, instances: &[Instance]| {
+                !self.occlusion_query || r.query_occlusion(mesh.as_ref(), instances)
+            };
+            match instances {
+                Some(instances) if visible(r, instances) => r.draw_instanced(mesh.as_ref(), instances),
+                None if visible(r, &[]) => r.draw(mesh.as_ref()),
+                _ => {}
+            }
+        }
+        r.draw(self.model_light.cube.as_ref());
+        if self.deferred {
+            r.resolve();
+            self.gbuffer.capture(r);
+            self.gbuffer.lighting(r, &light, &eye, &self.comps.read().unwrap().shadow);
+        }
+
+        if self.oit == EOit::Off {
+            transparent.sort_by(|a, b| dist(b.0.as_ref()).total_cmp(&dist(a.0.as_ref())));
+        }
+        let depth = r.get_depth_state();
+        r.set_blend(BlendState::alpha());
+        r.set_depth_state(DepthState { write: false, ..depth });
+        r.set_oit(self.oit);
+        for (mesh, instances) in transparent {
+            match instances {
+                Some(instances) => r.draw_instanced(mesh.as_ref(), instances),
+                None => r.draw(mesh.as_ref()),
+            }
+        }
+        r.set_blend(BlendState::new());
+        r.set_depth_state(depth);
+        r.set_oit(EOit::Off);
+        r.resolve();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::ELight;
+    use rasterizer::compare::{golden, Tolerance};
+    use rasterizer::shader::IGlsl;
+
+    /// 逐个渲染Model中的mesh，与assets/golden下的参考图像比较
+    #[test]
+    fn golden_test() {
+        // 模型资源路径相对于仓库根目录
+        std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../..")).unwrap();
+        let sz = (200, 200);
+        let mut scene = Scene::new(sz);
+        let mut r = Rasterizer::new(sz);
+        *r.wire_frame() = false;
+
+        let names = scene.get_meshes();
+        let mut errors = Vec::new();
+        for name in &names {
+            let meshes = names.iter().map(|n| (*n, n == name)).collect();
+            scene.update(&mut r, &meshes);
+            if let Err(e) = golden(
+                format!("assets/golden/{}.png", name),
+                sz,
+                r.get_color(),
+                &Tolerance::default(),
+            ) {
+                errors.push(e);
+            }
+        }
+
+        // spot在floor上的阴影：spot脚下的floor像素比关闭阴影时更暗
+        let meshes = names.iter().map(|n| (*n, *n == "spot" || *n == "floor")).collect();
+        let k = 56 + 35 * sz.0 as usize;
+        scene.update(&mut r, &meshes);
+        let lit = r.get_color()[k];
+        scene.shadows = true;
+        for kind in [ELight::Directional, ELight::Point] {
+            scene.comps.write().unwrap().light.kind = kind;
+            scene.update(&mut r, &meshes);
+            let path = format!("assets/golden/shadow_{:?}.png", kind).to_lowercase();
+            if let Err(e) = golden(path, sz, r.get_color(), &Tolerance::default()) {
+                errors.push(e);
+            }
+            let c = r.get_color()[k];
+            assert!((0..3).all(|i| c[i] < lit[i]), "{:?}: {:?} >= {:?}", kind, c, lit);
+        }
+        assert!(errors.is_empty(), "{}", errors.join("\n"));
+    }
+
+    /// 实例化绘制的mesh使用所有实例的包围盒进行遮挡查询
+    #[test]
+    fn occlusion_test() {
+        std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../..")).unwrap();
+        let sz = (100, 100);
+        let mut scene = Scene::new(sz);
+        let mut r = Rasterizer::new(sz);
+        *r.wire_frame() = false;
+        let meshes = scene.get_meshes().into_iter().map(|n| (n, n == "spot_herd")).collect();
+
+        scene.update(&mut r, &meshes);
+        let color = r.get_color().clone();
+        scene.occlusion_query = true;
+        scene.update(&mut r, &meshes);
+        assert!(*r.get_color() == color);
+
+        // 合并后的包围盒在屏幕上覆盖每个实例的包围盒
+        let herd = &scene.model.meshes["spot_herd"];
+        let instances = &scene.model.instances["spot_herd"];
+        let range = |corners: [Vec4; 8]| {
+            corners
+                .iter()
+                .fold((Vec2::fill(Tyf::MAX), Vec2::fill(Tyf::MIN)), |(lo, hi), p| {
+                    let (x, y) = (p.x / p.w, p.y / p.w);
+                    (Vec2::from(lo.x.min(x), lo.y.min(y)), Vec2::from(hi.x.max(x), hi.y.max(y)))
+                })
+        };
+        let (lo, hi) = range(herd.bounds(instances).unwrap());
+        for k in 0..instances.len() {
+            let (l, h) = range(herd.bounds(&instances[k..=k]).unwrap());
+            assert!(lo.x <= l.x && lo.y <= l.y && h.x <= hi.x && h.y <= hi.y);
+        }
+        let (l, h) = range(herd.bounds(&[]).unwrap());
+        assert!(l.x > lo.x || l.y > lo.y || h.x < hi.x || h.y < hi.y);
+    }
+
+    /// 内层眼球的alpha测试：alpha小于阈值的片段被丢弃，不修改颜色和深度
+    #[test]
+    fn alpha_test() {
+        std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../..")).unwrap();
+        let sz = (100, 100);
+        let mut scene = Scene::new(sz);
+        let mut r = Rasterizer::new(sz);
+        *r.wire_frame() = false;
+        let names = scene.get_meshes();
+        scene.update(&mut r, &names.iter().map(|n| (*n, false)).collect());
+        let (color, depth) = (r.get_color().clone(), r.get_depth().clone());
+
+        // 通过实例颜色改变眼球的alpha
+        let eye = names.iter().map(|n| (*n, *n == "african_head_eye")).collect();
+        for (alpha, discarded) in [(0.6, false), (0.4, true)] {
+            let tint = Vec4::fill(1.0).w(alpha);
+            scene
+                .model
+                .instances
+                .insert("african_head_eye", vec![Instance::from(Mat4::eye(1.0), tint)]);
+            scene.update(&mut r, &eye);
+            assert_eq!(*r.get_color() == color, discarded);
+            assert_eq!(*r.get_depth() == depth, discarded);
+        }
+    }
+} /* tests */
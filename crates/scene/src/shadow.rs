@@ -0,0 +1,269 @@
+//! 阴影贴图（shadow mapping）
+//!
+//! 先从光源的视角渲染场景的深度（shadow pass），得到每个方向上离光源最近的表面；
+//! 着色时将片段变换到光源的投影空间，与阴影贴图中的深度比较，比最近表面更远的片段被遮挡，处于阴影中。
+//!
+//! - 定向光使用一个正交投影的阴影贴图，覆盖以原点为中心的场景范围
+//! - 点光源使用立方体阴影贴图（omnidirectional shadow map）：从pos分别朝向±x、±y、±z的6个90度视角的透视投影
+//!
+//! 阴影贴图的精度有限，比较深度时需要加上深度偏移（bias），避免表面遮挡自己产生的条纹（shadow acne）；
+//! 表面与光线的夹角越大，一个texel覆盖的深度范围越大，偏移也需要越大（slope-scaled bias）。
+//! 再对周围多个texel的比较结果取平均（PCF，percentage-closer filtering），得到柔和的阴影边缘。
+
+use crate::light::{ELight, Light};
+use magx::*;
+use rasterizer::{framebuffer::Framebuffer, rasterizer::Rasterizer};
+
+/// 定向光阴影贴图的正交投影半宽
+const EXTENT: Tyf = 3.0;
+/// 定向光阴影贴图的视点到原点的距离
+const DISTANCE: Tyf = 10.0;
+/// 阴影贴图的近平面
+const NEAR: Tyf = 0.1;
+/// 阴影贴图的远平面
+const FAR: Tyf = 20.0;
+
+/// 立方体阴影贴图6个面的朝向和正上方向（依次为+x, -x, +y, -y, +z, -z）
+const CUBE: [(Vec3, Vec3); 6] = [
+    (Vec3::from(1.0, 0.0, 0.0), Vec3::from(0.0, -1.0, 0.0)),
+    (Vec3::from(-1.0, 0.0, 0.0), Vec3::from(0.0, -1.0, 0.0)),
+    (Vec3::from(0.0, 1.0, 0.0), Vec3::from(0.0, 0.0, 1.0)),
+    (Vec3::from(0.0, -1.0, 0.0), Vec3::from(0.0, 0.0, -1.0)),
+    (Vec3::from(0.0, 0.0, 1.0), Vec3::from(0.0, -1.0, 0.0)),
+    (Vec3::from(0.0, 0.0, -1.0), Vec3::from(0.0, -1.0, 0.0)),
+];
+
+/// 阴影贴图的一个面
+struct ShadowFace {
+    /// 光源的投影矩阵 * 视图矩阵
+    vp: Mat4,
+    /// 到光源视点的线性深度（以左下角为原点，按行保存）
+    depth: Vec<Tyf>,
+}
+
+/// 定向光或点光源的阴影贴图
+pub struct ShadowMap {
+    /// 每个面的大小（sz x sz）
+    pub sz: u32,
+    /// 深度偏移（世界坐标系中的距离），随表面与光线的夹角增大
+    pub bias: Tyf,
+    /// PCF的采样半径，采样(2 * pcf + 1)^2个texel，0表示不过滤
+    pub pcf: u32,
+    /// 渲染阴影贴图时的光源
+    light: Light,
+    /// 定向光有1个面，点光源有6个面，没有渲染时为空
+    faces: Vec<ShadowFace>,
+}
+
+impl ShadowMap {
+    pub fn new(sz: u32) -> Self {
+        Self {
+            sz,
+            bias: 0.02,
+            pcf: 1,
+            light: Light::new(),
+            faces: Vec::new(),
+        }
+    }
+
+    /// 是否已经渲染（没有渲染时所有片段都可见）
+    #[inline]
+    pub fn rendered(&self) -> bool {
+        !self.faces.is_empty()
+    }
+
+    /// 清除阴影贴图
+    #[inline]
+    pub fn clear(&mut self) {
+        self.faces.clear();
+    }
+
+    /// 从光源的视角渲染阴影贴图
+    ///
+    /// 依次将每个面的帧缓冲绑定到rasterizer，并调用draw(r, view, proj)绘制投射阴影的mesh的深度（如`IPipeline::draw_depth`）；
+    /// 阴影贴图始终使用正向Z，渲染完成后恢复rasterizer的反向Z设置（会清除深度buffer）。
+    pub fn render<F>(&mut self, r: &mut Rasterizer, light: &Light, mut draw: F)
+    where
+        F: FnMut(&mut Rasterizer, &Mat4, &Mat4),
+    {
+        let views: Vec<(Mat4, Mat4)> = match light.kind {
+            ELight::Directional => {
+                // 光线方向接近y轴时，使用z轴作为正上方向
+                let up = if light.dir.x.abs() < 1e-3 && light.dir.z.abs() < 1e-3 {
+                    Vec3::from(0.0, 0.0, 1.0)
+                } else {
+                    Vec3::from(0.0, 1.0, 0.0)
+                };
+                let eye = -light.dir * DISTANCE;
+                vec![(
+                    look_at(&eye, &Vec3::new(), &up),
+                    ortho(-EXTENT, EXTENT, -EXTENT, EXTENT, NEAR, FAR),
+                )]
+            }
+            ELight::Point => CUBE
+                .iter()
+                .map(|(dir, up)| {
+                    (
+                        look_at(&light.pos, &(light.pos + *dir), up),
+                        persp(Angle::Ang(90.0), 1.0, NEAR, FAR),
+                    )
+                })
+                .collect(),
+        };
+
+        let reversed = r.get_reversed_z();
+        let mut fbo = Framebuffer::new((self.sz, self.sz));
+        self.light = *light;
+        self.faces.clear();
+        for (view, proj) in views {
+            let screen = r.bind_framebuffer(fbo);
+            r.set_reversed_z(false);
+            r.clear_depth();
+            draw(r, &view, &proj);
+            fbo = r.bind_framebuffer(screen);
+            self.faces.push(ShadowFace {
+                vp: proj.mul_mat(&view),
+                depth: fbo.get_depth().iter().map(|d| self.linear(*d)).collect(),
+            });
+        }
+        r.set_reversed_z(reversed);
+    }
+
+    /// 将深度buffer中[0, 1]范围的深度转换成到光源视点的线性深度
+    #[inline]
+    fn linear(&self, d: f32) -> Tyf {
+        match self.light.kind {
+            ELight::Directional => NEAR + d * (FAR - NEAR),
+            ELight::Point => {
+                let z = d * 2.0 - 1.0;
+                2.0 * NEAR * FAR / (FAR + NEAR - z * (FAR - NEAR))
+            }
+        }
+    }
+
+    /// 计算片段对光源的可见度（0为完全处于阴影中，1为完全被照亮）
+    ///
+    /// - pos: 世界坐标系中的片段坐标
+    /// - norm: 片段法向量（需要归一化）
+    /// - ldir: 从片段看向光源的方向
+    pub fn visibility(&self, pos: &Vec3, norm: &Vec3, ldir: &Vec3) -> Tyf {
+        let face = match (self.light.kind, self.faces.len()) {
+            (_, 0) => return 1.0,
+            (ELight::Directional, _) => &self.faces[0],
+            (ELight::Point, _) => {
+                // 按片段相对光源的主轴方向选择立方体的面
+                let d = *pos - self.light.pos;
+                let axis = (0..3).fold(0, |a, k| if d[k].abs() > d[a].abs() { k } else { a });
+                &self.faces[axis * 2 + if d[axis] < 0.0 { 1 } else { 0 }]
+            }
+        };
+        let p = face.vp.mul_vec(&pos.to_vec4(1.0));
+        let (x, y, z) = (p.x / p.w, p.y / p.w, p.z / p.w);
+        if p.w <= 0.0 || x.abs() > 1.0 || y.abs() > 1.0 || z > 1.0 {
+            // 阴影贴图范围之外的片段不处于阴影中
+            return 1.0;
+        }
+        let depth = self.linear(z * 0.5 + 0.5);
+        let cos = norm.dot(ldir).clamp(0.05, 1.0);
+        let bias = self.bias * (1.0 + ((1.0 - cos * cos).sqrt() / cos).min(10.0));
+
+        let sz = self.sz as i32;
+        let i = (((x + 1.0) * 0.5 * self.sz as Tyf) as i32).clamp(0, sz - 1);
+        let j = (((y + 1.0) * 0.5 * self.sz as Tyf) as i32).clamp(0, sz - 1);
+        let r = self.pcf as i32;
+        let mut lit = 0;
+        for dj in -r..=r {
+            for di in -r..=r {
+                let (u, v) = ((i + di).clamp(0, sz - 1), (j + dj).clamp(0, sz - 1));
+                if depth - bias <= face.depth[(u + v * sz) as usize] {
+                    lit += 1;
+                }
+            }
+        }
+        lit as Tyf / ((2 * r + 1) * (2 * r + 1)) as Tyf
+    }
+}
+
+impl Default for ShadowMap {
+    fn default() -> Self {
+        Self::new(512)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rasterizer::pipeline::{IPipeline, IPrimitive};
+    use rasterizer::shader::{triangle_vertex, FragOutputs, IGlsl, IShader, Instance};
+    use rasterizer::varying::Varyings;
+    use std::ops::Range;
+
+    /// y = 1平面上，x, z在[-1, 1]范围内的正方形遮挡物
+    struct Occluder(Mat4);
+
+    impl IPrimitive for Occluder {
+        fn indices(&self) -> Range<usize> {
+            0..2
+        }
+    }
+
+    impl IShader for Occluder {
+        fn vertex(&self, pidx: usize, _inst: &Instance) -> ((Vec4, Vec4, Vec4), (Varyings, Varyings, Varyings)) {
+            let v = |x: Tyf, z: Tyf| self.0.mul_vec(&Vec4::from(x, 1.0, z, 1.0));
+            let tri = if pidx == 0 {
+                (v(-1.0, -1.0), v(1.0, -1.0), v(1.0, 1.0))
+            } else {
+                (v(-1.0, -1.0), v(1.0, 1.0), v(-1.0, 1.0))
+            };
+            (tri, (Varyings::new(), Varyings::new(), Varyings::new()))
+        }
+
+        fn vertex_at(&self, vidx: usize, inst: &Instance) -> (Vec4, Varyings) {
+            triangle_vertex(self.vertex(vidx / 3, inst), vidx % 3)
+        }
+
+        fn fragment(&self, _pidx: usize, _inst: &Instance, _v: &Varyings) -> FragOutputs {
+            Vec4::fill(1.0).into()
+        }
+    }
+
+    #[test]
+    fn shadow_test() {
+        let mut r = Rasterizer::new((8, 8));
+        r.set_reversed_z(true);
+        *r.cull_face() = false;
+        let up = Vec3::from(0.0, 1.0, 0.0);
+        let mut light = Light::new();
+        light.dir = -up;
+        light.pos = up * 3.0;
+
+        for kind in [ELight::Directional, ELight::Point] {
+            light.kind = kind;
+            let mut shadow = ShadowMap::new(128);
+            assert!(!shadow.rendered());
+            assert_eq!(shadow.visibility(&Vec3::new(), &up, &up), 1.0);
+            shadow.render(&mut r, &light, |r, view, proj| {
+                let primitive: Box<dyn IPrimitive> = Box::new(Occluder(proj.mul_mat(view)));
+                r.draw_depth(primitive.as_ref());
+            });
+            assert_eq!(shadow.faces.len(), if kind == ELight::Point { 6 } else { 1 });
+            // 恢复rasterizer的反向Z和帧缓冲
+            assert!(r.get_reversed_z());
+            assert_eq!(r.sz, (8, 8));
+
+            let vis = |p: Vec3| shadow.visibility(&p, &up, &light.ldir(&p));
+            // 遮挡物下方处于阴影中，遮挡物本身和范围之外的片段被照亮
+            assert_eq!(vis(Vec3::new()), 0.0);
+            assert_eq!(vis(Vec3::from(0.5, -0.5, -0.5)), 0.0);
+            assert_eq!(vis(Vec3::from(0.0, 1.0, 0.0)), 1.0);
+            assert_eq!(vis(Vec3::from(2.5, 0.0, 0.0)), 1.0);
+            assert_eq!(vis(Vec3::from(0.0, 4.0, 0.0)), 1.0);
+            // 阴影边缘经过PCF过滤
+            let edge = vis(Vec3::from(if kind == ELight::Point { 1.5 } else { 1.0 }, 0.0, 0.0));
+            assert!(edge > 0.0 && edge < 1.0, "{}", edge);
+
+            shadow.clear();
+            assert_eq!(shadow.visibility(&Vec3::new(), &up, &up), 1.0);
+        }
+    }
+} /* tests */
@@ -0,0 +1,220 @@
+//! Headless rendering: render one frame without opening a window
+
+use magx::*;
+use rasterizer::compare;
+use rasterizer::oit::EOit;
+use rasterizer::rasterizer::{EMsaa, Rasterizer};
+use rasterizer::shader::IGlsl;
+use scene::gbuffer::EGBuffer;
+use scene::light::ELight;
+use scene::scene::Scene;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Instant;
+
+pub const USAGE: &str = r#"Usage: erender render [OPTIONS] -o <FILE>
+
+Options:
+    --size <WxH>            Frame size [default: 400x400]
+    --meshes <a,b,...>      Meshes to draw [default: frustum,floor]
+    --camera <x,y,z>        Camera eye position [default: 0,0,3.5]
+    --center <x,y,z>        Camera target position [default: 0,0,0]
+    --msaa <0|2|4|8>        MSAA samples [default: 0]
+    --deferred              Use deferred shading
+    --shadows               Render shadow map
+    --light <KIND>          Light kind: directional, point [default: directional]
+    --oit <MODE>            Transparency: off, abuffer, peeling [default: off]
+    --wire                  Draw wire frame
+    -o, --output <FILE>     Color output (format from extension, e.g. png, tga)
+    --depth <FILE>          Depth output
+    --normal <FILE>         World-space normal output (from G-buffer)"#;
+
+/// Options of headless rendering
+#[derive(Debug, PartialEq)]
+pub struct RenderOptions {
+    pub size: (u32, u32),
+    pub meshes: Vec<String>,
+    pub eye: Option<Vec3>,
+    pub center: Option<Vec3>,
+    pub msaa: EMsaa,
+    pub deferred: bool,
+    pub shadows: bool,
+    pub light: ELight,
+    pub oit: EOit,
+    pub wire: bool,
+    pub output: String,
+    pub depth: Option<String>,
+    pub normal: Option<String>,
+}
+
+impl RenderOptions {
+    /// Parse options from command line arguments (after `render`)
+    pub fn parse(args: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut opts = Self {
+            size: (400, 400),
+            meshes: vec!["frustum".to_string(), "floor".to_string()],
+            eye: None,
+            center: None,
+            msaa: EMsaa::Off,
+            deferred: false,
+            shadows: false,
+            light: ELight::Directional,
+            oit: EOit::Off,
+            wire: false,
+            output: String::new(),
+            depth: None,
+            normal: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value of {}", arg));
+            match arg.as_str() {
+                "--size" => {
+                    let v = value()?;
+                    let (w, h) = v.split_once('x').ok_or(format!("Invalid size: {}", v))?;
+                    opts.size = (w.parse()?, h.parse()?);
+                    if opts.size.0 == 0 || opts.size.1 == 0 {
+                        return Err(format!("Invalid size: {}", v).into());
+                    }
+                }
+                "--meshes" => opts.meshes = value()?.split(',').map(|s| s.to_string()).collect(),
+                "--camera" => opts.eye = Some(parse_vec3(value()?)?),
+                "--center" => opts.center = Some(parse_vec3(value()?)?),
+                "--msaa" => {
+                    opts.msaa = match value()?.as_str() {
+                        "0" => EMsaa::Off,
+                        "2" => EMsaa::X2,
+                        "4" => EMsaa::X4,
+                        "8" => EMsaa::X8,
+                        v => return Err(format!("Invalid msaa: {}", v).into()),
+                    }
+                }
+                "--deferred" => opts.deferred = true,
+                "--shadows" => opts.shadows = true,
+                "--light" => {
+                    opts.light = match value()?.as_str() {
+                        "directional" => ELight::Directional,
+                        "point" => ELight::Point,
+                        v => return Err(format!("Invalid light: {}", v).into()),
+                    }
+                }
+                "--oit" => {
+                    opts.oit = match value()?.as_str() {
+                        "off" => EOit::Off,
+                        "abuffer" => EOit::ABuffer,
+                        "peeling" => EOit::DepthPeeling,
+                        v => return Err(format!("Invalid oit: {}", v).into()),
+                    }
+                }
+                "--wire" => opts.wire = true,
+                "-o" | "--output" => opts.output = value()?.clone(),
+                "--depth" => opts.depth = Some(value()?.clone()),
+                "--normal" => opts.normal = Some(value()?.clone()),
+                _ => return Err(format!("Unknown option: {}", arg).into()),
+            }
+        }
+        if opts.output.is_empty() {
+            return Err("Missing output file".into());
+        }
+        Ok(opts)
+    }
+}
+
+/// Parse vector from "x,y,z"
+fn parse_vec3(s: &str) -> Result<Vec3, Box<dyn Error>> {
+    let v = s.split(',').map(|v| v.trim().parse::<Tyf>()).collect::<Result<Vec<_>, _>>()?;
+    if v.len() != 3 {
+        return Err(format!("Invalid vector: {}", s).into());
+    }
+    Ok(Vec3::from(v[0], v[1], v[2]))
+}
+
+/// Convert depth buffer (origin at bottom-left) to gray image (origin at top-left)
+pub fn depth_image(sz: (u32, u32), buf: &[f32]) -> image::RgbaImage {
+    image::RgbaImage::from_fn(sz.0, sz.1, |x, y| {
+        let c = (buf[(x + (sz.1 - 1 - y) * sz.0) as usize] * 255.0) as u8;
+        image::Rgba([c, c, c, 255])
+    })
+}
+
+/// Build scene and rasterizer, render one frame and save outputs
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let opts = RenderOptions::parse(args)?;
+
+    let mut scene = Scene::new(opts.size);
+    let mut rasterizer = Rasterizer::new(opts.size);
+    let mut meshes: HashMap<&'static str, bool> = scene.get_meshes().into_iter().map(|n| (n, false)).collect();
+    for name in &opts.meshes {
+        let draw = meshes.get_mut(name.as_str()).ok_or(format!("Unknown mesh: {}", name))?;
+        *draw = true;
+    }
+    {
+        let mut comps = scene.comps.write().unwrap();
+        if let Some(eye) = opts.eye {
+            comps.camera.eye = eye;
+        }
+        if let Some(center) = opts.center {
+            comps.camera.center = center;
+        }
+        comps.light.kind = opts.light;
+    }
+    rasterizer.set_msaa(opts.msaa);
+    *rasterizer.wire_frame() = opts.wire;
+    scene.deferred = opts.deferred;
+    scene.shadows = opts.shadows;
+    scene.oit = opts.oit;
+
+    let start = Instant::now();
+    scene.update(&mut rasterizer, &meshes);
+    println!("Render time: {} ms", start.elapsed().as_millis());
+    println!("{}", rasterizer.get_stats());
+    compare::to_image(opts.size, rasterizer.get_color()).save(&opts.output)?;
+    if let Some(depth) = &opts.depth {
+        depth_image(opts.size, rasterizer.get_depth()).save(depth)?;
+    }
+    if let Some(normal) = &opts.normal {
+        // Normals come from G-buffer, which needs a deferred frame
+        if !scene.deferred {
+            scene.deferred = true;
+            scene.update(&mut rasterizer, &meshes);
+        }
+        compare::to_image(opts.size, &scene.gbuffer.view(EGBuffer::Normal)).save(normal)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_test() {
+        let opts = RenderOptions::parse(&args(
+            "--size 800x600 --meshes spot,floor --camera 1,2,3 --msaa 4 --deferred --light point --oit abuffer -o out.png --depth d.png",
+        ))
+        .unwrap();
+        assert_eq!(opts.size, (800, 600));
+        assert_eq!(opts.meshes, vec!["spot", "floor"]);
+        assert_eq!(opts.eye, Some(Vec3::from(1.0, 2.0, 3.0)));
+        assert_eq!(opts.center, None);
+        assert_eq!(opts.msaa, EMsaa::X4);
+        assert!(opts.deferred && !opts.shadows && !opts.wire);
+        assert_eq!(opts.light, ELight::Point);
+        assert_eq!(opts.oit, EOit::ABuffer);
+        assert_eq!(opts.output, "out.png");
+        assert_eq!(opts.depth, Some("d.png".to_string()));
+        assert_eq!(opts.normal, None);
+
+        assert!(RenderOptions::parse(&args("--size 800 -o out.png")).is_err());
+        assert!(RenderOptions::parse(&args("--camera 1,2 -o out.png")).is_err());
+        assert!(RenderOptions::parse(&args("--msaa 3 -o out.png")).is_err());
+        assert!(RenderOptions::parse(&args("--light spot -o out.png")).is_err());
+        assert!(RenderOptions::parse(&args("--oit sorted -o out.png")).is_err());
+        assert!(RenderOptions::parse(&args("--meshes spot")).is_err());
+        assert!(RenderOptions::parse(&args("-o")).is_err());
+    }
+} /* tests */
@@ -1,5 +1,14 @@
+mod headless;
 mod soft_renderer;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|a| a == "render").unwrap_or(false) {
+        if let Err(e) = headless::run(&args[1..]) {
+            eprintln!("Error: {}\n\n{}", e, headless::USAGE);
+            std::process::exit(1);
+        }
+        return;
+    }
     soft_renderer::run((400, 400)).unwrap();
 }
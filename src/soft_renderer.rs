@@ -1,10 +1,15 @@
 //! Pure software renderer
 
+use crate::headless;
 use eframe::egui;
-use image;
 use magx::*;
-use rasterizer::rasterizer::Rasterizer;
+use rasterizer::compare;
+use rasterizer::oit::EOit;
+use rasterizer::rasterizer::{ELineMode, EMsaa, ETonemap, Rasterizer};
 use rasterizer::shader::IGlsl;
+use rasterizer::state::{ECullMode, EFrontFace};
+use scene::gbuffer::EGBuffer;
+use scene::light::ELight;
 use scene::scene::Scene;
 use std::collections::HashMap;
 use std::time::Instant;
@@ -14,6 +19,14 @@ struct SoftRenderer {
     rasterizer: Rasterizer,
     draw_color: bool,
     draw_depth: bool,
+    /// Show overdraw heatmap
+    draw_overdraw: bool,
+    /// Time of last scene update
+    render_ms: u128,
+    /// Render at a fraction of the view size, and upscale to the view
+    render_scale: f32,
+    /// G-buffer attachment to show instead of color when deferred shading
+    gbuffer_view: Option<EGBuffer>,
     /// Request redraw scene
     redraw: bool,
     /// Meshes to draw on scene
@@ -45,59 +58,66 @@ impl SoftRenderer {
         // Update scene with rasterizer
         let start = Instant::now();
         scene.update(&mut rasterizer, &meshes);
-        println!("Render time: {} ms", start.elapsed().as_millis());
+        let render_ms = start.elapsed().as_millis();
+        println!("Render time: {} ms", render_ms);
 
         Self {
             scene,
             rasterizer,
             draw_color: true,
             draw_depth: true,
+            draw_overdraw: false,
+            render_ms,
+            render_scale: 1.0,
+            gbuffer_view: None,
             redraw: true,
             meshes,
         }
     }
 
+    /// Resize rasterizer and scene, and redraw at the new size
+    fn resize(&mut self, sz: (u32, u32)) {
+        self.rasterizer.resize(sz);
+        self.scene.resize(sz);
+        self.redraw = true;
+    }
+
     fn save(&self) {
-        let wid = self.rasterizer.sz.0;
-        let hei = self.rasterizer.sz.1;
-        let mut img = image::ImageBuffer::from_pixel(wid, hei, image::Rgba([0, 0, 0, 0]));
-        {
-            let buf = self.rasterizer.get_color();
-            for x in 0..wid {
-                for y in 0..hei {
-                    img.put_pixel(x, hei - (y + 1), image::Rgba(buf[(x + y * wid) as usize]));
-                }
-            }
-            img.save("soft_renderer_color.tga").unwrap();
-        }
-        {
-            let buf = self.rasterizer.get_depth();
-            for x in 0..wid {
-                for y in 0..hei {
-                    let c = (buf[(x + y * wid) as usize] * 255.0) as u8;
-                    img.put_pixel(x, hei - (y + 1), image::Rgba([c, c, c, 255]));
-                }
-            }
-            img.save("soft_renderer_depth.tga").unwrap();
-        }
+        let sz = self.rasterizer.sz;
+        compare::to_image(sz, self.rasterizer.get_color())
+            .save("soft_renderer_color.tga")
+            .unwrap();
+        headless::depth_image(sz, self.rasterizer.get_depth())
+            .save("soft_renderer_depth.tga")
+            .unwrap();
     }
 
     fn handle_keys(&mut self, key: &egui::Key, pressed: &bool, modifiers: &egui::Modifiers) {
         if *pressed && modifiers.is_none() {
             self.redraw = true;
             match key {
-                egui::Key::E => self.scene.comps.borrow_mut().camera.rotate_x(Angle::Ang(10.0)),
-                egui::Key::D => self.scene.comps.borrow_mut().camera.rotate_x(Angle::Ang(-10.0)),
-                egui::Key::S => self.scene.comps.borrow_mut().camera.rotate_y(Angle::Ang(10.0)),
-                egui::Key::F => self.scene.comps.borrow_mut().camera.rotate_y(Angle::Ang(-10.0)),
-                egui::Key::W => self.scene.comps.borrow_mut().camera.rotate_z(Angle::Ang(10.0)),
-                egui::Key::R => self.scene.comps.borrow_mut().camera.rotate_z(Angle::Ang(-10.0)),
-                egui::Key::G => self.scene.comps.borrow_mut().camera.move_forward(1.0),
-                egui::Key::A => self.scene.comps.borrow_mut().camera.move_forward(-1.0),
+                egui::Key::E => self.scene.comps.write().unwrap().camera.rotate_x(Angle::Ang(10.0)),
+                egui::Key::D => self.scene.comps.write().unwrap().camera.rotate_x(Angle::Ang(-10.0)),
+                egui::Key::S => self.scene.comps.write().unwrap().camera.rotate_y(Angle::Ang(10.0)),
+                egui::Key::F => self.scene.comps.write().unwrap().camera.rotate_y(Angle::Ang(-10.0)),
+                egui::Key::W => self.scene.comps.write().unwrap().camera.rotate_z(Angle::Ang(10.0)),
+                egui::Key::R => self.scene.comps.write().unwrap().camera.rotate_z(Angle::Ang(-10.0)),
+                egui::Key::G => self.scene.comps.write().unwrap().camera.move_forward(1.0),
+                egui::Key::A => self.scene.comps.write().unwrap().camera.move_forward(-1.0),
                 egui::Key::Z => *self.rasterizer.wire_frame() = !*self.rasterizer.wire_frame(),
+                egui::Key::O => *self.rasterizer.wire_overlay() = !*self.rasterizer.wire_overlay(),
                 egui::Key::X => *self.rasterizer.cull_face() = !*self.rasterizer.cull_face(),
+                egui::Key::T => *self.rasterizer.tiled() = !*self.rasterizer.tiled(),
+                egui::Key::M => self.rasterizer.set_msaa(match self.rasterizer.get_msaa() {
+                    EMsaa::Off => EMsaa::X2,
+                    EMsaa::X2 => EMsaa::X4,
+                    EMsaa::X4 => EMsaa::X8,
+                    EMsaa::X8 => EMsaa::Off,
+                }),
+                egui::Key::H => self.rasterizer.set_hdr(!self.rasterizer.get_hdr()),
                 egui::Key::C => self.draw_color = !self.draw_color,
                 egui::Key::V => self.draw_depth = !self.draw_depth,
+                egui::Key::B => self.draw_overdraw = !self.draw_overdraw,
                 _ => self.redraw = false,
             }
         }
@@ -134,11 +154,186 @@ impl eframe::App for SoftRenderer {
                 if ui.checkbox(&mut self.rasterizer.wire_frame(), "Wire[z]").changed() {
                     self.redraw = true;
                 }
+                if ui.checkbox(self.rasterizer.wire_overlay(), "Overlay[o]").changed() {
+                    self.redraw = true;
+                }
+                if *self.rasterizer.wire_overlay() {
+                    let (mut factor, mut units) = self.rasterizer.get_polygon_offset();
+                    let changed = ui
+                        .add(egui::Slider::new(&mut factor, 0.0..=4.0).text("Offset factor"))
+                        .changed()
+                        | ui.add(egui::Slider::new(&mut units, 0.0..=64.0).text("Offset units"))
+                            .changed();
+                    if changed {
+                        self.rasterizer.set_polygon_offset(factor, units);
+                        self.redraw = true;
+                    }
+                }
                 if ui.checkbox(&mut self.rasterizer.cull_face(), "Cull[x]").changed() {
                     self.redraw = true;
                 }
+                if *self.rasterizer.cull_face() {
+                    let mut cull_mode = self.rasterizer.get_cull_mode();
+                    egui::ComboBox::from_label("Cull mode")
+                        .selected_text(format!("{:?}", cull_mode))
+                        .show_ui(ui, |ui| {
+                            for m in [ECullMode::None, ECullMode::Back, ECullMode::Front, ECullMode::FrontAndBack] {
+                                ui.selectable_value(&mut cull_mode, m, format!("{:?}", m));
+                            }
+                        });
+                    if cull_mode != self.rasterizer.get_cull_mode() {
+                        self.rasterizer.set_cull_mode(cull_mode);
+                        self.redraw = true;
+                    }
+                }
+                let mut front_face = self.rasterizer.get_front_face();
+                egui::ComboBox::from_label("Front face")
+                    .selected_text(format!("{:?}", front_face))
+                    .show_ui(ui, |ui| {
+                        for f in [EFrontFace::Ccw, EFrontFace::Cw] {
+                            ui.selectable_value(&mut front_face, f, format!("{:?}", f));
+                        }
+                    });
+                if front_face != self.rasterizer.get_front_face() {
+                    self.rasterizer.set_front_face(front_face);
+                    self.redraw = true;
+                }
+                let mut msaa = self.rasterizer.get_msaa();
+                egui::ComboBox::from_label("MSAA[m]")
+                    .selected_text(format!("{:?}", msaa))
+                    .show_ui(ui, |ui| {
+                        for m in [EMsaa::Off, EMsaa::X2, EMsaa::X4, EMsaa::X8] {
+                            ui.selectable_value(&mut msaa, m, format!("{:?}", m));
+                        }
+                    });
+                if msaa != self.rasterizer.get_msaa() {
+                    self.rasterizer.set_msaa(msaa);
+                    self.redraw = true;
+                }
+                if ui.checkbox(self.rasterizer.tiled(), "Tiled[t]").changed() {
+                    self.redraw = true;
+                }
+                ui.add(egui::Slider::new(&mut self.render_scale, 0.25..=1.0).text("Scale"));
+                let mut reversed_z = self.rasterizer.get_reversed_z();
+                if ui.checkbox(&mut reversed_z, "Reversed-Z").changed() {
+                    self.rasterizer.set_reversed_z(reversed_z);
+                    self.redraw = true;
+                }
+                let mut point_size = self.rasterizer.get_point_size();
+                if ui.add(egui::Slider::new(&mut point_size, 1.0..=8.0).text("Point")).changed() {
+                    self.rasterizer.set_point_size(point_size);
+                    self.redraw = true;
+                }
+                let mut line_mode = self.rasterizer.get_line_mode();
+                egui::ComboBox::from_label("Line")
+                    .selected_text(format!("{:?}", line_mode))
+                    .show_ui(ui, |ui| {
+                        for m in [ELineMode::Dda, ELineMode::Bresenham, ELineMode::Wu] {
+                            ui.selectable_value(&mut line_mode, m, format!("{:?}", m));
+                        }
+                    });
+                if line_mode != self.rasterizer.get_line_mode() {
+                    self.rasterizer.set_line_mode(line_mode);
+                    self.redraw = true;
+                }
+                let mut line_width = self.rasterizer.get_line_width();
+                if ui.add(egui::Slider::new(&mut line_width, 1.0..=8.0).text("Width")).changed() {
+                    self.rasterizer.set_line_width(line_width);
+                    self.redraw = true;
+                }
+                if ui.checkbox(self.rasterizer.hiz(), "Hi-Z").changed() {
+                    self.redraw = true;
+                }
+                if ui.checkbox(&mut self.scene.occlusion_query, "Occlusion").changed() {
+                    self.redraw = true;
+                }
+                let mut hdr = self.rasterizer.get_hdr();
+                if ui.checkbox(&mut hdr, "HDR[h]").changed() {
+                    self.rasterizer.set_hdr(hdr);
+                    self.redraw = true;
+                }
+                if hdr {
+                    let mut tonemap = self.rasterizer.get_tonemap();
+                    egui::ComboBox::from_label("Tonemap")
+                        .selected_text(format!("{:?}", tonemap))
+                        .show_ui(ui, |ui| {
+                            for t in [ETonemap::Clamp, ETonemap::Reinhard, ETonemap::Aces] {
+                                ui.selectable_value(&mut tonemap, t, format!("{:?}", t));
+                            }
+                        });
+                    if tonemap != self.rasterizer.get_tonemap() {
+                        self.rasterizer.set_tonemap(tonemap);
+                        self.redraw = true;
+                    }
+                    let mut exposure = self.rasterizer.get_exposure();
+                    if ui.add(egui::Slider::new(&mut exposure, 0.0..=4.0).text("Exposure")).changed() {
+                        self.rasterizer.set_exposure(exposure);
+                        self.redraw = true;
+                    }
+                    if ui.checkbox(self.rasterizer.srgb(), "sRGB").changed() {
+                        self.redraw = true;
+                    }
+                }
+                if ui.checkbox(&mut self.scene.shadows, "Shadows").changed() {
+                    self.redraw = true;
+                }
+                if self.scene.shadows {
+                    let mut comps = self.scene.comps.write().unwrap();
+                    let mut kind = comps.light.kind;
+                    egui::ComboBox::from_label("Light")
+                        .selected_text(format!("{:?}", kind))
+                        .show_ui(ui, |ui| {
+                            for k in [ELight::Directional, ELight::Point] {
+                                ui.selectable_value(&mut kind, k, format!("{:?}", k));
+                            }
+                        });
+                    if kind != comps.light.kind {
+                        comps.light.kind = kind;
+                        self.redraw = true;
+                    }
+                    let shadow = &mut comps.shadow;
+                    if ui.add(egui::Slider::new(&mut shadow.pcf, 0..=3).text("PCF")).changed()
+                        | ui.add(egui::Slider::new(&mut shadow.bias, 0.0..=0.1).text("Bias")).changed()
+                    {
+                        self.redraw = true;
+                    }
+                }
+                let mut oit = self.scene.oit;
+                egui::ComboBox::from_label("OIT")
+                    .selected_text(format!("{:?}", oit))
+                    .show_ui(ui, |ui| {
+                        for m in [EOit::Off, EOit::ABuffer, EOit::DepthPeeling] {
+                            ui.selectable_value(&mut oit, m, format!("{:?}", m));
+                        }
+                    });
+                if oit != self.scene.oit {
+                    self.scene.oit = oit;
+                    self.redraw = true;
+                }
+                if self.scene.oit == EOit::DepthPeeling {
+                    let mut layers = self.rasterizer.get_peel_layers();
+                    if ui.add(egui::Slider::new(&mut layers, 1..=8).text("Layers")).changed() {
+                        self.rasterizer.set_peel_layers(layers);
+                        self.redraw = true;
+                    }
+                }
+                if ui.checkbox(&mut self.scene.deferred, "Deferred").changed() {
+                    self.redraw = true;
+                }
+                if self.scene.deferred {
+                    let name = |v: Option<EGBuffer>| v.map_or("Color".to_string(), |e| format!("{:?}", e));
+                    egui::ComboBox::from_label("View")
+                        .selected_text(name(self.gbuffer_view))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut self.gbuffer_view, None, name(None));
+                            for e in EGBuffer::ALL {
+                                ui.selectable_value(&mut self.gbuffer_view, Some(e), name(Some(e)));
+                            }
+                        });
+                }
                 ui.checkbox(&mut self.draw_color, "Color[c]");
                 ui.checkbox(&mut self.draw_depth, "Depth[v]");
+                ui.checkbox(&mut self.draw_overdraw, "Overdraw[b]");
                 ui.label("Meshes:");
                 for name in self.scene.get_meshes() {
                     if let Some(mut draw) = self.meshes.get_mut(name) {
@@ -153,16 +348,46 @@ impl eframe::App for SoftRenderer {
                 if ui.button("Save[^s]").clicked() {
                     SoftRenderer::save(self);
                 };
+                ui.separator();
+                ui.label(format!("Render time: {} ms", self.render_ms));
+                ui.label(self.rasterizer.get_stats().to_string());
             });
 
         egui::CentralPanel::default().show(ctx, |ui| {
+            // Split the panel into views, and render at the scaled view size
+            let views = [self.draw_color, self.draw_depth, self.draw_overdraw]
+                .iter()
+                .filter(|v| **v)
+                .count()
+                .max(1) as f32;
+            let avail = ui.available_size();
+            let view_size =
+                egui::vec2((avail.x - ui.spacing().item_spacing.x * (views - 1.0)) / views, avail.y).max(egui::vec2(1.0, 1.0));
+            let sz = (
+                (view_size.x * self.render_scale).max(1.0) as u32,
+                (view_size.y * self.render_scale).max(1.0) as u32,
+            );
+            if sz != self.rasterizer.sz {
+                self.resize(sz);
+            }
+            if self.redraw {
+                let start = Instant::now();
+                self.scene.update(&mut self.rasterizer, &self.meshes);
+                self.render_ms = start.elapsed().as_millis();
+                self.redraw = false;
+            }
+
             let wid = self.rasterizer.sz.0 as usize;
             let hei = self.rasterizer.sz.1 as usize;
             let size = [wid, hei];
             ui.horizontal(|ui| {
                 // Draw color
                 if self.draw_color {
-                    let buf = self.rasterizer.get_color();
+                    let view = self
+                        .gbuffer_view
+                        .filter(|_| self.scene.deferred)
+                        .map(|e| self.scene.gbuffer.view(e));
+                    let buf = view.as_ref().unwrap_or(self.rasterizer.get_color());
                     let mut pixels = vec![egui::Color32::BLACK; wid * hei];
                     for x in 0..wid {
                         for y in 0..hei {
@@ -172,7 +397,7 @@ impl eframe::App for SoftRenderer {
                     }
                     let image = egui::ColorImage { size, pixels };
                     let texture = ui.ctx().load_texture("Color", image, Default::default());
-                    ui.image((texture.id(), texture.size_vec2()));
+                    ui.image((texture.id(), view_size));
                 }
                 // Draw depth
                 if self.draw_depth {
@@ -190,23 +415,47 @@ impl eframe::App for SoftRenderer {
                     }
                     let image = egui::ColorImage { size, pixels };
                     let texture = ui.ctx().load_texture("Depth", image, Default::default());
-                    ui.image((texture.id(), texture.size_vec2()));
+                    ui.image((texture.id(), view_size));
+                }
+                // Draw overdraw heatmap
+                if self.draw_overdraw {
+                    let buf = self.rasterizer.get_overdraw();
+                    let mut pixels = vec![egui::Color32::BLACK; wid * hei];
+                    for x in 0..wid {
+                        for y in 0..hei {
+                            pixels[x + y * wid] = heatmap(buf[x + (hei - (y + 1)) * wid]);
+                        }
+                    }
+                    let image = egui::ColorImage { size, pixels };
+                    let texture = ui.ctx().load_texture("Overdraw", image, Default::default());
+                    ui.image((texture.id(), view_size));
                 }
             });
         });
-
-        if self.redraw {
-            self.scene.update(&mut self.rasterizer, &self.meshes);
-        }
-        self.redraw = false;
     }
 }
 
+/// Heatmap color of overdraw: black(0), blue(1), cyan, green, yellow, orange, red(>= 6)
+fn heatmap(n: u32) -> egui::Color32 {
+    const COLORS: [[u8; 3]; 7] = [
+        [0, 0, 0],
+        [0, 0, 255],
+        [0, 255, 255],
+        [0, 255, 0],
+        [255, 255, 0],
+        [255, 128, 0],
+        [255, 0, 0],
+    ];
+    let [r, g, b] = COLORS[(n as usize).min(COLORS.len() - 1)];
+    egui::Color32::from_rgb(r, g, b)
+}
+
 pub fn run(sz: (u32, u32)) -> eframe::Result<()> {
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_inner_size([sz.0 as f32 * 2.0 + 215.0, sz.1 as f32 + 15.0])
-            .with_resizable(false),
+            .with_inner_size([sz.0 as f32 * 3.0 + 215.0, sz.1 as f32 + 15.0])
+            .with_min_inner_size([400.0, 300.0])
+            .with_resizable(true),
         ..Default::default()
     };
 